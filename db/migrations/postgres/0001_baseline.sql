@@ -0,0 +1,1362 @@
+-- Baseline schema and reference data for PostgreSQL, equivalent to sqlite migrations 0001-0006
+-- Identifiers are unquoted so fold to lowercase, json views return jsonb
+-- References between oneroster entities are added by 0003_foreign_keys
+
+-- Helpers
+
+-- RFC3339 timestamp normalised to UTC with milliseconds, as sqlite strftime('%Y-%m-%dT%H:%M:%fZ')
+CREATE FUNCTION or_timestamp(value text) RETURNS text AS $$
+    SELECT to_char(value::timestamptz AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.MS"Z"')
+$$ LANGUAGE sql STABLE;
+
+-- YYYY-MM-DD, as sqlite date()
+CREATE FUNCTION or_date(value text) RETURNS text AS $$
+    SELECT value::date::text
+$$ LANGUAGE sql IMMUTABLE;
+
+-- The value when it is a json array, otherwise an empty one, as sqlite json_each() of null
+CREATE FUNCTION or_array(value jsonb) RETURNS jsonb AS $$
+    SELECT CASE WHEN jsonb_typeof(value) = 'array' THEN value ELSE '[]'::jsonb END
+$$ LANGUAGE sql IMMUTABLE;
+
+-- OR:4.13
+
+CREATE TABLE ClassType (
+    id serial PRIMARY KEY
+    , token text UNIQUE NOT NULL
+);
+
+CREATE TABLE OrgType (
+    id serial PRIMARY KEY
+    , token text UNIQUE NOT NULL
+);
+
+CREATE TABLE RoleType (
+    id serial PRIMARY KEY
+    , token text UNIQUE NOT NULL
+);
+
+CREATE TABLE SessionType (
+    id serial PRIMARY KEY
+    , token text UNIQUE NOT NULL
+);
+
+CREATE TABLE StatusType (
+    id serial PRIMARY KEY
+    , token text UNIQUE NOT NULL
+);
+
+-- CEDS v5 Entry Grade Level: https://ceds.ed.gov/CEDSElementDetails.aspx?TermId=7100
+CREATE TABLE GradeType (
+    id serial PRIMARY KEY
+    , token text UNIQUE NOT NULL
+    , description text NOT NULL
+);
+
+-- Auth tables
+
+-- Fields stripped or masked from every response to a credential
+CREATE TABLE redaction_profiles (
+    id serial PRIMARY KEY
+    , name text UNIQUE NOT NULL
+);
+
+CREATE TABLE redaction_profile_fields (
+    id serial PRIMARY KEY
+    , profile_id integer NOT NULL REFERENCES redaction_profiles (id) ON DELETE CASCADE
+    , entity text NOT NULL -- singular json object name or '*'
+    , field text NOT NULL
+    , action text NOT NULL CHECK (action IN ('strip', 'mask'))
+);
+CREATE UNIQUE INDEX RedactionProfileFieldsIndex ON redaction_profile_fields (profile_id, entity, field);
+
+CREATE TABLE credentials (
+    id serial PRIMARY KEY
+    , client_id text UNIQUE NOT NULL
+    , client_secret text NOT NULL
+    , tag text NOT NULL
+    , disabled boolean NOT NULL DEFAULT false
+    , expires timestamptz -- NULL never expires
+    , last_used timestamptz -- time of last successful login
+    , redaction_profile_id integer REFERENCES redaction_profiles (id) ON DELETE SET NULL
+);
+
+CREATE TABLE scopes (
+    id serial PRIMARY KEY
+    , scope text UNIQUE NOT NULL
+);
+
+CREATE TABLE credential_scopes (
+    id serial PRIMARY KEY
+    , credential_id integer NOT NULL REFERENCES credentials (id) ON DELETE CASCADE
+    , scope_id integer NOT NULL REFERENCES scopes (id) ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX CredentialScopesIndex ON credential_scopes (credential_id, scope_id);
+
+-- Restricts a credential to the listed orgs and their descendants
+CREATE TABLE credential_orgs (
+    id serial PRIMARY KEY
+    , credential_id integer NOT NULL REFERENCES credentials (id) ON DELETE CASCADE
+    , org_sourced_id text NOT NULL
+);
+CREATE UNIQUE INDEX CredentialOrgsIndex ON credential_orgs (credential_id, org_sourced_id);
+
+-- TLS client certificates identifying a credential, by SHA-256 fingerprint or subject DN
+CREATE TABLE credential_certs (
+    id serial PRIMARY KEY
+    , credential_id integer NOT NULL REFERENCES credentials (id) ON DELETE CASCADE
+    , kind text NOT NULL CHECK (kind IN ('fingerprint', 'subject'))
+    , value text NOT NULL -- lowercase hex without separators or RFC4514 style DN
+);
+CREATE UNIQUE INDEX CredentialCertsIndex ON credential_certs (kind, value);
+
+-- Audit tables
+-- client_id is not a foreign key so the trail outlives deleted credentials
+
+CREATE TABLE audit_requests (
+    id bigserial PRIMARY KEY
+    , "timestamp" timestamptz NOT NULL
+    , client_id text NOT NULL
+    , ip text NOT NULL
+    , method text NOT NULL
+    , route text NOT NULL
+    , query text
+    , status bigint NOT NULL
+    , size bigint
+);
+CREATE INDEX AuditRequestsClientIndex ON audit_requests (client_id, "timestamp");
+CREATE INDEX AuditRequestsTimestampIndex ON audit_requests ("timestamp");
+
+-- before is NULL for created records, after is NULL for deleted records
+CREATE TABLE audit_changes (
+    id bigserial PRIMARY KEY
+    , "timestamp" timestamptz NOT NULL
+    , client_id text NOT NULL
+    , entity text NOT NULL -- singular json object name
+    , sourced_id text NOT NULL
+    , operation text NOT NULL CHECK (operation IN ('create', 'update', 'delete'))
+    , before jsonb
+    , after jsonb
+);
+CREATE INDEX AuditChangesRecordIndex ON audit_changes (entity, sourced_id, "timestamp");
+CREATE INDEX AuditChangesClientIndex ON audit_changes (client_id, "timestamp");
+CREATE INDEX AuditChangesTimestampIndex ON audit_changes ("timestamp");
+
+-- OR:4
+
+-- OR:4.2
+CREATE TABLE AcademicSessions (
+    id serial PRIMARY KEY
+    , sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , dateLastModified text NOT NULL
+    , title text NOT NULL
+    , startDate text NOT NULL
+    , endDate text NOT NULL
+    , sessionTypeId integer NOT NULL REFERENCES SessionType (id)
+    , parentSourcedId text
+    , schoolYear text -- YYYY
+);
+
+-- Custom
+CREATE TABLE Subjects (
+    id serial PRIMARY KEY
+    , sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , dateLastModified text NOT NULL
+    , title text NOT NULL
+    , subjectCode text NOT NULL
+);
+
+-- OR:4.3
+CREATE TABLE Classes (
+    id serial PRIMARY KEY
+    , sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , dateLastModified text NOT NULL
+    , title text NOT NULL
+    , classCode text
+    , classTypeId integer NOT NULL REFERENCES ClassType (id)
+    , location text
+    , courseSourcedId text NOT NULL
+    , orgSourcedId text NOT NULL
+);
+
+CREATE TABLE ClassGrades (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , classSourcedId text NOT NULL
+    , gradeTypeId integer NOT NULL REFERENCES GradeType (id)
+);
+CREATE UNIQUE INDEX ClassGradeIndex ON ClassGrades (classSourcedId, gradeTypeId);
+
+CREATE TABLE ClassSubjects (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , classSourcedId text NOT NULL
+    , subjectSourcedId text NOT NULL
+);
+CREATE UNIQUE INDEX ClassSubjectIndex ON ClassSubjects (classSourcedId, subjectSourcedId);
+
+CREATE TABLE ClassAcademicSessions (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , classSourcedId text NOT NULL
+    , academicSessionSourcedId text NOT NULL
+);
+CREATE UNIQUE INDEX ClassAcademicSessionsIndex ON ClassAcademicSessions (classSourcedId, academicSessionSourcedId);
+
+CREATE TABLE Periods (
+    id serial PRIMARY KEY
+    , sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , dateLastModified text NOT NULL
+    , title text NOT NULL
+    , periodCode text NOT NULL
+    , description text
+);
+
+CREATE TABLE OrgPeriods (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , periodSourcedId text NOT NULL
+    , orgSourcedId text NOT NULL
+);
+CREATE UNIQUE INDEX OrgPeriodsIndex ON OrgPeriods (periodSourcedId, orgSourcedId);
+
+CREATE TABLE ClassPeriods (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , classSourcedId text NOT NULL
+    , periodSourcedId text NOT NULL
+);
+CREATE UNIQUE INDEX ClassPeriodIndex ON ClassPeriods (classSourcedId, periodSourcedId);
+
+-- OR:4.4
+CREATE TABLE Courses (
+    sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , dateLastModified text NOT NULL
+    , title text NOT NULL
+    , schoolYearSourcedId text
+    , courseCode text
+    , orgSourcedId text NOT NULL
+);
+
+CREATE TABLE CourseGrades (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , courseSourcedId text NOT NULL
+    , gradeTypeId integer NOT NULL REFERENCES GradeType (id)
+);
+CREATE UNIQUE INDEX CourseGradeIndex ON CourseGrades (courseSourcedId, gradeTypeId);
+
+CREATE TABLE CourseSubjects (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , courseSourcedId text NOT NULL
+    , subjectSourcedId text NOT NULL
+);
+CREATE UNIQUE INDEX CourseSubjectIndex ON CourseSubjects (courseSourcedId, subjectSourcedId);
+
+-- Demographics not supported
+
+-- OR:4.6
+CREATE TABLE Enrollments (
+    id serial PRIMARY KEY
+    , sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , dateLastModified text NOT NULL
+    , userSourcedId text NOT NULL
+    , classSourcedId text NOT NULL
+    , orgSourcedId text NOT NULL
+    , roleTypeId integer NOT NULL REFERENCES RoleType (id)
+    , "primary" integer -- bool 0/1
+    , beginDate text
+    , endDate text
+);
+CREATE UNIQUE INDEX EnrollmentsIndex ON Enrollments (userSourcedId, classSourcedId, orgSourcedId);
+
+-- OR:4.9
+CREATE TABLE Orgs (
+    id serial PRIMARY KEY
+    , sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id)
+    , dateLastModified text NOT NULL
+    , name text NOT NULL
+    , orgTypeId integer NOT NULL REFERENCES OrgType (id)
+    , identifier text
+    , parentSourcedId text
+);
+CREATE INDEX OrgsParentIndex ON Orgs (parentSourcedId);
+
+-- OR:4.12
+CREATE TABLE Users (
+    id serial PRIMARY KEY
+    , sourcedId text UNIQUE NOT NULL
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id) ON DELETE RESTRICT
+    , dateLastModified text NOT NULL
+    , username text NOT NULL
+    , enabledUser integer NOT NULL -- bool 0/1
+    , givenName text NOT NULL
+    , familyName text NOT NULL
+    , middleName text
+    , roleTypeId integer NOT NULL REFERENCES RoleType (id) ON DELETE RESTRICT
+    , identifier text
+    , email text
+    , sms text
+    , phone text
+    , password text
+);
+
+CREATE TABLE UserIds (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id) ON DELETE RESTRICT
+    , userSourcedId text NOT NULL
+    , type text NOT NULL
+    , identifier text NOT NULL
+);
+CREATE UNIQUE INDEX UserIdsIndex ON UserIds (userSourcedId, type);
+
+CREATE TABLE UserGrades (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id) ON DELETE RESTRICT
+    , userSourcedId text NOT NULL
+    , gradeTypeId integer NOT NULL REFERENCES GradeType (id) ON DELETE RESTRICT
+);
+CREATE UNIQUE INDEX UserGradesIndex ON UserGrades (userSourcedId, gradeTypeId);
+
+CREATE TABLE UserAgents (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id) ON DELETE RESTRICT
+    , userSourcedId text NOT NULL
+    , agentUserSourcedId text NOT NULL
+);
+CREATE UNIQUE INDEX UserAgentsIndex ON UserAgents (userSourcedId, agentUserSourcedId);
+
+CREATE TABLE UserOrgs (
+    id serial PRIMARY KEY
+    , statusTypeId integer NOT NULL REFERENCES StatusType (id) ON DELETE RESTRICT
+    , userSourcedId text NOT NULL
+    , orgSourcedId text NOT NULL
+);
+CREATE UNIQUE INDEX UserOrgsIndex ON UserOrgs (userSourcedId, orgSourcedId);
+
+-- OR:5.1
+CREATE VIEW AcademicSessionsJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', a.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', a.dateLastModified
+        , 'title', a.title
+        , 'startDate', a.startDate
+        , 'endDate', a.endDate
+        , 'type', SessionType.token
+        , 'parent', CASE WHEN a.parentSourcedId IS NOT NULL THEN
+            jsonb_build_object(
+                'href', 'academicSessions/' || a.parentSourcedId
+                , 'sourcedId', a.parentSourcedId
+                , 'type', 'academicSession'
+            ) END
+        , 'children', (
+            SELECT jsonb_agg(jsonb_build_object(
+                'href', 'academicSessions/' || c.sourcedId
+                , 'sourcedId', c.sourcedId
+                , 'type', 'academicSession'
+            ) ORDER BY c.sourcedId)
+            FROM AcademicSessions c
+            WHERE c.parentSourcedId = a.sourcedId
+        )
+        , 'schoolYear', a.schoolYear
+    ) AS academicSession
+    FROM
+        AcademicSessions a
+        LEFT JOIN StatusType ON a.statusTypeId = StatusType.id
+        LEFT JOIN SessionType ON a.sessionTypeId = SessionType.id
+;
+
+CREATE VIEW AcademicSessionsJsonArray AS
+    SELECT jsonb_build_object(
+        'academicSessions', coalesce(jsonb_agg(academicSession ORDER BY academicSession->>'sourcedId'), '[]')
+    ) AS academicSessions
+FROM AcademicSessionsJson
+;
+
+CREATE VIEW VwORGetAcademicSession AS
+    SELECT jsonb_build_object('academicSession', academicSession) AS academicSession
+FROM AcademicSessionsJson
+;
+
+CREATE VIEW VwORGetAllGradingPeriods AS
+    SELECT jsonb_build_object(
+        'academicSessions', coalesce(jsonb_agg(academicSession ORDER BY academicSession->>'sourcedId'), '[]')
+    ) AS academicSessions
+FROM AcademicSessionsJson
+WHERE academicSession->>'type' = 'gradingPeriod'
+;
+
+CREATE VIEW VwORGetGradingPeriod AS
+    SELECT jsonb_build_object('academicSession', academicSession) AS academicSession
+FROM AcademicSessionsJson
+WHERE academicSession->>'type' = 'gradingPeriod'
+;
+
+CREATE VIEW VwORGetAllTerms AS
+    SELECT jsonb_build_object(
+        'academicSessions', coalesce(jsonb_agg(academicSession ORDER BY academicSession->>'sourcedId'), '[]')
+    ) AS academicSessions
+FROM AcademicSessionsJson
+WHERE academicSession->>'type' = 'term'
+;
+
+CREATE VIEW VwORGetTerm AS
+    SELECT jsonb_build_object('academicSession', academicSession) AS academicSession
+FROM AcademicSessionsJson
+WHERE academicSession->>'type' = 'term'
+;
+
+CREATE VIEW PeriodsJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', Periods.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', Periods.dateLastModified
+        , 'title', Periods.title
+        , 'periodCode', Periods.periodCode
+        , 'description', Periods.description
+        , 'orgs', (
+            SELECT jsonb_agg(jsonb_build_object(
+                'href', 'users/' || OrgPeriods.orgSourcedId
+                , 'sourcedId', OrgPeriods.orgSourcedId
+                , 'type', 'org'
+            ) ORDER BY OrgPeriods.orgSourcedId)
+            FROM OrgPeriods
+            WHERE OrgPeriods.periodSourcedId = Periods.sourcedId
+                AND OrgPeriods.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+    ) AS period
+    FROM
+        Periods
+        LEFT JOIN StatusType ON Periods.statusTypeId = StatusType.id
+;
+
+CREATE VIEW PeriodsJsonArray AS
+    SELECT jsonb_build_object(
+        'periods', coalesce(jsonb_agg(period ORDER BY period->>'sourcedId'), '[]')
+    ) AS periods
+FROM PeriodsJson
+;
+
+CREATE VIEW SubjectsJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', Subjects.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', Subjects.dateLastModified
+        , 'title', Subjects.title
+        , 'subjectCode', Subjects.subjectCode
+    ) AS subject
+    FROM
+        Subjects
+        LEFT JOIN StatusType ON Subjects.statusTypeId = StatusType.id
+;
+
+CREATE VIEW SubjectsJsonArray AS
+    SELECT jsonb_build_object(
+        'subjects', coalesce(jsonb_agg(subject ORDER BY subject->>'sourcedId'), '[]')
+    ) AS subjects
+FROM SubjectsJson
+;
+
+-- OR 5.3
+CREATE VIEW CoursesJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', Courses.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', Courses.dateLastModified
+        , 'title', Courses.title
+        , 'schoolYear', CASE WHEN Courses.schoolYearSourcedId IS NOT NULL THEN
+            jsonb_build_object(
+                'href', 'academicSessions/' || Courses.schoolYearSourcedId
+                , 'sourcedId', Courses.schoolYearSourcedId
+                , 'type', 'academicSession'
+            ) END
+        , 'courseCode', Courses.courseCode
+        , 'grades', (
+            SELECT jsonb_agg(GradeType.token ORDER BY GradeType.id)
+            FROM CourseGrades
+            INNER JOIN GradeType ON CourseGrades.gradeTypeId = GradeType.id
+            WHERE CourseGrades.courseSourcedId = Courses.sourcedId
+                AND CourseGrades.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'subjects', (
+            SELECT jsonb_agg(Subjects.title ORDER BY Subjects.sourcedId)
+            FROM CourseSubjects
+            INNER JOIN Subjects ON CourseSubjects.subjectSourcedId = Subjects.sourcedId
+            WHERE CourseSubjects.courseSourcedId = Courses.sourcedId
+                AND CourseSubjects.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'org', jsonb_build_object(
+            'href', 'orgs/' || Courses.orgSourcedId
+            , 'sourcedId', Courses.orgSourcedId
+            , 'type', 'org'
+        )
+        , 'subjectCodes', (
+            SELECT jsonb_agg(Subjects.subjectCode ORDER BY Subjects.sourcedId)
+            FROM CourseSubjects
+            INNER JOIN Subjects ON CourseSubjects.subjectSourcedId = Subjects.sourcedId
+            WHERE CourseSubjects.courseSourcedId = Courses.sourcedId
+                AND CourseSubjects.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        -- TODO: resources
+    ) AS course
+    FROM
+        Courses
+        LEFT JOIN StatusType ON Courses.statusTypeId = StatusType.id
+;
+
+CREATE VIEW CoursesJsonArray AS
+    SELECT jsonb_build_object(
+        'courses', coalesce(jsonb_agg(course ORDER BY course->>'sourcedId'), '[]')
+    ) AS courses
+FROM CoursesJson
+;
+
+CREATE VIEW VwORGetCourse AS
+    SELECT jsonb_build_object('course', course) AS course
+FROM CoursesJson
+;
+
+-- OR 5.5
+CREATE VIEW EnrollmentsJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', Enrollments.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', Enrollments.dateLastModified
+        , 'role', RoleType.token
+        , 'primary', Enrollments."primary"
+        , 'user', jsonb_build_object(
+            'href', 'users/' || Enrollments.userSourcedId
+            , 'sourcedId', Enrollments.userSourcedId
+            , 'type', 'user'
+        )
+        , 'class', jsonb_build_object(
+            'href', 'classes/' || Enrollments.classSourcedId
+            , 'sourcedId', Enrollments.classSourcedId
+            , 'type', 'class'
+        )
+        , 'school', jsonb_build_object(
+            'href', 'orgs/' || Enrollments.orgSourcedId
+            , 'sourcedId', Enrollments.orgSourcedId
+            , 'type', 'org'
+        )
+        , 'beginDate', Enrollments.beginDate
+        , 'endDate', Enrollments.endDate
+    ) AS enrollment
+    FROM
+        Enrollments
+        LEFT JOIN StatusType ON Enrollments.statusTypeId = StatusType.id
+        LEFT JOIN RoleType ON Enrollments.roleTypeId = RoleType.id
+;
+
+CREATE VIEW EnrollmentsJsonArray AS
+    SELECT jsonb_build_object(
+        'enrollments', coalesce(jsonb_agg(enrollment ORDER BY enrollment->>'sourcedId'), '[]')
+    ) AS enrollments
+FROM EnrollmentsJson
+;
+
+CREATE VIEW VwORGetEnrollment AS
+    SELECT jsonb_build_object('enrollment', enrollment) AS enrollment
+FROM EnrollmentsJson
+;
+
+-- OR:5.8
+CREATE VIEW OrgsJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', Orgs.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', Orgs.dateLastModified
+        , 'name', Orgs.name
+        , 'type', OrgType.token
+        , 'identifier', Orgs.identifier
+        , 'parent', CASE WHEN Orgs.parentSourcedId IS NOT NULL THEN
+            jsonb_build_object(
+                'href', 'orgs/' || Orgs.parentSourcedId
+                , 'sourcedId', Orgs.parentSourcedId
+                , 'type', 'org'
+            ) END
+        , 'children', (
+            SELECT jsonb_agg(jsonb_build_object(
+                'href', 'orgs/' || c.sourcedId
+                , 'sourcedId', c.sourcedId
+                , 'type', 'org'
+            ) ORDER BY c.sourcedId)
+            FROM Orgs c
+            WHERE c.parentSourcedId = Orgs.sourcedId
+        )
+    ) AS org
+    FROM
+        Orgs
+        LEFT JOIN StatusType ON Orgs.statusTypeId = StatusType.id
+        LEFT JOIN OrgType ON Orgs.orgTypeId = OrgType.id
+;
+
+CREATE VIEW OrgsJsonArray AS
+    SELECT jsonb_build_object(
+        'orgs', coalesce(jsonb_agg(org ORDER BY org->>'sourcedId'), '[]')
+    ) AS orgs
+FROM OrgsJson
+;
+
+CREATE VIEW VwORGetOrg AS
+    SELECT jsonb_build_object('org', org) AS org
+FROM OrgsJson
+;
+
+CREATE VIEW VwORGetAllSchools AS
+    SELECT jsonb_build_object(
+        'orgs', coalesce(jsonb_agg(org ORDER BY org->>'sourcedId'), '[]')
+    ) AS orgs
+FROM OrgsJson
+WHERE org->>'type' = 'school'
+;
+
+CREATE VIEW VwORGetSchool AS
+    SELECT jsonb_build_object('org', org) AS org
+FROM OrgsJson
+WHERE org->>'type' = 'school'
+;
+
+CREATE VIEW ClassesJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', Classes.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', Classes.dateLastModified
+        , 'title', Classes.title
+        , 'classCode', Classes.classCode
+        , 'classType', ClassType.token
+        , 'locations', Classes.location
+        , 'grades', (
+            SELECT jsonb_agg(GradeType.token ORDER BY GradeType.id)
+            FROM ClassGrades
+            INNER JOIN GradeType ON ClassGrades.gradeTypeId = GradeType.id
+            WHERE ClassGrades.classSourcedId = Classes.sourcedId
+                AND ClassGrades.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'subjects', (
+            SELECT jsonb_agg(Subjects.title ORDER BY Subjects.sourcedId)
+            FROM ClassSubjects
+            INNER JOIN Subjects ON ClassSubjects.subjectSourcedId = Subjects.sourcedId
+            WHERE ClassSubjects.classSourcedId = Classes.sourcedId
+                AND ClassSubjects.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'course', jsonb_build_object(
+            'href', 'courses/' || Classes.courseSourcedId
+            , 'sourcedId', Classes.courseSourcedId
+            , 'type', 'course'
+        )
+        , 'school', jsonb_build_object(
+            'href', 'orgs/' || Classes.orgSourcedId
+            , 'sourcedId', Classes.orgSourcedId
+            , 'type', 'org'
+        )
+        , 'terms', (
+            SELECT jsonb_agg(jsonb_build_object(
+                'href', 'academicSessions/' || ClassAcademicSessions.academicSessionSourcedId
+                , 'sourcedId', ClassAcademicSessions.academicSessionSourcedId
+                , 'type', 'academicSession'
+            ) ORDER BY ClassAcademicSessions.academicSessionSourcedId)
+            FROM ClassAcademicSessions
+            WHERE ClassAcademicSessions.classSourcedId = Classes.sourcedId
+                AND ClassAcademicSessions.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'subjectCodes', (
+            SELECT jsonb_agg(Subjects.subjectCode ORDER BY Subjects.sourcedId)
+            FROM ClassSubjects
+            INNER JOIN Subjects ON ClassSubjects.subjectSourcedId = Subjects.sourcedId
+            WHERE ClassSubjects.classSourcedId = Classes.sourcedId
+                AND ClassSubjects.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'periods', (
+            SELECT jsonb_agg(Periods.periodCode ORDER BY Periods.sourcedId)
+            FROM ClassPeriods
+            INNER JOIN Periods ON ClassPeriods.periodSourcedId = Periods.sourcedId
+            WHERE ClassPeriods.classSourcedId = Classes.sourcedId
+                AND ClassPeriods.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+    ) AS class
+    FROM
+        Classes
+        LEFT JOIN StatusType ON Classes.statusTypeId = StatusType.id
+        LEFT JOIN ClassType ON Classes.classTypeId = ClassType.id
+;
+
+CREATE VIEW ClassesJsonArray AS
+    SELECT jsonb_build_object(
+        'classes', coalesce(jsonb_agg(class ORDER BY class->>'sourcedId'), '[]')
+    ) AS classes
+FROM ClassesJson
+;
+
+CREATE VIEW VwORGetClass AS
+    SELECT jsonb_build_object('class', class) AS class
+FROM ClassesJson
+;
+
+-- OR 5.11
+CREATE VIEW UsersJson AS
+    SELECT jsonb_build_object(
+        'sourcedId', Users.sourcedId
+        , 'status', StatusType.token
+        , 'dateLastModified', Users.dateLastModified
+        , 'username', Users.username
+        , 'userIds', (
+            SELECT jsonb_agg(jsonb_build_object(
+                'type', UserIds.type
+                , 'identifier', UserIds.identifier
+            ) ORDER BY UserIds.type)
+            FROM UserIds
+            WHERE UserIds.userSourcedId = Users.sourcedId
+                AND UserIds.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'enabledUser', Users.enabledUser
+        , 'givenName', Users.givenName
+        , 'familyName', Users.familyName
+        , 'middleName', Users.middleName
+        , 'role', RoleType.token
+        , 'identifier', Users.identifier
+        , 'email', Users.email
+        , 'sms', Users.sms
+        , 'phone', Users.phone
+        , 'agents', (
+            SELECT jsonb_agg(jsonb_build_object(
+                'href', 'users/' || UserAgents.agentUserSourcedId
+                , 'sourcedId', UserAgents.agentUserSourcedId
+                , 'type', 'user'
+            ) ORDER BY UserAgents.agentUserSourcedId)
+            FROM UserAgents
+            WHERE UserAgents.userSourcedId = Users.sourcedId
+                AND UserAgents.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'orgs', (
+            SELECT jsonb_agg(jsonb_build_object(
+                'href', 'orgs/' || UserOrgs.orgSourcedId
+                , 'sourcedId', UserOrgs.orgSourcedId
+                , 'type', 'org'
+            ) ORDER BY UserOrgs.orgSourcedId)
+            FROM UserOrgs
+            WHERE UserOrgs.userSourcedId = Users.sourcedId
+                AND UserOrgs.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'grades', (
+            SELECT jsonb_agg(GradeType.token ORDER BY GradeType.id)
+            FROM UserGrades
+            INNER JOIN GradeType ON UserGrades.gradeTypeId = GradeType.id
+            WHERE UserGrades.userSourcedId = Users.sourcedId
+                AND UserGrades.statusTypeId = ( SELECT id FROM StatusType WHERE token = 'active' )
+        )
+        , 'password', Users.password
+    ) AS "user"
+    FROM
+        Users
+        LEFT JOIN StatusType ON Users.statusTypeId = StatusType.id
+        LEFT JOIN RoleType ON Users.roleTypeId = RoleType.id
+;
+
+CREATE VIEW UsersJsonArray AS
+    SELECT jsonb_build_object(
+        'users', coalesce(jsonb_agg("user" ORDER BY "user"->>'sourcedId'), '[]')
+    ) AS users
+FROM UsersJson
+;
+
+CREATE VIEW VwORGetUser AS
+    SELECT jsonb_build_object('user', "user") AS "user"
+FROM UsersJson
+;
+
+CREATE VIEW VwORGetAllStudents AS
+    SELECT jsonb_build_object(
+        'users', coalesce(jsonb_agg("user" ORDER BY "user"->>'sourcedId'), '[]')
+    ) AS users
+FROM UsersJson
+WHERE "user"->>'role' = 'student'
+;
+
+CREATE VIEW VwORGetStudent AS
+    SELECT jsonb_build_object('user', "user") AS "user"
+FROM UsersJson
+WHERE "user"->>'role' = 'student'
+;
+
+CREATE VIEW VwORGetAllTeachers AS
+    SELECT jsonb_build_object(
+        'users', coalesce(jsonb_agg("user" ORDER BY "user"->>'sourcedId'), '[]')
+    ) AS users
+FROM UsersJson
+WHERE "user"->>'role' = 'teacher'
+;
+
+CREATE VIEW VwORGetTeacher AS
+    SELECT jsonb_build_object('user', "user") AS "user"
+FROM UsersJson
+WHERE "user"->>'role' = 'teacher'
+;
+
+-- Upsert triggers, inserting a json record into its view upserts the tables behind it
+-- Links not passed with a record are set to 'tobedeleted' rather than deleted
+
+CREATE FUNCTION UpsertAcademicSessionsJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW.academicSession;
+BEGIN
+    INSERT INTO AcademicSessions (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , title
+        , startDate
+        , endDate
+        , sessionTypeId
+        , parentSourcedId
+        , schoolYear
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j->>'title'
+        , or_date(j->>'startDate')
+        , or_date(j->>'endDate')
+        , (SELECT id FROM SessionType WHERE token = j->>'type')
+        , j#>>'{parent,sourcedId}'
+        , j->>'schoolYear'
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , title = excluded.title
+        , startDate = excluded.startDate
+        , endDate = excluded.endDate
+        , sessionTypeId = excluded.sessionTypeId
+        , parentSourcedId = excluded.parentSourcedId
+        , schoolYear = excluded.schoolYear
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertAcademicSessionsJson
+    INSTEAD OF INSERT ON AcademicSessionsJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertAcademicSessionsJson();
+
+CREATE FUNCTION UpsertPeriodsJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW.period;
+BEGIN
+    INSERT INTO Periods (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , title
+        , periodCode
+        , description
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j->>'title'
+        , j->>'periodCode'
+        , j->>'description'
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , title = excluded.title
+        , periodCode = excluded.periodCode
+        , description = excluded.description
+    ;
+
+    UPDATE OrgPeriods
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE periodSourcedId = j->>'sourcedId';
+
+    INSERT INTO OrgPeriods (periodSourcedId, statusTypeId, orgSourcedId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , org->>'sourcedId'
+    FROM jsonb_array_elements(or_array(j->'orgs')) AS org
+    WHERE org->>'sourcedId' IS NOT NULL
+    ON CONFLICT (periodSourcedId, orgSourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertPeriodsJson
+    INSTEAD OF INSERT ON PeriodsJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertPeriodsJson();
+
+CREATE FUNCTION UpsertClassesJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW.class;
+BEGIN
+    INSERT INTO Classes (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , title
+        , classCode
+        , classTypeId
+        , location
+        , courseSourcedId
+        , orgSourcedId
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j->>'title'
+        , j->>'classCode'
+        , (SELECT id FROM ClassType WHERE token = j->>'classType')
+        , j->>'location'
+        , j#>>'{course,sourcedId}'
+        , j#>>'{school,sourcedId}'
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , title = excluded.title
+        , classCode = excluded.classCode
+        , classTypeId = excluded.classTypeId
+        , location = excluded.location
+        , courseSourcedId = excluded.courseSourcedId
+        , orgSourcedId = excluded.orgSourcedId
+    ;
+
+    UPDATE ClassGrades
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE classSourcedId = j->>'sourcedId';
+
+    INSERT INTO ClassGrades (classSourcedId, statusTypeId, gradeTypeId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , GradeType.id
+    FROM jsonb_array_elements_text(or_array(j->'grades')) AS grades(value)
+    INNER JOIN GradeType ON GradeType.token = grades.value
+    ON CONFLICT (classSourcedId, gradeTypeId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+
+    UPDATE ClassSubjects
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE classSourcedId = j->>'sourcedId';
+
+    INSERT INTO ClassSubjects (classSourcedId, statusTypeId, subjectSourcedId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , Subjects.sourcedId
+    FROM jsonb_array_elements_text(or_array(j->'subjectCodes')) AS sc(value)
+    INNER JOIN Subjects ON Subjects.subjectCode = sc.value
+    ON CONFLICT (classSourcedId, subjectSourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+
+    UPDATE ClassAcademicSessions
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE classSourcedId = j->>'sourcedId';
+
+    INSERT INTO ClassAcademicSessions (classSourcedId, statusTypeId, academicSessionSourcedId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , term->>'sourcedId'
+    FROM jsonb_array_elements(or_array(j->'terms')) AS term
+    WHERE term->>'sourcedId' IS NOT NULL
+    ON CONFLICT (classSourcedId, academicSessionSourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+
+    UPDATE ClassPeriods
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE classSourcedId = j->>'sourcedId';
+
+    INSERT INTO ClassPeriods (classSourcedId, statusTypeId, periodSourcedId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , Periods.sourcedId
+    FROM jsonb_array_elements_text(or_array(j->'periods')) AS pc(value)
+    INNER JOIN Periods ON Periods.periodCode = pc.value
+    ON CONFLICT (classSourcedId, periodSourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertClassesJson
+    INSTEAD OF INSERT ON ClassesJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertClassesJson();
+
+CREATE FUNCTION UpsertOrgsJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW.org;
+BEGIN
+    INSERT INTO Orgs (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , name
+        , orgTypeId
+        , identifier
+        , parentSourcedId
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j->>'name'
+        , (SELECT id FROM OrgType WHERE token = j->>'type')
+        , j->>'identifier'
+        , j#>>'{parent,sourcedId}'
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , name = excluded.name
+        , orgTypeId = excluded.orgTypeId
+        , identifier = excluded.identifier
+        , parentSourcedId = excluded.parentSourcedId
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertOrgsJson
+    INSTEAD OF INSERT ON OrgsJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertOrgsJson();
+
+CREATE FUNCTION UpsertSubjectsJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW.subject;
+BEGIN
+    INSERT INTO Subjects (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , title
+        , subjectCode
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j->>'title'
+        , j->>'subjectCode'
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , title = excluded.title
+        , subjectCode = excluded.subjectCode
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertSubjectsJson
+    INSTEAD OF INSERT ON SubjectsJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertSubjectsJson();
+
+CREATE FUNCTION UpsertCoursesJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW.course;
+BEGIN
+    INSERT INTO Courses (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , title
+        , schoolYearSourcedId
+        , courseCode
+        , orgSourcedId
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j->>'title'
+        , j#>>'{schoolYear,sourcedId}'
+        , j->>'courseCode'
+        , j#>>'{org,sourcedId}'
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , title = excluded.title
+        , schoolYearSourcedId = excluded.schoolYearSourcedId
+        , courseCode = excluded.courseCode
+        , orgSourcedId = excluded.orgSourcedId
+    ;
+
+    UPDATE CourseGrades
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE courseSourcedId = j->>'sourcedId';
+
+    INSERT INTO CourseGrades (courseSourcedId, statusTypeId, gradeTypeId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , GradeType.id
+    FROM jsonb_array_elements_text(or_array(j->'grades')) AS grades(value)
+    INNER JOIN GradeType ON GradeType.token = grades.value
+    ON CONFLICT (courseSourcedId, gradeTypeId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+
+    UPDATE CourseSubjects
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE courseSourcedId = j->>'sourcedId';
+
+    INSERT INTO CourseSubjects (courseSourcedId, statusTypeId, subjectSourcedId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , Subjects.sourcedId
+    FROM jsonb_array_elements_text(or_array(j->'subjectCodes')) AS sc(value)
+    INNER JOIN Subjects ON Subjects.subjectCode = sc.value
+    ON CONFLICT (courseSourcedId, subjectSourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertCoursesJson
+    INSTEAD OF INSERT ON CoursesJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertCoursesJson();
+
+CREATE FUNCTION UpsertEnrollmentsJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW.enrollment;
+BEGIN
+    INSERT INTO Enrollments (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , userSourcedId
+        , classSourcedId
+        , orgSourcedId
+        , roleTypeId
+        , "primary"
+        , beginDate
+        , endDate
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j#>>'{user,sourcedId}'
+        , j#>>'{class,sourcedId}'
+        , j#>>'{school,sourcedId}'
+        , (SELECT id FROM RoleType WHERE token = j->>'role')
+        , (j->>'primary')::integer
+        , or_date(j->>'beginDate')
+        , or_date(j->>'endDate')
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , userSourcedId = excluded.userSourcedId
+        , classSourcedId = excluded.classSourcedId
+        , orgSourcedId = excluded.orgSourcedId
+        , roleTypeId = excluded.roleTypeId
+        , "primary" = excluded."primary"
+        , beginDate = excluded.beginDate
+        , endDate = excluded.endDate
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertEnrollmentsJson
+    INSTEAD OF INSERT ON EnrollmentsJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertEnrollmentsJson();
+
+CREATE FUNCTION UpsertUsersJson() RETURNS trigger AS $$
+DECLARE
+    j jsonb := NEW."user";
+BEGIN
+    INSERT INTO Users (
+        sourcedId
+        , statusTypeId
+        , dateLastModified
+        , username
+        , enabledUser
+        , givenName
+        , familyName
+        , middleName
+        , roleTypeId
+        , identifier
+        , email
+        , sms
+        , phone
+        , password
+    )
+    VALUES (
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = j->>'status')
+        , or_timestamp(j->>'dateLastModified')
+        , j->>'username'
+        , (j->>'enabledUser')::integer
+        , j->>'givenName'
+        , j->>'familyName'
+        , j->>'middleName'
+        , (SELECT id FROM RoleType WHERE token = j->>'role')
+        , j->>'identifier'
+        , j->>'email'
+        , j->>'sms'
+        , j->>'phone'
+        , j->>'password'
+    )
+    ON CONFLICT (sourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , dateLastModified = excluded.dateLastModified
+        , username = excluded.username
+        , enabledUser = excluded.enabledUser
+        , givenName = excluded.givenName
+        , familyName = excluded.familyName
+        , middleName = excluded.middleName
+        , roleTypeId = excluded.roleTypeId
+        , identifier = excluded.identifier
+        , email = excluded.email
+        , sms = excluded.sms
+        , phone = excluded.phone
+        , password = excluded.password
+    ;
+
+    UPDATE UserIds
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE userSourcedId = j->>'sourcedId';
+
+    INSERT INTO UserIds (userSourcedId, statusTypeId, type, identifier)
+    SELECT DISTINCT ON (userIds->>'type')
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , userIds->>'type'
+        , userIds->>'identifier'
+    FROM jsonb_array_elements(or_array(j->'userIds')) AS userIds
+    WHERE userIds->>'type' IS NOT NULL AND userIds->>'identifier' IS NOT NULL
+    ON CONFLICT (userSourcedId, type) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+        , identifier = excluded.identifier
+    ;
+
+    UPDATE UserOrgs
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE userSourcedId = j->>'sourcedId';
+
+    INSERT INTO UserOrgs (userSourcedId, statusTypeId, orgSourcedId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , orgs->>'sourcedId'
+    FROM jsonb_array_elements(or_array(j->'orgs')) AS orgs
+    WHERE orgs->>'sourcedId' IS NOT NULL
+    ON CONFLICT (userSourcedId, orgSourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+
+    UPDATE UserAgents
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE userSourcedId = j->>'sourcedId';
+
+    INSERT INTO UserAgents (userSourcedId, statusTypeId, agentUserSourcedId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , agents->>'sourcedId'
+    FROM jsonb_array_elements(or_array(j->'agents')) AS agents
+    WHERE agents->>'sourcedId' IS NOT NULL
+    ON CONFLICT (userSourcedId, agentUserSourcedId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+
+    UPDATE UserGrades
+    SET statusTypeId = ( SELECT id FROM StatusType WHERE token = 'tobedeleted' )
+    WHERE userSourcedId = j->>'sourcedId';
+
+    INSERT INTO UserGrades (userSourcedId, statusTypeId, gradeTypeId)
+    SELECT DISTINCT
+        j->>'sourcedId'
+        , (SELECT id FROM StatusType WHERE token = 'active')
+        , GradeType.id
+    FROM jsonb_array_elements_text(or_array(j->'grades')) AS grades(value)
+    INNER JOIN GradeType ON GradeType.token = grades.value
+    ON CONFLICT (userSourcedId, gradeTypeId) DO UPDATE SET
+        statusTypeId = excluded.statusTypeId
+    ;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER TriggerUpsertUsersJson
+    INSTEAD OF INSERT ON UsersJson
+    FOR EACH ROW EXECUTE FUNCTION UpsertUsersJson();
+
+-- Reference data
+
+INSERT INTO scopes (scope) VALUES
+    ('roster-core.readonly')
+    , ('roster-core.createput')
+    , ('admin.readonly')
+    , ('roster.readonly')
+    , ('roster.createput')
+    , ('roster-demographics.readonly')
+    , ('resource.readonly')
+    , ('gradebook.readonly')
+    , ('gradebook.createput')
+    , ('gradebook.delete')
+    , ('admin.create')
+    , ('admin.delete');
+
+INSERT INTO StatusType (token) VALUES ('active'), ('tobedeleted'), ('inactive');
+
+INSERT INTO OrgType (token) VALUES
+    ('department'), ('school'), ('district'), ('local'), ('state'), ('national');
+
+INSERT INTO ClassType (token) VALUES ('homeroom'), ('scheduled');
+
+INSERT INTO RoleType (token) VALUES
+    ('administrator'), ('aide'), ('guardian'), ('parent'),
+    ('proctor'), ('relative'), ('student'), ('teacher');
+
+INSERT INTO SessionType (token) VALUES ('gradingPeriod'), ('semester'), ('schoolYear'), ('term');
+
+INSERT INTO GradeType (token, description) VALUES
+    ('IT', 'Infant/toddler'),
+    ('PR', 'Preschool'),
+    ('PK', 'Prekindergarten'),
+    ('TK', 'Transitional Kindergarten'),
+    ('KG', 'Kindergarten'),
+    ('01', 'First grade'),
+    ('02', 'Second grade'),
+    ('03', 'Third grade'),
+    ('04', 'Fourth grade'),
+    ('05', 'Fifth grade'),
+    ('06', 'Sixth grade'),
+    ('07', 'Seventh grade'),
+    ('08', 'Eigth grade'),
+    ('09', 'Ninth grade'),
+    ('10', 'Tenth grade'),
+    ('11', 'Eleventh grade'),
+    ('12', 'Twelfth grade'),
+    ('13', 'Grade 13'),
+    ('PS', 'Postsecondary'),
+    ('UG', 'Ungraded'),
+    ('Other', 'Other');
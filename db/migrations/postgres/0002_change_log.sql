@@ -0,0 +1,28 @@
+-- Permanent history of every record written, unlike audit_changes it is never purged
+-- and has no client_id so it can be served to vendors as a delta feed.
+-- before is NULL for created records, after is NULL for deleted records
+CREATE TABLE change_log (
+    id bigserial PRIMARY KEY -- cursor for incremental consumers
+    , "timestamp" timestamptz NOT NULL
+    , entity text NOT NULL -- singular json object name
+    , sourced_id text NOT NULL
+    , operation text NOT NULL CHECK (operation IN ('create', 'update', 'delete'))
+    , before jsonb
+    , after jsonb
+);
+CREATE INDEX ChangeLogRecordIndex ON change_log (entity, sourced_id, "timestamp");
+CREATE INDEX ChangeLogTimestampIndex ON change_log ("timestamp");
+
+-- existing records start their history at the upgrade
+INSERT INTO change_log ("timestamp", entity, sourced_id, operation, after)
+SELECT now(), e.entity, e.data->>'sourcedId', 'create', e.data
+FROM (
+    SELECT 'academicSession' AS entity, academicSession AS data FROM AcademicSessionsJson
+    UNION ALL SELECT 'period', period FROM PeriodsJson
+    UNION ALL SELECT 'subject', subject FROM SubjectsJson
+    UNION ALL SELECT 'course', course FROM CoursesJson
+    UNION ALL SELECT 'org', org FROM OrgsJson
+    UNION ALL SELECT 'class', class FROM ClassesJson
+    UNION ALL SELECT 'user', "user" FROM UsersJson
+    UNION ALL SELECT 'enrollment', enrollment FROM EnrollmentsJson
+) e;
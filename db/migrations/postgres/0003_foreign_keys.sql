@@ -0,0 +1,64 @@
+-- References between oneroster entities, matching the sqlite schema.
+-- NOT VALID enforces them for new and changed rows without failing the upgrade
+-- on GUIDRefs already dangling, `oneroster db check` lists those
+
+ALTER TABLE AcademicSessions ADD CONSTRAINT AcademicSessionsParentFk
+    FOREIGN KEY (parentSourcedId) REFERENCES AcademicSessions (sourcedId) DEFERRABLE INITIALLY DEFERRED NOT VALID;
+
+ALTER TABLE Classes ADD CONSTRAINT ClassesCourseFk
+    FOREIGN KEY (courseSourcedId) REFERENCES Courses (sourcedId) NOT VALID;
+ALTER TABLE Classes ADD CONSTRAINT ClassesOrgFk
+    FOREIGN KEY (orgSourcedId) REFERENCES Orgs (sourcedId) NOT VALID;
+ALTER TABLE ClassGrades ADD CONSTRAINT ClassGradesClassFk
+    FOREIGN KEY (classSourcedId) REFERENCES Classes (sourcedId) NOT VALID;
+ALTER TABLE ClassSubjects ADD CONSTRAINT ClassSubjectsClassFk
+    FOREIGN KEY (classSourcedId) REFERENCES Classes (sourcedId) NOT VALID;
+ALTER TABLE ClassSubjects ADD CONSTRAINT ClassSubjectsSubjectFk
+    FOREIGN KEY (subjectSourcedId) REFERENCES Subjects (sourcedId) NOT VALID;
+ALTER TABLE ClassAcademicSessions ADD CONSTRAINT ClassAcademicSessionsClassFk
+    FOREIGN KEY (classSourcedId) REFERENCES Classes (sourcedId) NOT VALID;
+ALTER TABLE ClassAcademicSessions ADD CONSTRAINT ClassAcademicSessionsSessionFk
+    FOREIGN KEY (academicSessionSourcedId) REFERENCES AcademicSessions (sourcedId) NOT VALID;
+
+ALTER TABLE OrgPeriods ADD CONSTRAINT OrgPeriodsOrgFk
+    FOREIGN KEY (orgSourcedId) REFERENCES Orgs (sourcedId) NOT VALID;
+ALTER TABLE OrgPeriods ADD CONSTRAINT OrgPeriodsPeriodFk
+    FOREIGN KEY (periodSourcedId) REFERENCES Periods (sourcedId) NOT VALID;
+ALTER TABLE ClassPeriods ADD CONSTRAINT ClassPeriodsClassFk
+    FOREIGN KEY (classSourcedId) REFERENCES Classes (sourcedId) NOT VALID;
+ALTER TABLE ClassPeriods ADD CONSTRAINT ClassPeriodsPeriodFk
+    FOREIGN KEY (periodSourcedId) REFERENCES Periods (sourcedId) NOT VALID;
+
+ALTER TABLE Courses ADD CONSTRAINT CoursesSchoolYearFk
+    FOREIGN KEY (schoolYearSourcedId) REFERENCES AcademicSessions (sourcedId) NOT VALID;
+ALTER TABLE Courses ADD CONSTRAINT CoursesOrgFk
+    FOREIGN KEY (orgSourcedId) REFERENCES Orgs (sourcedId) NOT VALID;
+ALTER TABLE CourseGrades ADD CONSTRAINT CourseGradesCourseFk
+    FOREIGN KEY (courseSourcedId) REFERENCES Courses (sourcedId) NOT VALID;
+ALTER TABLE CourseSubjects ADD CONSTRAINT CourseSubjectsCourseFk
+    FOREIGN KEY (courseSourcedId) REFERENCES Courses (sourcedId) NOT VALID;
+ALTER TABLE CourseSubjects ADD CONSTRAINT CourseSubjectsSubjectFk
+    FOREIGN KEY (subjectSourcedId) REFERENCES Subjects (sourcedId) NOT VALID;
+
+ALTER TABLE Enrollments ADD CONSTRAINT EnrollmentsUserFk
+    FOREIGN KEY (userSourcedId) REFERENCES Users (sourcedId) NOT VALID;
+ALTER TABLE Enrollments ADD CONSTRAINT EnrollmentsClassFk
+    FOREIGN KEY (classSourcedId) REFERENCES Classes (sourcedId) NOT VALID;
+ALTER TABLE Enrollments ADD CONSTRAINT EnrollmentsOrgFk
+    FOREIGN KEY (orgSourcedId) REFERENCES Orgs (sourcedId) NOT VALID;
+
+ALTER TABLE Orgs ADD CONSTRAINT OrgsParentFk
+    FOREIGN KEY (parentSourcedId) REFERENCES Orgs (sourcedId) DEFERRABLE INITIALLY DEFERRED NOT VALID;
+
+ALTER TABLE UserIds ADD CONSTRAINT UserIdsUserFk
+    FOREIGN KEY (userSourcedId) REFERENCES Users (sourcedId) ON DELETE CASCADE NOT VALID;
+ALTER TABLE UserGrades ADD CONSTRAINT UserGradesUserFk
+    FOREIGN KEY (userSourcedId) REFERENCES Users (sourcedId) ON DELETE CASCADE NOT VALID;
+ALTER TABLE UserAgents ADD CONSTRAINT UserAgentsUserFk
+    FOREIGN KEY (userSourcedId) REFERENCES Users (sourcedId) ON DELETE CASCADE DEFERRABLE INITIALLY DEFERRED NOT VALID;
+ALTER TABLE UserAgents ADD CONSTRAINT UserAgentsAgentFk
+    FOREIGN KEY (agentUserSourcedId) REFERENCES Users (sourcedId) ON DELETE CASCADE DEFERRABLE INITIALLY DEFERRED NOT VALID;
+ALTER TABLE UserOrgs ADD CONSTRAINT UserOrgsUserFk
+    FOREIGN KEY (userSourcedId) REFERENCES Users (sourcedId) ON DELETE CASCADE NOT VALID;
+ALTER TABLE UserOrgs ADD CONSTRAINT UserOrgsOrgFk
+    FOREIGN KEY (orgSourcedId) REFERENCES Orgs (sourcedId) ON DELETE CASCADE NOT VALID;
@@ -0,0 +1,15 @@
+-- User passwords are stored as bcrypt hashes from now on, plaintext left in Users is hashed when the
+-- server starts. Earlier versions of users kept in the history lose their plaintext passwords here
+INSERT INTO scopes (scope) VALUES
+    ('roster-password.readonly')
+    , ('roster-password.verify')
+ON CONFLICT DO NOTHING;
+
+UPDATE change_log SET before = before - 'password'
+WHERE entity = 'user' AND before -> 'password' IS NOT NULL;
+UPDATE change_log SET after = after - 'password'
+WHERE entity = 'user' AND after -> 'password' IS NOT NULL;
+UPDATE audit_changes SET before = before - 'password'
+WHERE entity = 'user' AND before -> 'password' IS NOT NULL;
+UPDATE audit_changes SET after = after - 'password'
+WHERE entity = 'user' AND after -> 'password' IS NOT NULL;
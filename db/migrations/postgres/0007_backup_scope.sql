@@ -0,0 +1,17 @@
+-- Downloading a backup needs admin.backup, a backup holds the credential hashes and the tenants'
+-- signing keys. Only the root admin created with the database is granted it, any other
+-- credential has to be given it explicitly
+INSERT INTO scopes (scope) VALUES
+    ('admin.backup')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO credential_scopes (credential_id, scope_id)
+SELECT
+    c.id
+    , b.id
+FROM
+    credentials c
+    INNER JOIN scopes b ON b.scope = 'admin.backup'
+WHERE
+    c.tag = 'root admin'
+ON CONFLICT DO NOTHING;
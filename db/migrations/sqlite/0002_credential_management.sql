@@ -0,0 +1,26 @@
+-- Credential disabling, expiry and last use
+ALTER TABLE credentials ADD COLUMN "disabled" integer NOT NULL DEFAULT 0; -- bool 0/1
+ALTER TABLE credentials ADD COLUMN "expires" text; -- RFC3339, NULL never expires
+ALTER TABLE credentials ADD COLUMN "last_used" text; -- RFC3339, time of last successful login
+
+INSERT
+    OR IGNORE INTO scopes (
+        scope)
+    VALUES (
+        'roster.readonly')
+    , (
+        'roster.createput')
+    , (
+        'roster-demographics.readonly')
+    , (
+        'resource.readonly')
+    , (
+        'gradebook.readonly')
+    , (
+        'gradebook.createput')
+    , (
+        'gradebook.delete')
+    , (
+        'admin.create')
+    , (
+        'admin.delete');
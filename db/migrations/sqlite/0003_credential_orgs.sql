@@ -0,0 +1,9 @@
+-- Restricts a credential to the listed orgs and their descendants
+-- org_sourced_id is not a foreign key so credentials can be bound before the org is synced
+CREATE TABLE credential_orgs (
+    "id" integer PRIMARY KEY AUTOINCREMENT
+    , "credential_id" integer NOT NULL
+    , "org_sourced_id" text NOT NULL
+    , FOREIGN KEY (credential_id) REFERENCES credentials (id) ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX CredentialOrgsIndex ON credential_orgs (credential_id, org_sourced_id);
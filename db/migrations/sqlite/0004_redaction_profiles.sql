@@ -0,0 +1,17 @@
+-- Fields stripped or masked from every response to a credential
+CREATE TABLE redaction_profiles (
+    "id" integer PRIMARY KEY AUTOINCREMENT
+    , "name" text UNIQUE NOT NULL
+);
+
+CREATE TABLE redaction_profile_fields (
+    "id" integer PRIMARY KEY AUTOINCREMENT
+    , "profile_id" integer NOT NULL
+    , "entity" text NOT NULL -- singular json object name or '*'
+    , "field" text NOT NULL
+    , "action" text NOT NULL CHECK ("action" IN ('strip', 'mask'))
+    , FOREIGN KEY (profile_id) REFERENCES redaction_profiles (id) ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX RedactionProfileFieldsIndex ON redaction_profile_fields (profile_id, entity, field);
+
+ALTER TABLE credentials ADD COLUMN "redaction_profile_id" integer REFERENCES redaction_profiles (id) ON DELETE SET NULL;
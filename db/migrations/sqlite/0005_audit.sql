@@ -0,0 +1,31 @@
+-- Audit tables
+-- client_id is not a foreign key so the trail outlives deleted credentials
+
+CREATE TABLE audit_requests (
+    "id" integer PRIMARY KEY AUTOINCREMENT
+    , "timestamp" text NOT NULL -- RFC3339
+    , "client_id" text NOT NULL
+    , "ip" text NOT NULL
+    , "method" text NOT NULL
+    , "route" text NOT NULL
+    , "query" text
+    , "status" integer NOT NULL
+    , "size" integer
+);
+CREATE INDEX AuditRequestsClientIndex ON audit_requests (client_id, timestamp);
+CREATE INDEX AuditRequestsTimestampIndex ON audit_requests (timestamp);
+
+-- before is NULL for created records, after is NULL for deleted records
+CREATE TABLE audit_changes (
+    "id" integer PRIMARY KEY AUTOINCREMENT
+    , "timestamp" text NOT NULL -- RFC3339
+    , "client_id" text NOT NULL
+    , "entity" text NOT NULL -- singular json object name
+    , "sourced_id" text NOT NULL
+    , "operation" text NOT NULL CHECK ("operation" IN ('create', 'update', 'delete'))
+    , "before" text
+    , "after" text
+);
+CREATE INDEX AuditChangesRecordIndex ON audit_changes (entity, sourced_id, timestamp);
+CREATE INDEX AuditChangesClientIndex ON audit_changes (client_id, timestamp);
+CREATE INDEX AuditChangesTimestampIndex ON audit_changes (timestamp);
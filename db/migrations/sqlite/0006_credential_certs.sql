@@ -0,0 +1,9 @@
+-- TLS client certificates identifying a credential, by SHA-256 fingerprint or subject DN
+CREATE TABLE credential_certs (
+    "id" integer PRIMARY KEY AUTOINCREMENT
+    , "credential_id" integer NOT NULL
+    , "kind" text NOT NULL CHECK ("kind" IN ('fingerprint', 'subject'))
+    , "value" text NOT NULL -- lowercase hex without separators or RFC4514 style DN
+    , FOREIGN KEY (credential_id) REFERENCES credentials (id) ON DELETE CASCADE
+);
+CREATE UNIQUE INDEX CredentialCertsIndex ON credential_certs (kind, value);
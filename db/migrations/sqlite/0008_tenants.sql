@@ -0,0 +1,10 @@
+-- Tenants hosted by this server, each with its own database and token signing keys.
+-- Only read from the database the server is started with
+CREATE TABLE tenants (
+    "name" text PRIMARY KEY
+    , "hostname" text UNIQUE -- lowercase, NULL when only reached by path prefix
+    , "database" text NOT NULL -- path or connection url
+    , "private_key" text NOT NULL -- pem
+    , "public_key" text NOT NULL -- pem
+    , "created" text NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Data keys encrypting personal data fields of users, each wrapped by the master key given to the server.
+-- Encrypted values carry the id of their data key so ids are never reused, db rekey replaces them all
+CREATE TABLE data_keys (
+    "id" integer PRIMARY KEY AUTOINCREMENT
+    , "wrapped_key" text NOT NULL -- base64 nonce, ciphertext and tag
+    , "created" text NOT NULL
+);
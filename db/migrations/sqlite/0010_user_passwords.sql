@@ -0,0 +1,18 @@
+-- User passwords are stored as bcrypt hashes from now on, plaintext left in Users is hashed when the
+-- server starts. Earlier versions of users kept in the history lose their plaintext passwords here
+INSERT
+    OR IGNORE INTO scopes (
+        scope)
+    VALUES (
+        'roster-password.readonly')
+    , (
+        'roster-password.verify');
+
+UPDATE change_log SET before = json_remove(before, '$.password')
+WHERE entity = 'user' AND json_extract(before, '$.password') IS NOT NULL;
+UPDATE change_log SET after = json_remove(after, '$.password')
+WHERE entity = 'user' AND json_extract(after, '$.password') IS NOT NULL;
+UPDATE audit_changes SET before = json_remove(before, '$.password')
+WHERE entity = 'user' AND json_extract(before, '$.password') IS NOT NULL;
+UPDATE audit_changes SET after = json_remove(after, '$.password')
+WHERE entity = 'user' AND json_extract(after, '$.password') IS NOT NULL;
@@ -0,0 +1,155 @@
+-- Full text indexes behind the search parameter, trigram tokens match any part of a word.
+-- Users and classes rows share the rowid of their record, all are kept in sync by the triggers below.
+-- Encrypted values are left out, their ciphertext would only produce false matches
+CREATE VIRTUAL TABLE UsersSearch USING fts5 (
+    name
+    , username
+    , email
+    , identifiers
+    , tokenize = 'trigram'
+);
+
+CREATE VIRTUAL TABLE ClassesSearch USING fts5 (
+    title
+    , classCode
+    , tokenize = 'trigram'
+);
+
+CREATE VIRTUAL TABLE OrgsSearch USING fts5 (
+    sourcedId UNINDEXED
+    , name
+    , identifier
+    , tokenize = 'trigram'
+);
+
+-- the Users identifier followed by the active UserIds identifiers
+CREATE VIEW UserSearchIdentifiers AS
+SELECT
+    u.id
+    , trim(coalesce(u.identifier, '') || ' ' || coalesce((
+        SELECT group_concat(i.identifier, ' ')
+        FROM UserIds i
+        WHERE i.userSourcedId = u.sourcedId
+            AND i.statusTypeId = (SELECT id FROM StatusType WHERE token = 'active')
+            AND i.identifier NOT LIKE 'enc:1:%'
+    ), '')) AS identifiers
+FROM Users u;
+
+CREATE TRIGGER TriggerInsertUsersSearch
+    AFTER INSERT ON Users
+    FOR EACH ROW
+BEGIN
+    INSERT INTO UsersSearch (rowid, name, username, email, identifiers)
+    VALUES (
+        NEW.id
+        , NEW.givenName || coalesce(' ' || NEW.middleName, '') || ' ' || NEW.familyName
+        , NEW.username
+        , CASE WHEN NEW.email LIKE 'enc:1:%' THEN NULL ELSE NEW.email END
+        , (SELECT identifiers FROM UserSearchIdentifiers WHERE id = NEW.id)
+    );
+END;
+
+CREATE TRIGGER TriggerUpdateUsersSearch
+    AFTER UPDATE ON Users
+    FOR EACH ROW
+BEGIN
+    UPDATE UsersSearch SET
+        name = NEW.givenName || coalesce(' ' || NEW.middleName, '') || ' ' || NEW.familyName
+        , username = NEW.username
+        , email = CASE WHEN NEW.email LIKE 'enc:1:%' THEN NULL ELSE NEW.email END
+        , identifiers = (SELECT identifiers FROM UserSearchIdentifiers WHERE id = NEW.id)
+    WHERE rowid = NEW.id;
+END;
+
+CREATE TRIGGER TriggerDeleteUsersSearch
+    AFTER DELETE ON Users
+    FOR EACH ROW
+BEGIN
+    DELETE FROM UsersSearch WHERE rowid = OLD.id;
+END;
+
+CREATE TRIGGER TriggerInsertUserIdsSearch
+    AFTER INSERT ON UserIds
+    FOR EACH ROW
+BEGIN
+    UPDATE UsersSearch
+    SET identifiers = (SELECT identifiers FROM UserSearchIdentifiers WHERE id = UsersSearch.rowid)
+    WHERE rowid = (SELECT id FROM Users WHERE sourcedId = NEW.userSourcedId);
+END;
+
+CREATE TRIGGER TriggerUpdateUserIdsSearch
+    AFTER UPDATE ON UserIds
+    FOR EACH ROW
+BEGIN
+    UPDATE UsersSearch
+    SET identifiers = (SELECT identifiers FROM UserSearchIdentifiers WHERE id = UsersSearch.rowid)
+    WHERE rowid = (SELECT id FROM Users WHERE sourcedId = NEW.userSourcedId);
+END;
+
+CREATE TRIGGER TriggerDeleteUserIdsSearch
+    AFTER DELETE ON UserIds
+    FOR EACH ROW
+BEGIN
+    UPDATE UsersSearch
+    SET identifiers = (SELECT identifiers FROM UserSearchIdentifiers WHERE id = UsersSearch.rowid)
+    WHERE rowid = (SELECT id FROM Users WHERE sourcedId = OLD.userSourcedId);
+END;
+
+CREATE TRIGGER TriggerInsertClassesSearch
+    AFTER INSERT ON Classes
+    FOR EACH ROW
+BEGIN
+    INSERT INTO ClassesSearch (rowid, title, classCode) VALUES (NEW.id, NEW.title, NEW.classCode);
+END;
+
+CREATE TRIGGER TriggerUpdateClassesSearch
+    AFTER UPDATE ON Classes
+    FOR EACH ROW
+BEGIN
+    UPDATE ClassesSearch SET title = NEW.title, classCode = NEW.classCode WHERE rowid = NEW.id;
+END;
+
+CREATE TRIGGER TriggerDeleteClassesSearch
+    AFTER DELETE ON Classes
+    FOR EACH ROW
+BEGIN
+    DELETE FROM ClassesSearch WHERE rowid = OLD.id;
+END;
+
+-- Orgs has a text primary key and VACUUM may renumber its rowids, so rows carry the sourcedId
+CREATE TRIGGER TriggerInsertOrgsSearch
+    AFTER INSERT ON Orgs
+    FOR EACH ROW
+BEGIN
+    INSERT INTO OrgsSearch (sourcedId, name, identifier)
+    VALUES (NEW.sourcedId, NEW.name, NEW.identifier);
+END;
+
+CREATE TRIGGER TriggerUpdateOrgsSearch
+    AFTER UPDATE ON Orgs
+    FOR EACH ROW
+BEGIN
+    UPDATE OrgsSearch SET name = NEW.name, identifier = NEW.identifier
+    WHERE sourcedId = NEW.sourcedId;
+END;
+
+CREATE TRIGGER TriggerDeleteOrgsSearch
+    AFTER DELETE ON Orgs
+    FOR EACH ROW
+BEGIN
+    DELETE FROM OrgsSearch WHERE sourcedId = OLD.sourcedId;
+END;
+
+INSERT INTO UsersSearch (rowid, name, username, email, identifiers)
+SELECT
+    u.id
+    , u.givenName || coalesce(' ' || u.middleName, '') || ' ' || u.familyName
+    , u.username
+    , CASE WHEN u.email LIKE 'enc:1:%' THEN NULL ELSE u.email END
+    , i.identifiers
+FROM Users u
+INNER JOIN UserSearchIdentifiers i ON i.id = u.id;
+
+INSERT INTO ClassesSearch (rowid, title, classCode) SELECT id, title, classCode FROM Classes;
+
+INSERT INTO OrgsSearch (sourcedId, name, identifier) SELECT sourcedId, name, identifier FROM Orgs;
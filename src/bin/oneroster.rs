@@ -1,10 +1,10 @@
 use async_std::task;
 use clap;
 use libre_oneroster::server::ServerError;
-use libre_oneroster::{client, server};
+use libre_oneroster::{client, logging, server};
+use std::time::Duration;
 
 fn main() {
-    env_logger::init();
     match cli() {
         Ok(_) => (),
         Err(err) => match err {
@@ -18,6 +18,17 @@ fn cli() -> Result<(), ServerError> {
     let matches = clap::App::new("libre-oneroster")
         .version("0.0.1")
         .setting(clap::AppSettings::ArgRequiredElseHelp)
+        .arg(
+            clap::Arg::new("log_format")
+                .about("how log lines are written, filtered by RUST_LOG")
+                .long("log-format")
+                .env("OR_LOG_FORMAT")
+                .global(true)
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
         .subcommand(
             clap::App::new("client")
                 .arg(
@@ -151,11 +162,11 @@ fn cli() -> Result<(), ServerError> {
                 )
                 .arg(
                     clap::Arg::new("database")
-                        .about("Path to the database file")
+                        .about("Path to the SQLite database file or a postgres:// connection url")
                         .short('d')
                         .long("database")
                         .takes_value(true)
-                        .value_name("PATH")
+                        .value_name("PATH|URL")
                         .default_value("oneroster.db"),
                 )
                 .arg(
@@ -169,13 +180,24 @@ fn cli() -> Result<(), ServerError> {
                 )
                 .arg(
                     clap::Arg::new("public_key")
-                        .about("path to the pem encoded public key used to decode the JWT")
+                        .about("path to the pem encoded public key or certificate used to decode the JWT")
                         .short('j')
                         .long("public-key")
                         .takes_value(true)
                         .value_name("PATH")
                         .required(true),
                 )
+                .arg(
+                    clap::Arg::new("jwt_algorithm")
+                        .about("JWT signing algorithm, defaults to RS256, ES256, ES384 or EdDSA by key type")
+                        .long("jwt-algorithm")
+                        .takes_value(true)
+                        .value_name("ALGORITHM")
+                        .possible_values(&[
+                            "RS256", "RS384", "RS512", "PS256", "PS384", "PS512", "ES256", "ES384",
+                            "EdDSA",
+                        ]),
+                )
                 .arg(
                     clap::Arg::new("web_private_key")
                         .about("path to the pem encoded private key used to secure HTTPS")
@@ -183,7 +205,7 @@ fn cli() -> Result<(), ServerError> {
                         .long("web-private-key")
                         .takes_value(true)
                         .value_name("PATH")
-                        .required(true),
+                        .required_unless_present_any(&["plain_http", "unix_socket"]),
                 )
                 .arg(
                     clap::Arg::new("web_public_key")
@@ -192,10 +214,258 @@ fn cli() -> Result<(), ServerError> {
                         .long("web-public-key")
                         .takes_value(true)
                         .value_name("PATH")
-                        .required(true),
+                        .required_unless_present_any(&["plain_http", "unix_socket"]),
+                )
+                .arg(
+                    clap::Arg::new("login_attempts")
+                        .about("failed logins per client id or IP before locking out, 0 disables")
+                        .long("login-attempts")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .default_value("5"),
+                )
+                .arg(
+                    clap::Arg::new("lockout")
+                        .about("seconds a client id or IP is locked out for after failed logins")
+                        .long("lockout")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("300"),
+                )
+                .arg(
+                    clap::Arg::new("rate_limit")
+                        .about("requests allowed per client id or IP each window, 0 disables")
+                        .long("rate-limit")
+                        .takes_value(true)
+                        .value_name("COUNT")
+                        .default_value("600"),
+                )
+                .arg(
+                    clap::Arg::new("rate_window")
+                        .about("length of the rate limit window in seconds")
+                        .long("rate-window")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("60"),
+                )
+                .arg(
+                    clap::Arg::new("db_readers")
+                        .about("database connections serving reads, writes use one more")
+                        .long("db-readers")
+                        .takes_value(true)
+                        .value_name("CONNECTIONS")
+                        .default_value("4"),
+                )
+                .arg(
+                    clap::Arg::new("db_busy_timeout")
+                        .about("seconds a query waits on a locked SQLite database before failing")
+                        .long("db-busy-timeout")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .default_value("5"),
+                )
+                .arg(
+                    clap::Arg::new("audit_retention")
+                        .about("days the audit trail of requests and changes is kept, 0 keeps forever")
+                        .long("audit-retention")
+                        .takes_value(true)
+                        .value_name("DAYS")
+                        .default_value("365"),
+                )
+                .arg(
+                    clap::Arg::new("purge_retention")
+                        .about("days after their last change tobedeleted records are hard deleted, 0 keeps forever")
+                        .long("purge-retention")
+                        .takes_value(true)
+                        .value_name("DAYS")
+                        .default_value("0"),
+                )
+                .arg(
+                    clap::Arg::new("encryption_key_file")
+                        .about("path to the hex encoded key encrypting personal data fields, or set OR_ENCRYPTION_KEY to the key itself")
+                        .long("encryption-key-file")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .env("OR_ENCRYPTION_KEY_FILE"),
+                )
+                .arg(
+                    clap::Arg::new("client_ca")
+                        .about("path to the pem encoded CA bundle used to verify TLS client certificates")
+                        .long("client-ca")
+                        .takes_value(true)
+                        .value_name("PATH"),
+                )
+                .arg(
+                    clap::Arg::new("client_auth")
+                        .about("with --client-ca, whether a bound certificate can stand in for the token or is required as well")
+                        .long("client-auth")
+                        .takes_value(true)
+                        .possible_values(&["either", "both"])
+                        .default_value("either"),
+                )
+                .arg(
+                    clap::Arg::new("plain_http")
+                        .about("serve plain HTTP on --address, for use behind a TLS terminating proxy")
+                        .long("http")
+                        .takes_value(false)
+                        .conflicts_with_all(&["client_ca", "unix_socket"]),
+                )
+                .arg(
+                    clap::Arg::new("unix_socket")
+                        .about("serve plain HTTP on a unix domain socket instead of --address")
+                        .long("unix-socket")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .conflicts_with("client_ca"),
+                )
+                .arg(
+                    clap::Arg::new("trusted_proxy")
+                        .about("IP or CIDR of a proxy whose X-Forwarded-For/Proto/Host headers are trusted")
+                        .long("trusted-proxy")
+                        .takes_value(true)
+                        .value_name("IP/CIDR")
+                        .multiple_occurrences(true)
+                        .use_delimiter(true),
+                )
+                .arg(
+                    clap::Arg::new("metrics")
+                        .about("serve /metrics, which needs no token, for a scraper on a private network")
+                        .long("metrics")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            clap::App::new("db")
+                .about("Manages the server database")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::App::new("migrate")
+                        .about("Applies pending schema migrations, the server also does this on startup")
+                        .arg(
+                            clap::Arg::new("database")
+                                .about("Path to the SQLite database file or a postgres:// connection url")
+                                .short('d')
+                                .long("database")
+                                .takes_value(true)
+                                .value_name("PATH|URL")
+                                .default_value("oneroster.db"),
+                        )
+                        .arg(
+                            clap::Arg::new("dry_run")
+                                .about("lists the migrations which would be applied without applying them")
+                                .long("dry-run")
+                                .takes_value(false),
+                        ),
+                )
+                .subcommand(
+                    clap::App::new("backup")
+                        .about("Writes a snapshot of the database while the server keeps running")
+                        .arg(
+                            clap::Arg::new("database")
+                                .about("Path to the SQLite database file or a postgres:// connection url")
+                                .short('d')
+                                .long("database")
+                                .takes_value(true)
+                                .value_name("PATH|URL")
+                                .default_value("oneroster.db"),
+                        )
+                        .arg(
+                            clap::Arg::new("path")
+                                .about("File to write, SQLite backups are database files and PostgreSQL backups pg_dump archives")
+                                .required(true)
+                                .value_name("PATH"),
+                        ),
+                )
+                .subcommand(
+                    clap::App::new("purge")
+                        .about("Hard deletes tobedeleted records nothing else points at, as the server's --purge-retention job")
+                        .arg(
+                            clap::Arg::new("database")
+                                .about("Path to the SQLite database file or a postgres:// connection url")
+                                .short('d')
+                                .long("database")
+                                .takes_value(true)
+                                .value_name("PATH|URL")
+                                .default_value("oneroster.db"),
+                        )
+                        .arg(
+                            clap::Arg::new("retention")
+                                .about("days since their last change records are kept for")
+                                .long("retention")
+                                .takes_value(true)
+                                .value_name("DAYS")
+                                .required(true),
+                        ),
+                )
+                .subcommand(
+                    clap::App::new("check")
+                        .about("Lists GUIDRefs to records which do not exist, grouped by the entity holding them")
+                        .arg(
+                            clap::Arg::new("database")
+                                .about("Path to the SQLite database file or a postgres:// connection url")
+                                .short('d')
+                                .long("database")
+                                .takes_value(true)
+                                .value_name("PATH|URL")
+                                .default_value("oneroster.db"),
+                        )
+                        .arg(
+                            clap::Arg::new("mark")
+                                .about("marks the records and links holding orphaned references tobedeleted")
+                                .long("mark")
+                                .takes_value(false),
+                        ),
+                )
+                .subcommand(
+                    clap::App::new("rekey")
+                        .about("Re-encrypts personal data fields under a new data key and optionally a new master key, stop the server first")
+                        .arg(
+                            clap::Arg::new("database")
+                                .about("Path to the SQLite database file or a postgres:// connection url")
+                                .short('d')
+                                .long("database")
+                                .takes_value(true)
+                                .value_name("PATH|URL")
+                                .default_value("oneroster.db"),
+                        )
+                        .arg(
+                            clap::Arg::new("key_file")
+                                .about("path to the current encryption key, or set OR_ENCRYPTION_KEY to the key itself")
+                                .long("key-file")
+                                .takes_value(true)
+                                .value_name("PATH")
+                                .env("OR_ENCRYPTION_KEY_FILE"),
+                        )
+                        .arg(
+                            clap::Arg::new("new_key_file")
+                                .about("path to the encryption key replacing the current one")
+                                .long("new-key-file")
+                                .takes_value(true)
+                                .value_name("PATH"),
+                        ),
+                )
+                .subcommand(
+                    clap::App::new("restore")
+                        .about("Verifies a backup and replaces the database with it, stop the server first")
+                        .arg(
+                            clap::Arg::new("database")
+                                .about("Path to the SQLite database file or a postgres:// connection url")
+                                .short('d')
+                                .long("database")
+                                .takes_value(true)
+                                .value_name("PATH|URL")
+                                .default_value("oneroster.db"),
+                        )
+                        .arg(
+                            clap::Arg::new("path")
+                                .about("Backup file written by db backup or the admin backup endpoint")
+                                .required(true)
+                                .value_name("PATH"),
+                        ),
                 ),
         )
         .get_matches();
+    logging::init(matches.value_of_t_or_exit("log_format"));
 
     match matches.subcommand() {
         Some(("client", cm)) => {
@@ -209,28 +479,100 @@ fn cli() -> Result<(), ServerError> {
             Ok(())
         }
         Some(("server", args)) => {
-            let encode_key = server::read_private_key(args.value_of("private_key").unwrap())
-                .map_err(|e| {
-                    log::error!("Problem reading private key");
-                    e
-                })?;
-            let decode_key = server::read_public_key(args.value_of("public_key").unwrap())
-                .map_err(|e| {
-                    log::error!("Problem reading public key");
-                    e
-                })?;
+            let jwt = server::read_jwt_keys(
+                args.value_of("private_key").unwrap(),
+                args.value_of("public_key").unwrap(),
+                args.value_of("jwt_algorithm"),
+            )?;
+            let transport = if let Some(path) = args.value_of("unix_socket") {
+                server::Transport::Unix(path.to_string())
+            } else if args.is_present("plain_http") {
+                server::Transport::Http
+            } else {
+                server::Transport::Tls {
+                    web_public_key: args.value_of_t("web_public_key").unwrap(),
+                    web_private_key: args.value_of_t("web_private_key").unwrap(),
+                    mtls: args
+                        .value_of("client_ca")
+                        .map(|ca| server::tls::MtlsConfig {
+                            client_ca: ca.to_string(),
+                            client_auth: args.value_of_t("client_auth").unwrap(),
+                        }),
+                }
+            };
             let c = server::Config {
                 database: args.value_of_t("database").unwrap(),
+                pool: server::db::PoolConfig {
+                    readers: args.value_of_t("db_readers").unwrap(),
+                    busy_timeout: Duration::from_secs(args.value_of_t("db_busy_timeout").unwrap()),
+                },
                 init: args.is_present("init"),
                 socket_address: args.value_of_t("socket_address").unwrap(),
-                encode_key,
-                decode_key,
-                web_public_key: args.value_of_t("web_public_key").unwrap(),
-                web_private_key: args.value_of_t("web_private_key").unwrap(),
+                jwt,
+                limits: server::limits::LimitConfig {
+                    login_attempts: args.value_of_t("login_attempts").unwrap(),
+                    lockout: Duration::from_secs(args.value_of_t("lockout").unwrap()),
+                    requests: args.value_of_t("rate_limit").unwrap(),
+                    window: Duration::from_secs(args.value_of_t("rate_window").unwrap()),
+                },
+                transport,
+                audit: server::audit::AuditConfig {
+                    retention_days: args.value_of_t("audit_retention").unwrap(),
+                },
+                purge: server::purge::PurgeConfig {
+                    retention_days: args.value_of_t("purge_retention").unwrap(),
+                },
+                proxies: server::proxy::TrustedProxies {
+                    ranges: match args.is_present("trusted_proxy") {
+                        true => args.values_of_t_or_exit("trusted_proxy"),
+                        false => Vec::new(),
+                    },
+                    unix: false,
+                },
+                metrics: args.is_present("metrics"),
+                encryption: server::read_encryption_key(args.value_of("encryption_key_file"))?,
             };
             task::block_on(server::run(c)).unwrap();
             Ok(())
         }
+        Some(("db", db)) => match db.subcommand() {
+            Some(("migrate", args)) => task::block_on(server::migrate(
+                args.value_of("database").unwrap(),
+                args.is_present("dry_run"),
+            )),
+            Some(("backup", args)) => task::block_on(server::backup(
+                args.value_of("database").unwrap(),
+                args.value_of("path").unwrap(),
+            )),
+            Some(("purge", args)) => task::block_on(server::purge(
+                args.value_of("database").unwrap(),
+                args.value_of_t_or_exit("retention"),
+            )),
+            Some(("check", args)) => task::block_on(server::check(
+                args.value_of("database").unwrap(),
+                args.is_present("mark"),
+            )),
+            Some(("rekey", args)) => {
+                let key =
+                    server::read_encryption_key(args.value_of("key_file"))?.ok_or_else(|| {
+                        ServerError::Encryption("no encryption key given".to_string())
+                    })?;
+                let new_key = match args.value_of("new_key_file") {
+                    Some(path) => server::read_encryption_key(Some(path))?,
+                    None => None,
+                };
+                task::block_on(server::rekey(
+                    args.value_of("database").unwrap(),
+                    key,
+                    new_key,
+                ))
+            }
+            Some(("restore", args)) => task::block_on(server::restore(
+                args.value_of("database").unwrap(),
+                args.value_of("path").unwrap(),
+            )),
+            _ => Ok(()),
+        },
         Some(("sync", args)) => {
             let or = crate::client::Config {
                 url: args.value_of_t("api").unwrap(),
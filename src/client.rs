@@ -36,16 +36,15 @@ async fn login(c: &surf::Client, conf: Config) -> surf::Result<String> {
         "client_id={}&client_secret={}&scope={}",
         conf.client_id, conf.client_secret, conf.scope
     );
-    log::debug!("{:?}", cred);
+    log::debug!("logging in as: {}", conf.client_id);
     let mut r = c
         .post("auth/login")
         .body(cred)
         .header("content-type", "application/x-www-form-urlencoded")
         .await?;
-    log::debug!("response: {:?}", r);
+    log::debug!("login response: {}", r.status());
     let t: model::TokenReturn = r.body_json().await?;
     let token = t.access_token;
-    log::debug!("token={}", token);
     Ok(token)
 }
 
@@ -77,7 +77,7 @@ impl FromStr for Provider {
 
 async fn connect_database(connection_string: &str) -> Client<TcpStream> {
     let creds = tiberius::Config::from_ado_string(connection_string).unwrap();
-    log::debug!("SQL server connection info: {:?}", creds);
+    log::debug!("connecting to SQL server: {}", creds.get_addr());
     let tcp = TcpStream::connect_named(&creds).await.unwrap();
     let client = Client::connect(creds, tcp).await.unwrap();
     return client;
@@ -1,3 +1,4 @@
 pub mod client;
+pub mod logging;
 pub mod model;
 pub mod server;
@@ -14,7 +14,7 @@ pub(crate) struct TokenReturn {
 // in the spec output, they are for the purposes
 // of ingest. Their required state is enforced by
 // the accompanying sql query
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GUIDRef {
     pub href: Option<String>,
@@ -41,7 +41,7 @@ pub struct AcademicSession {
 }
 
 #[skip_serializing_none]
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Period {
     pub sourced_id: String,
@@ -54,7 +54,7 @@ pub struct Period {
 }
 
 #[skip_serializing_none]
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Subject {
     pub sourced_id: String,
@@ -65,7 +65,7 @@ pub struct Subject {
 }
 
 #[skip_serializing_none]
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Class {
     pub sourced_id: String,
@@ -86,7 +86,7 @@ pub struct Class {
 }
 
 #[skip_serializing_none]
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Course {
     pub sourced_id: String,
@@ -101,7 +101,7 @@ pub struct Course {
     pub subject_codes: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Enrollment {
     pub sourced_id: String,
@@ -116,7 +116,7 @@ pub struct Enrollment {
     pub end_date: Option<NaiveDate>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Org {
     pub sourced_id: String,
@@ -131,7 +131,7 @@ pub struct Org {
 }
 
 #[skip_serializing_none]
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct User {
     pub sourced_id: String,
@@ -154,7 +154,7 @@ pub struct User {
     pub password: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserId {
     #[serde(rename = "type")]
@@ -162,7 +162,7 @@ pub struct UserId {
     pub identifier: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 // required due to writing to db as
 // UpperCamelCase, causing serialization errors
@@ -172,7 +172,7 @@ pub enum StatusType {
     tobedeleted,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_camel_case_types)]
 pub enum OrgType {
     department,
@@ -183,7 +183,7 @@ pub enum OrgType {
     national,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_camel_case_types)]
 pub enum SessionType {
     gradingPeriod,
@@ -192,7 +192,7 @@ pub enum SessionType {
     term,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_camel_case_types)]
 pub enum RoleType {
     administrator,
@@ -205,7 +205,7 @@ pub enum RoleType {
     teacher,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_camel_case_types)]
 pub enum GUIDType {
     academicSession,
@@ -225,92 +225,92 @@ pub enum GUIDType {
     user,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_camel_case_types)]
 pub enum ClassType {
     homeroom,
     scheduled,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Classes {
     pub classes: Vec<Class>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ClassSingle {
     pub class: Class,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AcademicSessions {
     pub academic_sessions: Vec<AcademicSession>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AcademicSessionSingle {
     pub academic_session: AcademicSession,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Periods {
     pub periods: Vec<Period>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Orgs {
     pub orgs: Vec<Org>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct OrgSingle {
     pub org: Org,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Users {
     pub users: Vec<User>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserSingle {
     pub user: User,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Subjects {
     pub subjects: Vec<Subject>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Courses {
     pub courses: Vec<Course>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CourseSingle {
     pub course: Course,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Enrollments {
     pub enrollments: Vec<Enrollment>,
 }
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EnrollmentSingle {
     pub enrollment: Enrollment,
@@ -1,12 +1,27 @@
+pub mod audit;
 mod auth;
-mod db;
+pub mod db;
 pub mod errors;
+mod etag;
+pub mod limits;
+mod metrics;
+mod negotiate;
 mod params;
+pub mod proxy;
+pub mod purge;
+mod render;
+mod request_id;
+mod search;
+mod stream;
+mod tenant;
+pub mod tls;
 
+pub use auth::keys::{read_jwt_keys, JwtKeys};
+use auth::principal::Principal;
+use auth::scopes;
 pub use errors::*;
 use http_types::mime;
-use std::fs::File;
-use std::io::prelude::*;
+use std::sync::Arc;
 use tide::prelude::*;
 use tide::utils::After;
 use tide::Request;
@@ -16,93 +31,173 @@ type Result<T> = std::result::Result<T, ServerError>;
 
 #[derive(Clone)]
 pub(crate) struct State {
-    db: sqlx::SqlitePool,
-    encode_key: jsonwebtoken::EncodingKey,
-    decode_key: jsonwebtoken::DecodingKey,
+    db: Arc<dyn db::Storage>,
+    jwt: Arc<JwtKeys>,
+    limits: Arc<limits::Limiter>,
+    metrics: Arc<metrics::Metrics>,
+    /// `None` unless client certificates are verified
+    client_auth: Option<tls::ClientAuth>,
+    /// `None` in a tenant's own state, tenants cannot host tenants
+    tenants: Option<Arc<tenant::Tenants>>,
 }
 
 /// Creates a GET endpoint function
 /// $name takes the name of the function to generate as well as the matching DB req function
 /// $object takes the name of the top level json object within the collection { "myObject": [{}] }
 /// $wrapper takes the name of the top level json object as a string for JQ to use in querying
+/// $view takes the db::Collection read a record at a time when the request allows it
 macro_rules! create_get_endpoint {
-    ($name:ident, $object:ident, $wrapper:literal) => {
+    ($name:ident, $object:ident, $wrapper:literal, $view:ident) => {
         async fn $name(req: Request<State>) -> tide::Result {
-            let params = req.query()?;
-            let data = db::$name(&req.state().db).await?;
+            let params: params::Parameters = req.query()?;
+            let principal = Principal::from_request(&req)?;
+            let validators = etag::Validators::new(&req, principal).await?;
+            if let Some(res) = validators.not_modified(&req) {
+                return Ok(res);
+            }
+            let format = negotiate::Format::from_request(&req);
+            if stream::streams(&params) {
+                let db = req.state().db.as_ref();
+                let view = db::Collection::$view;
+                let (counts, snapshot) =
+                    stream::count(db, view, principal.clone(), &params).await?;
+                if counts.records == 0 || (counts.visible == 0 && principal.orgs.is_some()) {
+                    return Err(ServerError::NoContent.into());
+                }
+                let links = params::link_header_builder(&req, &params, counts.visible).await;
+                let body = stream::body(snapshot, view, principal.clone(), &params, format).await?;
+                return Ok(validators
+                    .headers(tide::Response::builder(200))
+                    .header("link", links)
+                    .header("x-total-count", counts.total.to_string())
+                    .header("vary", "accept")
+                    .content_type(format.mime())
+                    .body(body)
+                    .build());
+            }
+            let mut data = db::$name(req.state().db.as_ref(), params.as_of).await?;
+            if let Some(query) = &params.search {
+                // the index only knows the records as they are now
+                if params.as_of.is_some() {
+                    return Err(ServerError::InvalidParameters.into());
+                }
+                let entity = search::entity($wrapper)?;
+                let ranks = search::rank(req.state().db.as_ref(), entity, query).await?;
+                search::order(&mut data.$object, &ranks, |i| &i.sourced_id);
+            }
+            principal.retain(&mut data.$object)?;
             let links = params::link_header_builder(&req, &params, data.$object.len()).await;
+            let mut body = json!(data);
+            principal.redact(&mut body);
             let (output, total) =
-                params::apply_parameters(&json!(data).to_string(), &params, $wrapper).await?;
-            Ok(tide::Response::builder(200)
+                params::apply_parameters(&body.to_string(), &params, $wrapper).await?;
+            let output = render::render(format, output, $wrapper, params.fields.as_deref())?;
+            Ok(validators
+                .headers(tide::Response::builder(200))
                 .header("link", links)
                 .header("x-total-count", total.trim())
-                .content_type(mime::JSON)
+                .header("vary", "accept")
+                .content_type(format.mime())
                 .body(output)
                 .build())
         }
     };
 }
 
-create_get_endpoint!(get_all_classes, classes, "classes");
+create_get_endpoint!(get_all_classes, classes, "classes", Classes);
 create_get_endpoint!(
     get_all_academic_sessions,
     academic_sessions,
-    "academicSessions"
+    "academicSessions",
+    AcademicSessions
 );
-create_get_endpoint!(get_all_periods, periods, "periods");
-create_get_endpoint!(get_all_orgs, orgs, "orgs");
-create_get_endpoint!(get_all_users, users, "users");
-create_get_endpoint!(get_all_subjects, subjects, "subjects");
-create_get_endpoint!(get_all_courses, courses, "courses");
-create_get_endpoint!(get_all_enrollments, enrollments, "enrollments");
+create_get_endpoint!(get_all_periods, periods, "periods", Periods);
+create_get_endpoint!(get_all_orgs, orgs, "orgs", Orgs);
+create_get_endpoint!(get_all_users, users, "users", Users);
+create_get_endpoint!(get_all_subjects, subjects, "subjects", Subjects);
+create_get_endpoint!(get_all_courses, courses, "courses", Courses);
+create_get_endpoint!(get_all_enrollments, enrollments, "enrollments", Enrollments);
 create_get_endpoint!(
     get_all_grading_periods,
     academic_sessions,
-    "academicSessions"
+    "academicSessions",
+    GradingPeriods
 );
-create_get_endpoint!(get_all_schools, orgs, "orgs");
-create_get_endpoint!(get_all_students, users, "users");
-create_get_endpoint!(get_all_teachers, users, "users");
-create_get_endpoint!(get_all_terms, academic_sessions, "academicSessions");
+create_get_endpoint!(get_all_schools, orgs, "orgs", Schools);
+create_get_endpoint!(get_all_students, users, "users", Students);
+create_get_endpoint!(get_all_teachers, users, "users", Teachers);
+create_get_endpoint!(get_all_terms, academic_sessions, "academicSessions", Terms);
 
+/// Creates a GET endpoint function for a single record
+/// $object takes the name of the top level json object { "myObject": {} }
 macro_rules! create_get_endpoint_by_id {
-    ($name:ident) => {
+    ($name:ident, $object:ident) => {
         async fn $name(req: Request<State>) -> tide::Result {
             let id = req.param("id")?;
-            let data = db::$name(&req.state().db, id).await?;
-            Ok(tide::Response::builder(200)
+            let principal = Principal::from_request(&req)?;
+            let validators = etag::Validators::new(&req, principal).await?;
+            if let Some(res) = validators.not_modified(&req) {
+                return Ok(res);
+            }
+            let data = db::$name(req.state().db.as_ref(), id).await?;
+            if !principal.permits(&data.$object) {
+                return Err(ServerError::UnknownObject.into());
+            }
+            let mut body = json!(data);
+            principal.redact(&mut body);
+            Ok(validators
+                .headers(tide::Response::builder(200))
                 .content_type(mime::JSON)
                 .header("x-total-count", "1")
-                .body(json!(data).to_string())
+                .body(body.to_string())
                 .build())
         }
     };
 }
-create_get_endpoint_by_id!(get_academic_session);
-create_get_endpoint_by_id!(get_class);
-create_get_endpoint_by_id!(get_course);
-create_get_endpoint_by_id!(get_grading_period);
-create_get_endpoint_by_id!(get_enrollment);
-create_get_endpoint_by_id!(get_org);
-create_get_endpoint_by_id!(get_school);
-create_get_endpoint_by_id!(get_student);
-create_get_endpoint_by_id!(get_teacher);
-create_get_endpoint_by_id!(get_term);
-create_get_endpoint_by_id!(get_user);
+create_get_endpoint_by_id!(get_academic_session, academic_session);
+create_get_endpoint_by_id!(get_class, class);
+create_get_endpoint_by_id!(get_course, course);
+create_get_endpoint_by_id!(get_grading_period, academic_session);
+create_get_endpoint_by_id!(get_enrollment, enrollment);
+create_get_endpoint_by_id!(get_org, org);
+create_get_endpoint_by_id!(get_school, org);
+create_get_endpoint_by_id!(get_student, user);
+create_get_endpoint_by_id!(get_teacher, user);
+create_get_endpoint_by_id!(get_term, academic_session);
+create_get_endpoint_by_id!(get_user, user);
 
 macro_rules! create_get_collection_endpoint_by_id {
     ($name:ident, $object:ident, $wrapper:literal) => {
         async fn $name(req: Request<State>) -> tide::Result {
             let id = req.param("id")?;
-            let params = req.query()?;
-            let data = db::$name(&req.state().db, &id).await?;
+            let params: params::Parameters = req.query()?;
+            // the change log has no history of the joins behind these views
+            if params.as_of.is_some() {
+                return Err(ServerError::InvalidParameters.into());
+            }
+            let principal = Principal::from_request(&req)?;
+            if !principal.permits_org(id) {
+                return Err(ServerError::UnknownObject.into());
+            }
+            let validators = etag::Validators::new(&req, principal).await?;
+            if let Some(res) = validators.not_modified(&req) {
+                return Ok(res);
+            }
+            let mut data = db::$name(req.state().db.as_ref(), &id).await?;
+            principal.retain(&mut data.$object)?;
             let links = params::link_header_builder(&req, &params, data.$object.len()).await;
+            let mut body = json!(data);
+            principal.redact(&mut body);
             let (output, total) =
-                params::apply_parameters(&json!(data).to_string(), &params, $wrapper).await?;
-            Ok(tide::Response::builder(200)
+                params::apply_parameters(&body.to_string(), &params, $wrapper).await?;
+            let format = negotiate::Format::from_request(&req);
+            let output = render::render(format, output, $wrapper, params.fields.as_deref())?;
+            Ok(validators
+                .headers(tide::Response::builder(200))
                 .header("link", links)
                 .header("x-total-count", total.trim())
-                .content_type(mime::JSON)
+                .header("vary", "accept")
+                .content_type(format.mime())
                 .body(output)
                 .build())
         }
@@ -114,17 +209,76 @@ create_get_collection_endpoint_by_id!(get_students_for_school, users, "users");
 create_get_collection_endpoint_by_id!(get_teachers_for_school, users, "users");
 create_get_collection_endpoint_by_id!(get_enrollments_for_school, enrollments, "enrollments");
 
+/// Serves the change log oldest first, `next` is the cursor for the following page
+async fn get_changes(req: Request<State>) -> tide::Result {
+    let filter: db::ChangeFilter = req.query()?;
+    let principal = Principal::from_request(&req)?;
+    let validators = etag::Validators::new(&req, principal).await?;
+    if let Some(res) = validators.not_modified(&req) {
+        return Ok(res);
+    }
+    let changes = req.state().db.get_changes(&filter).await?;
+    let next = match changes.len() as u32 {
+        n if n == filter.limit => changes.last().map(|c| c.id),
+        _ => None,
+    };
+    let mut visible = Vec::new();
+    for mut change in changes {
+        if principal.scope_change(&mut change) {
+            for record in change.before.iter_mut().chain(change.after.iter_mut()) {
+                principal.redact_record(&change.entity, record);
+            }
+            visible.push(change);
+        }
+    }
+    Ok(validators
+        .headers(tide::Response::builder(200))
+        .content_type(mime::JSON)
+        .body(json!({ "changes": visible, "next": next }))
+        .build())
+}
+
 macro_rules! create_put_endpoint {
     ($i:ident) => {
         async fn $i(mut req: Request<State>) -> tide::Result {
             let json = to_vec(&mut req).await?;
-            log::debug!("put request for: {:?}", json);
-            db::$i(json, &req.state().db).await?;
+            let principal = Principal::from_request(&req)?;
+            log::debug!("{} from: {}", stringify!($i), principal.client_id);
+            db::$i(json, principal, req.state().db.as_ref()).await?;
             Ok(tide::Response::builder(200).build())
         }
     };
 }
 
+#[derive(Deserialize)]
+struct PasswordCheck {
+    password: String,
+}
+
+/// Checks a user's password for single sign-on bridges, repeated failures for a user
+/// lock the credential out of checking it like failed logins
+async fn verify_password(mut req: Request<State>) -> tide::Result {
+    let check: PasswordCheck = req.body_json().await?;
+    let id = req.param("id")?.to_string();
+    let principal = Principal::from_request(&req)?;
+    let limits = &req.state().limits;
+    let keys = vec![format!("password:{}:{}", principal.client_id, id)];
+    limits.check_login(&keys)?;
+    let data = db::get_user(req.state().db.as_ref(), &id).await?;
+    if !principal.permits(&data.user) {
+        return Err(ServerError::UnknownObject.into());
+    }
+    let verified = auth::passwords::verify(check.password, data.user.password).await?;
+    match verified {
+        true => limits.login_succeeded(&keys[0]),
+        false => limits.login_failed(&keys),
+    }
+    Ok(tide::Response::builder(200)
+        .content_type(mime::JSON)
+        .body(json!({ "verified": verified }))
+        .build())
+}
+
 create_put_endpoint!(put_academic_sessions);
 create_put_endpoint!(put_periods);
 create_put_endpoint!(put_orgs);
@@ -137,112 +291,295 @@ create_put_endpoint!(put_enrollments);
 #[derive(Debug)]
 pub struct Config {
     pub database: String,
+    pub pool: db::PoolConfig,
     pub init: bool,
     pub socket_address: std::net::SocketAddr,
-    pub encode_key: jsonwebtoken::EncodingKey,
-    pub decode_key: jsonwebtoken::DecodingKey,
-    pub web_public_key: String,
-    pub web_private_key: String,
+    pub jwt: JwtKeys,
+    pub transport: Transport,
+    pub limits: limits::LimitConfig,
+    pub audit: audit::AuditConfig,
+    pub purge: purge::PurgeConfig,
+    pub proxies: proxy::TrustedProxies,
+    /// serves /metrics, which needs no token, on the host and every tenant
+    pub metrics: bool,
+    /// encrypts personal data fields when set
+    pub encryption: Option<db::MasterKey>,
+}
+
+/// How the server accepts connections
+#[derive(Debug)]
+pub enum Transport {
+    /// HTTPS on the socket address, optionally verifying client certificates
+    Tls {
+        web_public_key: String,
+        web_private_key: String,
+        mtls: Option<tls::MtlsConfig>,
+    },
+    /// plain HTTP on the socket address, for use behind a TLS terminating proxy
+    Http,
+    /// plain HTTP on a unix domain socket
+    Unix(String),
 }
 
 pub async fn run(config: Config) -> tide::Result<()> {
     log::info!("starting server...");
     log::debug!("configuration: {:?}", config);
 
-    let path = "sqlite:".to_owned() + &config.database;
-    let pool = match db::init(&path, config.init).await {
-        Ok(pool) => pool,
+    let url = db::database_url(&config.database);
+    let db = match db::init(&url, config.init, &config.pool, config.encryption.as_ref()).await {
+        Ok(db) => db,
         Err(e) => {
             log::error!("Error: could not start server: {}", e);
             return Ok(());
         }
     };
 
+    let limits = Arc::new(limits::Limiter::new(config.limits));
+    let client_auth = match &config.transport {
+        Transport::Tls { mtls, .. } => mtls.as_ref().map(|m| m.client_auth),
+        _ => None,
+    };
+    let settings = tenant::Settings {
+        pool: config.pool.clone(),
+        audit: config.audit.clone(),
+        purge: config.purge.clone(),
+        limits: limits.clone(),
+        client_auth,
+        encryption: config.encryption.clone(),
+        metrics: config.metrics,
+    };
+    let tenants = tenant::Tenants::load(db.clone(), &url, settings).await?;
     let state = State {
-        db: pool,
-        encode_key: config.encode_key,
-        decode_key: config.decode_key,
+        db,
+        jwt: Arc::new(config.jwt),
+        limits,
+        metrics: Arc::new(metrics::Metrics::new(config.metrics)),
+        client_auth,
+        tenants: Some(Arc::new(tenants)),
     };
+    audit::spawn_purge(config.audit, state.db.clone());
+    purge::spawn_purge(config.purge, state.db.clone());
+    auth::passwords::spawn_upgrade(state.db.clone());
     let mut srv = tide::with_state(state);
 
+    let mut proxies = config.proxies;
+    proxies.unix |= matches!(config.transport, Transport::Unix(_));
+    // outermost so everything after sees the real client and public url
+    srv.with(proxy::Forwarded::new(proxies));
+    // hands tenant requests to their own app before the host's routes see them
+    srv.with(tenant::Route::new());
+    mount(&mut srv);
+    match config.transport {
+        Transport::Tls {
+            web_public_key,
+            web_private_key,
+            mtls: Some(mtls),
+        } => {
+            log::info!("ready on: https://{}", &config.socket_address);
+            let listener = tls::MtlsListener::new(
+                config.socket_address,
+                &web_public_key,
+                &web_private_key,
+                &mtls,
+            )?;
+            srv.listen(listener).await?;
+        }
+        Transport::Tls {
+            web_public_key,
+            web_private_key,
+            mtls: None,
+        } => {
+            log::info!("ready on: https://{}", &config.socket_address);
+            srv.listen(
+                TlsListener::build()
+                    .addrs(config.socket_address)
+                    .cert(web_public_key)
+                    .key(web_private_key),
+            )
+            .await?;
+        }
+        Transport::Http => {
+            log::info!("ready on: http://{}", &config.socket_address);
+            srv.listen(config.socket_address).await?;
+        }
+        Transport::Unix(path) => {
+            log::info!("ready on: unix socket {}", &path);
+            remove_stale_socket(&path)?;
+            srv.listen(format!("http+unix://{}", path)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the api to a server, used for the host and each tenant
+fn mount(srv: &mut tide::Server<State>) {
+    // first so every line logged for a request carries its id
+    srv.with(request_id::RequestId::new());
+    // outside ApiError so error bodies are compressed too
+    srv.with(negotiate::Compress::new());
+    // also before ApiError so requests are counted by their final status
+    srv.with(metrics::Measure::new());
+    // before ApiError so the audit trail sees the final status it sets
+    srv.with(audit::Audit::new());
     srv.with(After(errors::middleware::ApiError::new()));
-    log::info!("ready on: {}", &config.socket_address);
+    srv.with(limits::RateLimit::new());
     srv.at("/").get(|_| async { Ok("oneroster ui\n") });
+    srv.at("/healthz").get(|_| async { Ok("ok\n") });
+    srv.at("/readyz").get(readyz);
+    if srv.state().metrics.exposed {
+        srv.at("/metrics").get(get_metrics);
+    }
     srv.at("/auth/login").post(login);
     srv.at("/auth/check_token").get(check_token);
     // oneroster
+    // each route is guarded by the scopes the spec grants it, see auth::scopes
+    let core = || auth::middleware::Jwt::new(scopes::ROSTER_CORE);
+    let roster = || auth::middleware::Jwt::new(scopes::ROSTER);
     let mut authsrv = tide::with_state(srv.state().clone());
-    authsrv.with(auth::middleware::Jwt::new(vec![
-        "roster-core".to_string(),
-        "roster".to_string(),
-    ]));
     authsrv
         .at("/")
+        .with(core())
         .get(|_| async { Ok("hello protected world\n") });
-    authsrv.at("/orgs").get(get_all_orgs).put(put_orgs);
-    authsrv.at("/orgs/:id").get(get_org);
-    authsrv.at("/schools").get(get_all_schools);
-    authsrv.at("/schools/:id").get(get_school);
+    authsrv
+        .at("/orgs")
+        .with(core())
+        .get(get_all_orgs)
+        .put(put_orgs);
+    authsrv.at("/orgs/:id").with(core()).get(get_org);
+    authsrv.at("/schools").with(core()).get(get_all_schools);
+    authsrv.at("/schools/:id").with(core()).get(get_school);
     authsrv
         .at("/schools/:id/classes")
+        .with(roster())
         .get(get_classes_for_school);
     authsrv
         .at("/schools/:id/students")
+        .with(roster())
         .get(get_students_for_school);
     authsrv
         .at("/schools/:id/teachers")
+        .with(roster())
         .get(get_teachers_for_school);
     authsrv
         .at("/schools/:id/enrollments")
+        .with(roster())
         .get(get_enrollments_for_school);
-    authsrv.at("/classes").get(get_all_classes).put(put_classes);
-    authsrv.at("/classes/:id").get(get_class);
+    authsrv
+        .at("/classes")
+        .with(core())
+        .get(get_all_classes)
+        .put(put_classes);
+    authsrv.at("/classes/:id").with(core()).get(get_class);
     authsrv
         .at("/academicSessions")
+        .with(core())
         .get(get_all_academic_sessions)
         .put(put_academic_sessions);
     authsrv
         .at("/academicSessions/:id")
+        .with(core())
         .get(get_academic_session);
-    authsrv.at("/gradingPeriods").get(get_all_grading_periods);
-    authsrv.at("/gradingPeriods/:id").get(get_grading_period);
-    authsrv.at("/periods").get(get_all_periods).put(put_periods);
+    authsrv
+        .at("/gradingPeriods")
+        .with(core())
+        .get(get_all_grading_periods);
+    authsrv
+        .at("/gradingPeriods/:id")
+        .with(core())
+        .get(get_grading_period);
+    authsrv
+        .at("/periods")
+        .with(core())
+        .get(get_all_periods)
+        .put(put_periods);
     authsrv
         .at("/subjects")
+        .with(core())
         .get(get_all_subjects)
         .put(put_subjects);
-    authsrv.at("/courses").get(get_all_courses).put(put_courses);
-    authsrv.at("/courses/:id").get(get_course);
-    authsrv.at("/users").get(get_all_users).put(put_users);
-    authsrv.at("/users/:id").get(get_user);
-    authsrv.at("/students").get(get_all_students);
-    authsrv.at("/students/:id").get(get_student);
-    authsrv.at("/teachers").get(get_all_teachers);
-    authsrv.at("/teachers/:id").get(get_teacher);
-    authsrv.at("/terms").get(get_all_terms);
-    authsrv.at("/terms/:id").get(get_term);
+    authsrv
+        .at("/courses")
+        .with(core())
+        .get(get_all_courses)
+        .put(put_courses);
+    authsrv.at("/courses/:id").with(core()).get(get_course);
+    authsrv
+        .at("/users")
+        .with(core())
+        .get(get_all_users)
+        .put(put_users);
+    authsrv.at("/users/:id").with(core()).get(get_user);
+    authsrv
+        .at("/users/:id/verifyPassword")
+        .with(auth::middleware::Jwt::new(scopes::PASSWORD_VERIFY))
+        .post(verify_password);
+    authsrv.at("/students").with(core()).get(get_all_students);
+    authsrv.at("/students/:id").with(core()).get(get_student);
+    authsrv.at("/teachers").with(core()).get(get_all_teachers);
+    authsrv.at("/teachers/:id").with(core()).get(get_teacher);
+    authsrv.at("/changes").with(roster()).get(get_changes);
+    authsrv.at("/terms").with(core()).get(get_all_terms);
+    authsrv.at("/terms/:id").with(core()).get(get_term);
     authsrv
         .at("/enrollments")
+        .with(roster())
         .get(get_all_enrollments)
         .put(put_enrollments);
-    authsrv.at("/enrollments/:id").get(get_enrollment);
+    authsrv
+        .at("/enrollments/:id")
+        .with(roster())
+        .get(get_enrollment);
     // user management
     let mut adminsrv = tide::with_state(srv.state().clone());
-    adminsrv.with(auth::middleware::Jwt::new(vec!["admin".to_string()]));
+    adminsrv.with(auth::middleware::Jwt::new(scopes::ADMIN));
     adminsrv.at("/users").get(get_api_users);
     adminsrv.at("/user").post(create_api_user);
-    adminsrv.at("/user/:uuid").delete(delete_api_user);
+    adminsrv
+        .at("/user/:uuid")
+        .get(get_api_user)
+        .post(update_api_user)
+        .delete(delete_api_user);
+    adminsrv.at("/user/:uuid/secret").post(rotate_api_secret);
+    adminsrv.at("/redactions").get(get_redaction_profiles);
+    adminsrv.at("/redaction").post(put_redaction_profile);
+    adminsrv
+        .at("/redaction/:name")
+        .delete(delete_redaction_profile);
+    adminsrv.at("/audit/requests").get(get_audit_requests);
+    adminsrv.at("/audit/changes").get(get_audit_changes);
+    adminsrv
+        .at("/backup")
+        .with(auth::middleware::Jwt::new(scopes::BACKUP))
+        .get(get_backup);
+    adminsrv.at("/orphans").get(get_orphans).post(mark_orphans);
+    if srv.state().tenants.is_some() {
+        adminsrv.at("/tenants").get(get_tenants).post(create_tenant);
+        adminsrv.at("/tenants/:name").delete(delete_tenant);
+    }
 
     srv.at("/admin").nest(adminsrv);
     srv.at("/ims/oneroster/v1p1").nest(authsrv);
-    srv.listen(
-        TlsListener::build()
-            .addrs(config.socket_address)
-            .cert(config.web_public_key)
-            .key(config.web_private_key),
-        //config.socket_address
-    )
-    .await?;
+}
+
+/// Removes a socket left behind by a previous run, refusing to remove any other file
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(ServerError::Io(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path),
+            )))
+        }
+        Err(_) => (),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn remove_stale_socket(_path: &str) -> Result<()> {
     Ok(())
 }
 
@@ -268,51 +605,330 @@ async fn login(mut req: tide::Request<State>) -> tide::Result {
     log::debug!("login request");
     let creds: Creds = req.body_form().await?;
     log::info!("login attempt from: {}", creds.client_id);
-    let token = auth::credentials::login(creds, &req.state().db, &req.state().encode_key).await?;
-    Ok(tide::Response::builder(200).body(json!(token)).build())
+    let limits = &req.state().limits;
+    let keys = vec![
+        format!("client:{}", creds.client_id),
+        format!("ip:{}", limits::client_ip(&req)),
+    ];
+    limits.check_login(&keys)?;
+    match auth::credentials::login(creds, req.state().db.as_ref(), &req.state().jwt).await {
+        Ok(token) => {
+            // the IP keeps its failures, one known credential must not reset the count for
+            // guesses at every other client_id from the same address
+            limits.login_succeeded(&keys[0]);
+            Ok(tide::Response::builder(200).body(json!(token)).build())
+        }
+        Err(ServerError::InvalidLogin) => {
+            limits.login_failed(&keys);
+            req.state().metrics.login_failed();
+            Err(ServerError::InvalidLogin.into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether the server can serve requests: the database answers, its schema is the version
+/// this release expects and any encrypted fields can be read. Callers need no token
+/// so they only see which checks failed, the reasons are logged
+async fn readyz(req: tide::Request<State>) -> tide::Result {
+    let db = req.state().db.as_ref();
+    let check = |name: &str, failure: Option<String>| match failure {
+        None => "ok",
+        Some(reason) => {
+            log::warn!("not ready: {}: {}", name, reason);
+            "failed"
+        }
+    };
+    let (database, schema) = match db.schema_version().await {
+        Ok((version, latest)) if version == latest => (None, None),
+        Ok((version, latest)) => (
+            None,
+            Some(format!(
+                "database is at version {}, this release expects {}",
+                version, latest
+            )),
+        ),
+        Err(e) => (Some(e.to_string()), Some("unknown".to_string())),
+    };
+    let checks = json!({
+        "database": check("database", database),
+        "schema": check("schema", schema),
+        "keys": check("keys", db.check_keys().err().map(|e| e.to_string())),
+    });
+    let ready = checks
+        .as_object()
+        .into_iter()
+        .flatten()
+        .all(|(_, v)| v == "ok");
+    let status = if ready { 200 } else { 503 };
+    Ok(tide::Response::builder(status).body(checks).build())
+}
+
+async fn get_metrics(req: tide::Request<State>) -> tide::Result {
+    let out = req.state().metrics.render(req.state().db.as_ref()).await?;
+    Ok(tide::Response::builder(200)
+        .body(out)
+        .content_type("text/plain; version=0.0.4; charset=utf-8")
+        .build())
 }
 
 async fn create_api_user(mut req: tide::Request<State>) -> tide::Result {
     let new: db::CreateApiUser = req.body_json().await?;
-    let creds = db::create_api_user(new, &req.state().db).await?;
+    let creds = db::create_api_user(new, req.state().db.as_ref()).await?;
     Ok(tide::Response::builder(200).body(json!(creds)).build())
 }
 
 async fn delete_api_user(req: tide::Request<State>) -> tide::Result {
     let uuid = req.param("uuid")?;
-    db::delete_api_user(uuid, &req.state().db).await?;
+    req.state().db.delete_api_user(uuid).await?;
     Ok(tide::Response::builder(200).build())
 }
 
 async fn get_api_users(req: tide::Request<State>) -> tide::Result {
-    let res = db::get_api_users(&req.state().db).await?;
+    let res = req.state().db.get_api_users().await?;
     Ok(tide::Response::builder(200).body(json!(res)).build())
 }
 
-async fn check_token(req: tide::Request<State>) -> tide::Result<String> {
-    let token = auth::middleware::parse_auth_header(&req).await?;
-    if auth::jwt::validate_token(token, &req.state().decode_key).await {
-        return Ok("✔ Token valid\n".to_string());
+async fn get_api_user(req: tide::Request<State>) -> tide::Result {
+    let uuid = req.param("uuid")?;
+    let res = req.state().db.get_api_user(uuid).await?;
+    Ok(tide::Response::builder(200).body(json!(res)).build())
+}
+
+async fn update_api_user(mut req: tide::Request<State>) -> tide::Result {
+    let update: db::UpdateApiUser = req.body_json().await?;
+    let uuid = req.param("uuid")?;
+    let res = db::update_api_user(uuid, update, req.state().db.as_ref()).await?;
+    Ok(tide::Response::builder(200).body(json!(res)).build())
+}
+
+async fn rotate_api_secret(req: tide::Request<State>) -> tide::Result {
+    let uuid = req.param("uuid")?;
+    let creds = db::rotate_api_secret(uuid, req.state().db.as_ref()).await?;
+    Ok(tide::Response::builder(200).body(json!(creds)).build())
+}
+
+async fn get_redaction_profiles(req: tide::Request<State>) -> tide::Result {
+    let res = req.state().db.get_redaction_profiles().await?;
+    Ok(tide::Response::builder(200).body(json!(res)).build())
+}
+
+async fn put_redaction_profile(mut req: tide::Request<State>) -> tide::Result {
+    let profile: db::RedactionProfile = req.body_json().await?;
+    req.state().db.put_redaction_profile(profile).await?;
+    Ok(tide::Response::builder(200).build())
+}
+
+async fn delete_redaction_profile(req: tide::Request<State>) -> tide::Result {
+    let name = req.param("name")?;
+    req.state().db.delete_redaction_profile(name).await?;
+    Ok(tide::Response::builder(200).build())
+}
+
+async fn get_audit_requests(req: tide::Request<State>) -> tide::Result {
+    let filter: db::AuditFilter = req.query()?;
+    let res = req.state().db.get_audit_requests(&filter).await?;
+    Ok(tide::Response::builder(200).body(json!(res)).build())
+}
+
+async fn get_audit_changes(req: tide::Request<State>) -> tide::Result {
+    let filter: db::AuditFilter = req.query()?;
+    let principal = Principal::from_request(&req)?;
+    let mut res = req.state().db.get_audit_changes(&filter).await?;
+    for change in res.iter_mut() {
+        for record in change.before.iter_mut().chain(change.after.iter_mut()) {
+            principal.redact_record(&change.entity, record);
+        }
     }
-    Ok("✗ Token invalid\n".to_string())
+    Ok(tide::Response::builder(200).body(json!(res)).build())
 }
 
-pub fn read_private_key(path: &str) -> Result<jsonwebtoken::EncodingKey> {
-    let mut file = File::open(path)?;
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf)?;
-    let private_key = jsonwebtoken::EncodingKey::from_rsa_pem(&buf)?;
-    Ok(private_key)
+/// Lists GUIDRefs to records which do not exist, grouped by the entity holding them
+async fn get_orphans(req: tide::Request<State>) -> tide::Result {
+    let res = req.state().db.check_references(None).await?;
+    Ok(tide::Response::builder(200)
+        .body(json!(db::orphans_by_entity(res)))
+        .build())
 }
 
-pub fn read_public_key(path: &str) -> Result<jsonwebtoken::DecodingKey> {
-    let mut file = File::open(path)?;
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf)?;
-    let cert = openssl::x509::X509::from_pem(&buf)?;
-    let pem = cert.public_key()?.rsa()?.public_key_to_pem()?;
-    let public_key = jsonwebtoken::DecodingKey::from_rsa_pem(&pem)?;
-    Ok(public_key)
+/// Marks the records and links holding orphaned GUIDRefs tobedeleted
+async fn mark_orphans(req: tide::Request<State>) -> tide::Result {
+    let principal = Principal::from_request(&req)?;
+    let res = req
+        .state()
+        .db
+        .check_references(Some(&principal.client_id))
+        .await?;
+    Ok(tide::Response::builder(200)
+        .body(json!(db::orphans_by_entity(res)))
+        .build())
+}
+
+async fn get_tenants(req: tide::Request<State>) -> tide::Result {
+    let res = tenants(&req)?.list().await?;
+    Ok(tide::Response::builder(200).body(json!(res)).build())
+}
+
+/// Provisions a tenant and starts serving it, returning its root admin credential
+async fn create_tenant(mut req: tide::Request<State>) -> tide::Result {
+    let new: db::CreateTenant = req.body_json().await?;
+    let (tenant, creds) = tenants(&req)?.create(new).await?;
+    Ok(tide::Response::builder(200)
+        .body(json!({ "tenant": tenant, "credentials": creds }))
+        .build())
+}
+
+/// Stops serving a tenant, leaving its database in place
+async fn delete_tenant(req: tide::Request<State>) -> tide::Result {
+    let name = req.param("name")?;
+    tenants(&req)?.remove(name).await?;
+    Ok(tide::Response::builder(200).build())
+}
+
+fn tenants(req: &tide::Request<State>) -> Result<&tenant::Tenants> {
+    req.state()
+        .tenants
+        .as_deref()
+        .ok_or(ServerError::NoPermission)
+}
+
+/// Downloads a snapshot of the live database,
+/// a SQLite database file or a pg_dump custom format archive
+async fn get_backup(req: tide::Request<State>) -> tide::Result {
+    let path = std::env::temp_dir().join(format!("oneroster-{}.backup", uuid::Uuid::new_v4()));
+    let path = path.to_string_lossy().to_string();
+    create_private(&path)?;
+    if let Err(e) = req.state().db.backup(&path).await {
+        let _ = async_std::fs::remove_file(&path).await;
+        return Err(e.into());
+    }
+    let body = tide::Body::from_file(&path).await;
+    // the open file keeps streaming after it is unlinked
+    async_std::fs::remove_file(&path).await?;
+    Ok(tide::Response::builder(200)
+        .body(body?)
+        .content_type("application/octet-stream")
+        .header(
+            "Content-Disposition",
+            "attachment; filename=\"oneroster.backup\"",
+        )
+        .build())
+}
+
+/// Creates an empty file only its owner can read for a backup to be written to,
+/// other users of the host must not see the snapshot while it is downloaded
+fn create_private(path: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?;
+    Ok(())
+}
+
+/// Applies pending schema migrations to an existing database,
+/// `dry_run` only prints the migrations which would be applied
+pub async fn migrate(database: &str, dry_run: bool) -> Result<()> {
+    let url = db::database_url(database);
+    let migrations = db::migrate(&url, dry_run).await?;
+    if migrations.is_empty() {
+        println!("database is up to date");
+    }
+    for m in migrations {
+        match dry_run {
+            true => println!("would apply {}", m),
+            false => println!("applied {}", m),
+        }
+    }
+    Ok(())
+}
+
+/// Writes a snapshot of a database to `path`, safe while the server is running
+pub async fn backup(database: &str, path: &str) -> Result<()> {
+    let url = db::database_url(database);
+    db::backup(&url, path).await?;
+    println!("backed up to {}", path);
+    Ok(())
+}
+
+/// Hard deletes records marked tobedeleted over `retention_days` ago, the server's purge job run once
+pub async fn purge(database: &str, retention_days: u32) -> Result<()> {
+    let url = db::database_url(database);
+    let db = db::init(&url, false, &db::PoolConfig::default(), None).await?;
+    let count = purge::purge(retention_days, db.as_ref()).await?;
+    println!("purged {} records", count);
+    Ok(())
+}
+
+/// Lists GUIDRefs to records which do not exist, `mark` also marks the records holding them tobedeleted
+pub async fn check(database: &str, mark: bool) -> Result<()> {
+    let url = db::database_url(database);
+    let db = db::init(&url, false, &db::PoolConfig::default(), None).await?;
+    let orphans = db
+        .check_references(mark.then_some(db::CHECK_CLIENT_ID))
+        .await?;
+    if orphans.is_empty() {
+        println!("no orphaned references");
+    }
+    for (entity, orphans) in db::orphans_by_entity(orphans) {
+        println!("{}:", entity);
+        for o in orphans {
+            println!(
+                "  {} {}.{} -> missing {} {} ({})",
+                o.sourced_id, o.table, o.column, o.references, o.missing, o.status
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Re-encrypts personal data fields under a new data key, with `new_key` the master key is
+/// replaced as well, stop the server first
+pub async fn rekey(
+    database: &str,
+    key: db::MasterKey,
+    new_key: Option<db::MasterKey>,
+) -> Result<()> {
+    let url = db::database_url(database);
+    let count = db::rekey(&url, &key, new_key.as_ref()).await?;
+    println!("re-encrypted {} rows", count);
+    Ok(())
+}
+
+/// Reads the key encrypting personal data fields from a file,
+/// or else the OR_ENCRYPTION_KEY environment variable, as 64 hex characters
+pub fn read_encryption_key(path: Option<&str>) -> Result<Option<db::MasterKey>> {
+    let hex = match path {
+        Some(path) => std::fs::read_to_string(path)
+            .inspect_err(|_| log::error!("Problem reading encryption key: {}", path))?,
+        None => match std::env::var("OR_ENCRYPTION_KEY") {
+            Ok(hex) => hex,
+            Err(_) => return Ok(None),
+        },
+    };
+    Ok(Some(db::MasterKey::from_hex(&hex)?))
+}
+
+/// Replaces a database with a verified backup, stop the server first
+pub async fn restore(database: &str, path: &str) -> Result<()> {
+    let url = db::database_url(database);
+    db::restore(&url, path).await?;
+    println!(
+        "restored {}, pending migrations apply on the next start",
+        path
+    );
+    Ok(())
+}
+
+async fn check_token(req: tide::Request<State>) -> tide::Result<String> {
+    let token = auth::middleware::parse_auth_header(&req).await?;
+    if auth::jwt::validate_token(token, &req.state().jwt).await {
+        return Ok("✔ Token valid\n".to_string());
+    }
+    Ok("✗ Token invalid\n".to_string())
 }
 
 // tests
@@ -320,8 +936,8 @@ pub fn read_public_key(path: &str) -> Result<jsonwebtoken::DecodingKey> {
 #[async_std::test]
 async fn db() -> Result<()> {
     let path = "sqlite:db/rust_test.db";
-    db::init(path, true).await?;
-    let pool = db::connect(path).await?;
+    db::init(path, true, &db::PoolConfig::default(), None).await?;
+    let pool = sqlx::SqlitePool::connect(path).await?;
 
     sqlx::query(
         r#"INSERT INTO academicSessions (sourcedId, data) values (
@@ -0,0 +1,82 @@
+use crate::server::{db, limits, State};
+use async_std::task::JoinHandle;
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often expired audit entries are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// days audit entries are kept for, 0 keeps them forever
+    pub retention_days: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            retention_days: 365,
+        }
+    }
+}
+
+/// The client_id behind an authenticated request,
+/// set on the response by the Jwt middleware for the audit middleware to find
+#[derive(Debug, Clone)]
+pub(crate) struct Actor(pub(crate) String);
+
+/// Records every authenticated request once the final response status is known
+pub(crate) struct Audit {}
+
+impl Audit {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for Audit {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let db = req.state().db.clone();
+        let ip = limits::client_ip(&req);
+        let method = req.method().to_string();
+        let route = req.url().path().to_string();
+        let query = req.url().query().map(|q| q.to_string());
+        let res = next.run(req).await;
+        if let Some(Actor(client_id)) = res.ext::<Actor>() {
+            let entry = db::AuditRequest {
+                timestamp: Utc::now(),
+                client_id: client_id.clone(),
+                ip,
+                method,
+                route,
+                query,
+                status: u16::from(res.status()) as i64,
+                size: res.len().map(|l| l as i64),
+            };
+            // a failure to audit is logged rather than failing the request
+            if let Err(e) = db.record_request(&entry).await {
+                log::error!("could not record audit entry: {}", e);
+            }
+        }
+        Ok(res)
+    }
+}
+
+/// Purges expired audit entries now and then once a day, `None` when retention is off
+pub(crate) fn spawn_purge(config: AuditConfig, db: Arc<dyn db::Storage>) -> Option<JoinHandle<()>> {
+    if config.retention_days == 0 {
+        return None;
+    }
+    Some(async_std::task::spawn(async move {
+        loop {
+            let before = Utc::now() - chrono::Duration::days(config.retention_days.into());
+            match db.purge_audit(before).await {
+                Ok(count) => log::info!("purged {} audit entries before {}", count, before),
+                Err(e) => log::error!("could not purge audit entries: {}", e),
+            }
+            async_std::task::sleep(PURGE_INTERVAL).await;
+        }
+    }))
+}
@@ -1,3 +1,8 @@
 pub(crate) mod credentials;
 pub(crate) mod jwt;
+pub(crate) mod keys;
 pub(crate) mod middleware;
+pub(crate) mod passwords;
+pub(crate) mod principal;
+pub(crate) mod redaction;
+pub(crate) mod scopes;
@@ -1,6 +1,9 @@
 use crate::server;
-use crate::server::{auth::jwt, db, Result};
+use crate::server::auth::keys::JwtKeys;
+use crate::server::auth::scopes::Scopes;
+use crate::server::{auth::jwt, db, tls, Result};
 use bcrypt;
+use chrono::Utc;
 use rand::{rngs, Rng, RngCore};
 use uuid::Uuid;
 
@@ -12,16 +15,18 @@ pub(crate) struct NewCreds {
 // TODO: write tests in rust
 pub(crate) async fn login(
     creds: server::Creds,
-    db: &sqlx::SqlitePool,
-    key: &jsonwebtoken::EncodingKey,
+    db: &dyn db::Storage,
+    keys: &JwtKeys,
 ) -> Result<jwt::TokenReturn> {
-    let compare = db::get_api_creds(&creds.client_id, db).await;
+    let compare = db.get_api_creds(&creds.client_id).await;
     match compare {
         Ok(compare) => {
             let verify = bcrypt::verify(&creds.client_secret, &compare.client_secret)?;
             if verify {
+                check_active(&compare)?;
                 let scopes = verify_scopes(&compare.scope, &creds.scope).await?;
-                let token = jwt::create_token(creds.client_id, scopes, key).await?;
+                let token = jwt::create_token(creds.client_id.clone(), scopes, keys).await?;
+                db.touch_api_user(&creds.client_id).await?;
                 return Ok(token);
             }
         }
@@ -41,20 +46,44 @@ pub(crate) async fn login(
     Err(server::ServerError::InvalidLogin)
 }
 
-pub(crate) async fn verify_scopes(current: &String, requested: &String) -> Result<String> {
-    let mut matches: Vec<&str> = vec![];
-    log::debug!("{}, {}", current, requested);
-    for r in requested.split(' ') {
-        for c in current.split(' ') {
-            if r.eq(c) {
-                matches.push(c);
-            }
-        }
+/// Errors if a credential has been disabled or has expired
+pub(crate) fn check_active(creds: &db::ApiCreds) -> Result<()> {
+    if creds.disabled {
+        return Err(server::ServerError::CredentialDisabled);
     }
+    if creds.expires.is_some_and(|e| e <= Utc::now()) {
+        return Err(server::ServerError::CredentialExpired);
+    }
+    Ok(())
+}
+
+/// Resolves the credential a TLS client certificate is bound to,
+/// returning its client_id and every scope granted to it
+pub(crate) async fn certificate_login(
+    cert: &tls::ClientCert,
+    db: &dyn db::Storage,
+) -> Result<(String, String)> {
+    let client_id = db.get_cert_client_id(cert).await?.ok_or_else(|| {
+        log::info!(
+            "no credential bound to client certificate: {} ({})",
+            cert.subject,
+            cert.fingerprint
+        );
+        server::ServerError::UnknownClientCertificate
+    })?;
+    let creds = db.get_api_creds(&client_id).await?;
+    check_active(&creds)?;
+    Ok((client_id, creds.scope))
+}
+
+/// Returns the requested scopes which have been granted to the credential
+pub(crate) async fn verify_scopes(current: &str, requested: &str) -> Result<String> {
+    log::debug!("{}, {}", current, requested);
+    let granted = Scopes::parse_lenient(current);
+    let matches = granted.intersection(&Scopes::parse_lenient(requested));
     log::debug!("allowed scopes: {:?}", matches);
-    if matches.len() >= 1 {
-        let m = matches.join(" ");
-        return Ok(m);
+    if !matches.is_empty() {
+        return Ok(matches.to_string());
     }
     Err(server::ServerError::NoAuthorizedScopes)
 }
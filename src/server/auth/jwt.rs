@@ -1,5 +1,5 @@
+use crate::server::auth::keys::JwtKeys;
 use crate::server::Result;
-use jsonwebtoken;
 use std::time::SystemTime;
 use tide::prelude::*;
 
@@ -7,28 +7,21 @@ use tide::prelude::*;
 pub(crate) struct Claims {
     aud: String,
     exp: u64,
-    sub: String,
+    pub(crate) sub: String,
     pub(crate) scope: String,
 }
-// scopes:
-// roster-core.readonly roster.readonly roster-demographics.readonly
-// resource.readonly gradebook.readonly gradebook.createput gradebook.delete
-// admin.readonly admin.create admin.delete
+// scopes: see auth::scopes::CATALOGUE
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct TokenReturn {
-    access_token: String,
+    pub(crate) access_token: String,
     token_type: String,
     expires_in: u64,
     scope: String,
 }
 
-pub(crate) async fn create_token(
-    id: String,
-    scope: String,
-    encode_key: &jsonwebtoken::EncodingKey,
-) -> Result<TokenReturn> {
-    let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+pub(crate) async fn create_token(id: String, scope: String, keys: &JwtKeys) -> Result<TokenReturn> {
+    let header = jsonwebtoken::Header::new(keys.algorithm);
     let exp_in: u64 = 3600;
     let exp = SystemTime::now().duration_since(std::time::UNIX_EPOCH)?
         + std::time::Duration::from_secs(exp_in);
@@ -38,8 +31,8 @@ pub(crate) async fn create_token(
         sub: id,
         scope: scope.clone(),
     };
-    let token = jsonwebtoken::encode(&header, &claims, &encode_key)?;
-    log::debug!("creating token:\n{}", &token);
+    let token = jsonwebtoken::encode(&header, &claims, &keys.encode_key)?;
+    log::debug!("created token for: {}", claims.sub);
     let result = TokenReturn {
         access_token: token,
         token_type: "bearer".to_string(),
@@ -51,18 +44,17 @@ pub(crate) async fn create_token(
 
 pub(crate) async fn decode_token(
     token: String,
-    key: &jsonwebtoken::DecodingKey,
+    keys: &JwtKeys,
 ) -> Result<jsonwebtoken::TokenData<Claims>> {
-    let validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
-    let claims = jsonwebtoken::decode::<Claims>(&token, key, &validation)?;
+    let validation = jsonwebtoken::Validation::new(keys.algorithm);
+    let claims = jsonwebtoken::decode::<Claims>(&token, &keys.decode_key, &validation)?;
     Ok(claims)
 }
 
-pub(crate) async fn validate_token(token: String, key: &jsonwebtoken::DecodingKey) -> bool {
-    log::debug!("validating token:\n{}", token);
-    match decode_token(token, key).await {
+pub(crate) async fn validate_token(token: String, keys: &JwtKeys) -> bool {
+    match decode_token(token, keys).await {
         Ok(t) => {
-            log::debug!("validated:\n{:?}", t);
+            log::debug!("validated token for: {}", t.claims.sub);
             true
         }
         Err(_) => false,
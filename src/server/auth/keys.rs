@@ -0,0 +1,159 @@
+use crate::server::{Result, ServerError};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::rsa::Rsa;
+use std::fmt;
+use std::fs;
+
+/// The key pair used to sign and verify tokens and the algorithm matching the key type
+pub struct JwtKeys {
+    pub(crate) encode_key: EncodingKey,
+    pub(crate) decode_key: DecodingKey,
+    pub(crate) algorithm: Algorithm,
+}
+
+impl fmt::Debug for JwtKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JwtKeys({:?}, ..)", self.algorithm)
+    }
+}
+
+/// Reads a pem encoded private key (PKCS#1, SEC1 or PKCS#8) and a public key
+/// (X.509 certificate, SPKI or PKCS#1), picking the algorithm from the key type:
+/// RSA uses RS256 unless `algorithm` asks for another RSA algorithm such as PS256,
+/// EC P-256 uses ES256, EC P-384 uses ES384 and Ed25519 uses EdDSA
+pub fn read_jwt_keys(
+    private_key: &str,
+    public_key: &str,
+    algorithm: Option<&str>,
+) -> Result<JwtKeys> {
+    let private = fs::read(private_key)
+        .inspect_err(|_| log::error!("Problem reading private key: {}", private_key))?;
+    let public = fs::read(public_key)
+        .inspect_err(|_| log::error!("Problem reading public key: {}", public_key))?;
+    let algorithm = match algorithm {
+        Some(a) => Some(
+            a.parse::<Algorithm>()
+                .map_err(|_| ServerError::UnsupportedKey(format!("unknown algorithm {}", a)))?,
+        ),
+        None => None,
+    };
+    from_pem(&private, &public, algorithm)
+}
+
+/// Generates an Ed25519 key pair, returning the pem encoded PKCS#8 private key and SPKI public key
+pub(crate) fn generate_jwt_pem() -> Result<(String, String)> {
+    let key = PKey::generate_ed25519()?;
+    let private = String::from_utf8_lossy(&key.private_key_to_pem_pkcs8()?).to_string();
+    let public = String::from_utf8_lossy(&key.public_key_to_pem()?).to_string();
+    Ok((private, public))
+}
+
+pub(crate) fn from_pem(
+    private: &[u8],
+    public: &[u8],
+    algorithm: Option<Algorithm>,
+) -> Result<JwtKeys> {
+    let private = PKey::private_key_from_pem(private)?;
+    let public = read_public(public)?;
+    if !private.public_eq(&public) {
+        return Err(ServerError::UnsupportedKey(
+            "public key does not match the private key".to_string(),
+        ));
+    }
+    let key_algorithm = match private.id() {
+        Id::RSA => Algorithm::RS256,
+        Id::EC => match private.ec_key()?.group().curve_name() {
+            Some(Nid::X9_62_PRIME256V1) => Algorithm::ES256,
+            Some(Nid::SECP384R1) => Algorithm::ES384,
+            _ => {
+                return Err(ServerError::UnsupportedKey(
+                    "only P-256 and P-384 EC keys are supported".to_string(),
+                ))
+            }
+        },
+        Id::ED25519 => Algorithm::EdDSA,
+        _ => {
+            return Err(ServerError::UnsupportedKey(
+                "only RSA, EC and Ed25519 keys are supported".to_string(),
+            ))
+        }
+    };
+    let algorithm = match (key_algorithm, algorithm) {
+        (_, None) => key_algorithm,
+        (Algorithm::RS256, Some(a @ Algorithm::RS256))
+        | (Algorithm::RS256, Some(a @ Algorithm::RS384))
+        | (Algorithm::RS256, Some(a @ Algorithm::RS512))
+        | (Algorithm::RS256, Some(a @ Algorithm::PS256))
+        | (Algorithm::RS256, Some(a @ Algorithm::PS384))
+        | (Algorithm::RS256, Some(a @ Algorithm::PS512)) => a,
+        (k, Some(a)) if k == a => a,
+        (k, Some(a)) => {
+            return Err(ServerError::UnsupportedKey(format!(
+                "{:?} cannot be used with a key for {:?}",
+                a, k
+            )))
+        }
+    };
+    // jsonwebtoken only reads PKCS#8 EC and Ed25519 private keys
+    let pkcs8 = private.private_key_to_pem_pkcs8()?;
+    let spki = public.public_key_to_pem()?;
+    let (encode_key, decode_key) = match private.id() {
+        Id::RSA => (
+            EncodingKey::from_rsa_pem(&pkcs8)?,
+            DecodingKey::from_rsa_pem(&spki)?,
+        ),
+        Id::EC => (
+            EncodingKey::from_ec_pem(&pkcs8)?,
+            DecodingKey::from_ec_pem(&spki)?,
+        ),
+        _ => (
+            EncodingKey::from_ed_pem(&pkcs8)?,
+            DecodingKey::from_ed_pem(&spki)?,
+        ),
+    };
+    log::info!("signing tokens with {:?}", algorithm);
+    Ok(JwtKeys {
+        encode_key,
+        decode_key,
+        algorithm,
+    })
+}
+
+/// Reads the public key from an X.509 certificate, SPKI or PKCS#1 RSA public key
+fn read_public(pem: &[u8]) -> Result<PKey<Public>> {
+    if let Ok(cert) = openssl::x509::X509::from_pem(pem) {
+        return Ok(cert.public_key()?);
+    }
+    if let Ok(key) = PKey::public_key_from_pem(pem) {
+        return Ok(key);
+    }
+    Ok(PKey::from_rsa(Rsa::public_key_from_pem_pkcs1(pem)?)?)
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn algorithm_follows_key_type() -> Result<()> {
+    use crate::server::auth::jwt;
+    use openssl::ec::{EcGroup, EcKey};
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let ec = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let ed = PKey::generate_ed25519()?;
+    let rsa = PKey::from_rsa(Rsa::generate(2048)?)?;
+    for (key, algorithm, expected) in [
+        (ec, None, Algorithm::ES256),
+        (ed, None, Algorithm::EdDSA),
+        (rsa, Some(Algorithm::PS256), Algorithm::PS256),
+    ] {
+        let keys = from_pem(
+            &key.private_key_to_pem_pkcs8()?,
+            &key.public_key_to_pem()?,
+            algorithm,
+        )?;
+        assert_eq!(keys.algorithm, expected);
+        let token = jwt::create_token("id".to_string(), "scope".to_string(), &keys).await?;
+        assert!(jwt::validate_token(token.access_token, &keys).await);
+    }
+    Ok(())
+}
@@ -1,67 +1,123 @@
+use crate::server::audit::Actor;
+use crate::server::auth::principal::Principal;
+use crate::server::auth::redaction::{Redaction, RedactionAction, RedactionRule};
+use crate::server::auth::scopes::{Action, Scope, Scopes};
+use crate::server::tls::{ClientAuth, ClientCert};
 use crate::server::{auth, Result, ServerError, State};
-use futures::TryFutureExt;
-use http_types::Method;
 
 pub(crate) struct Jwt {
-    scope: Vec<String>,
+    scope: &'static [Scope],
 }
 
 impl Jwt {
-    pub(crate) fn new(scope: Vec<String>) -> Self {
+    /// `scope` lists every scope granting access to the route,
+    /// only those matching the CRUD action of the request method are considered
+    pub(crate) fn new(scope: &'static [Scope]) -> Self {
         Self { scope }
     }
 }
 
 #[tide::utils::async_trait]
 impl tide::Middleware<State> for Jwt {
-    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
-        let token = parse_auth_header(&req)
-            .and_then(|t| async { auth::jwt::decode_token(t, &req.state().decode_key).await })
-            .await?;
-        parse_permission(&self.scope, req.method(), &token.claims.scope).await?;
-        Ok(next.run(req).await)
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        let (client_id, scope) = identify(&req).await?;
+        let mut res = match authorize(self.scope, &client_id, &scope, &req).await {
+            Ok(principal) => {
+                req.set_ext(principal);
+                next.run(req).await
+            }
+            Err(e) => tide::Error::from(e).into(),
+        };
+        // denied requests from an identified client are audited too
+        res.insert_ext(Actor(client_id));
+        Ok(res)
     }
 }
 
+/// Resolves the client_id and granted scopes from the bearer token and/or TLS client certificate
+async fn identify(req: &tide::Request<State>) -> Result<(String, String)> {
+    let cert = req.ext::<ClientCert>();
+    let mode = req.state().client_auth;
+    let token = match (parse_auth_header(req).await, cert) {
+        (Ok(token), _) => auth::jwt::decode_token(token, &req.state().jwt).await?,
+        (Err(ServerError::NoBearerToken), Some(cert)) if mode == Some(ClientAuth::Either) => {
+            return auth::credentials::certificate_login(cert, req.state().db.as_ref()).await;
+        }
+        (Err(e), _) => return Err(e),
+    };
+    if let Some(cert) = cert {
+        match req.state().db.get_cert_client_id(cert).await? {
+            Some(id) if id != token.claims.sub => {
+                return Err(ServerError::ClientCertificateMismatch)
+            }
+            None if mode == Some(ClientAuth::Both) => {
+                return Err(ServerError::UnknownClientCertificate)
+            }
+            _ => (),
+        }
+    } else if mode == Some(ClientAuth::Both) {
+        return Err(ServerError::ClientCertificateMismatch);
+    }
+    Ok((token.claims.sub, token.claims.scope))
+}
+
+/// Checks the scopes and request limit of a credential and loads what it may see,
+/// the token only grants the scopes its credential still holds while it is active
+async fn authorize(
+    scopes: &[Scope],
+    client_id: &str,
+    target: &str,
+    req: &tide::Request<State>,
+) -> Result<Principal> {
+    let creds = req.state().db.get_api_creds(client_id).await?;
+    auth::credentials::check_active(&creds)?;
+    let target = Scopes::parse_lenient(target)
+        .intersection(&Scopes::parse_lenient(&creds.scope))
+        .to_string();
+    let target = target.as_str();
+    parse_permission(scopes, req.method(), target).await?;
+    req.state().limits.hit(&format!("client:{}", client_id))?;
+    let orgs = req.state().db.get_org_scope(client_id).await?;
+    let mut redaction = req.state().db.get_redaction(client_id).await?;
+    // password hashes are only served to credentials granted them
+    if !Scopes::parse_lenient(target).contains(&Scope::RosterPasswordReadonly) {
+        redaction
+            .get_or_insert_with(Redaction::default)
+            .rules
+            .push(RedactionRule {
+                entity: "user".to_string(),
+                field: "password".to_string(),
+                action: RedactionAction::Strip,
+            });
+    }
+    Ok(Principal {
+        client_id: client_id.to_string(),
+        orgs,
+        redaction,
+    })
+}
+
 /// verifies the correct CRUD and ENDPOINT permissions are met in the scope string
 async fn parse_permission(
-    scopes: &Vec<String>,
+    scopes: &[Scope],
     method: http_types::Method,
-    target: &String,
+    target: &str,
 ) -> Result<()> {
-    if let Some(method) = parse_method_permission(method).await {
-        parse_scope_permission(scopes, method, &target).await?;
+    if let Some(action) = Action::from_method(method) {
+        let granted = Scopes::parse_lenient(target);
+        if granted.permits(scopes, action) {
+            return Ok(());
+        }
         log::debug!(
             "scope: {:?} does not meet requirements: {:?}, {:?}",
             target,
             scopes,
-            method
+            action
         );
-        return Ok(());
-    }
-    Err(ServerError::NoPermission)
-}
-
-/// converts endpoint methods into their relevant scope CRUD action
-async fn parse_method_permission<'a>(method: http_types::Method) -> Option<&'a str> {
-    let result = match method {
-        Method::Get => Some("readonly"),
-        Method::Put => Some("createput"),
-        Method::Delete => Some("delete"),
-        Method::Post => Some("create"),
-        _ => None,
-    };
-    result
-}
-
-/// iterates a list of scopes to check if matching exists in scope string
-async fn parse_scope_permission(scopes: &Vec<String>, method: &str, target: &String) -> Result<()> {
-    for scope in scopes {
-        let permission = scope.clone() + "." + method;
-        if target.contains(&permission) {
-            log::debug!("{:?} contains {:?}", target, &permission);
-            return Ok(());
-        }
     }
     Err(ServerError::NoPermission)
 }
@@ -0,0 +1,147 @@
+use crate::model;
+use crate::server::{db, Result};
+use async_std::task::{self, JoinHandle};
+use bcrypt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// bcrypt cost, the same as credential secrets
+const COST: u32 = 12;
+/// Checked against when a user has no password so the answer takes as long either way
+const DUMMY_HASH: &str = "$2b$12$54Zvtx.e/V/nRPo0PUYrxOHqXZywSKzM7LLFqC/p59F0x87SsZdvW";
+
+/// Whether a password is already a bcrypt hash, these are stored as given
+pub(crate) fn is_hash(password: &str) -> bool {
+    password.len() == 60
+        && ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+}
+
+/// Hashes the plaintext passwords of users about to be stored, spread over a blocking
+/// task per core. `stored` holds the current passwords of the users by sourcedId
+pub(crate) async fn hash_users(
+    users: Vec<model::User>,
+    stored: HashMap<String, String>,
+) -> Result<Vec<model::User>> {
+    if users.iter().all(|u| u.password.is_none()) {
+        return Ok(users);
+    }
+    let stored = Arc::new(stored);
+    let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let size = users.len().div_ceil(workers);
+    let mut users = users.into_iter().peekable();
+    let mut chunks = Vec::new();
+    while users.peek().is_some() {
+        let chunk: Vec<model::User> = users.by_ref().take(size).collect();
+        let stored = stored.clone();
+        chunks.push(task::spawn_blocking(move || {
+            chunk
+                .into_iter()
+                .map(|u| hash_user(u, &stored))
+                .collect::<Result<Vec<_>>>()
+        }));
+    }
+    let mut hashed = Vec::new();
+    for chunk in futures::future::join_all(chunks).await {
+        hashed.extend(chunk?);
+    }
+    Ok(hashed)
+}
+
+/// An empty password is no password, a plaintext one matching the stored hash keeps that hash
+fn hash_user(mut user: model::User, stored: &HashMap<String, String>) -> Result<model::User> {
+    user.password = match user.password.take().filter(|p| !p.is_empty()) {
+        None => None,
+        Some(password) if is_hash(&password) => Some(password),
+        Some(password) => match stored.get(&user.sourced_id) {
+            Some(hash) if is_hash(hash) && bcrypt::verify(&password, hash)? => Some(hash.clone()),
+            _ => Some(bcrypt::hash(&password, COST)?),
+        },
+    };
+    Ok(user)
+}
+
+/// Checks a password against a user's stored hash
+pub(crate) async fn verify(password: String, hash: Option<String>) -> Result<bool> {
+    task::spawn_blocking(move || match hash.filter(|h| is_hash(h)) {
+        Some(hash) => Ok(bcrypt::verify(&password, &hash)?),
+        None => {
+            // Security
+            // Users without a password are hashed against too so they cannot be told apart
+            bcrypt::verify(&password, DUMMY_HASH)?;
+            Ok(false)
+        }
+    })
+    .await
+}
+
+/// Hashes the passwords earlier versions stored in plaintext, returning how many were replaced
+pub(crate) async fn upgrade(db: &dyn db::Storage) -> Result<u64> {
+    let plaintext: Vec<(String, String)> = db
+        .get_passwords(None)
+        .await?
+        .into_iter()
+        .filter(|(_, p)| !p.is_empty() && !is_hash(p))
+        .collect();
+    if plaintext.is_empty() {
+        return Ok(0);
+    }
+    let hashes = task::spawn_blocking(move || {
+        plaintext
+            .into_iter()
+            .map(|(sourced_id, plaintext)| {
+                Ok(db::PasswordHash {
+                    hash: bcrypt::hash(&plaintext, COST)?,
+                    sourced_id,
+                    plaintext,
+                })
+            })
+            .collect::<Result<Vec<_>>>()
+    })
+    .await?;
+    db.set_password_hashes(&hashes).await
+}
+
+/// Upgrades plaintext passwords in the background so startup is not held up hashing them
+pub(crate) fn spawn_upgrade(db: Arc<dyn db::Storage>) -> JoinHandle<()> {
+    task::spawn(async move {
+        match upgrade(db.as_ref()).await {
+            Ok(0) => (),
+            Ok(count) => log::info!("hashed {} plaintext user passwords", count),
+            Err(e) => log::error!("could not hash plaintext user passwords: {}", e),
+        }
+    })
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn passwords_are_hashed_once() -> Result<()> {
+    let user = |password: &str| -> Result<model::User> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "sourcedId": "u1",
+            "status": "active",
+            "dateLastModified": "2021-01-01T00:00:00.000Z",
+            "username": "u1",
+            "enabledUser": 1,
+            "givenName": "A",
+            "familyName": "B",
+            "role": "student",
+            "password": password,
+        }))?)
+    };
+    let hashed = hash_users(vec![user("secret")?, user("")?], HashMap::new()).await?;
+    let hash = hashed[0].password.clone().unwrap_or_default();
+    assert!(is_hash(&hash) && hashed[1].password.is_none());
+    assert!(verify("secret".to_string(), Some(hash.clone())).await?);
+    assert!(!verify("secret".to_string(), None).await?);
+
+    let stored = HashMap::from([("u1".to_string(), hash.clone())]);
+    let again = hash_users(vec![user("secret")?], stored.clone()).await?;
+    assert_eq!(again[0].password.as_ref(), Some(&hash));
+    let changed = hash_users(vec![user("other")?], stored).await?;
+    assert_ne!(changed[0].password.as_ref(), Some(&hash));
+    let given = hash_users(vec![user(DUMMY_HASH)?], HashMap::new()).await?;
+    assert_eq!(given[0].password.as_deref(), Some(DUMMY_HASH));
+    Ok(())
+}
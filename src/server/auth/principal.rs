@@ -0,0 +1,389 @@
+use crate::model;
+use crate::server::auth::redaction::Redaction;
+use crate::server::db::RecordChange;
+use crate::server::{Result, ServerError, State};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// The authenticated credential behind a request, set by the Jwt middleware
+#[derive(Debug, Clone)]
+pub(crate) struct Principal {
+    pub(crate) client_id: String,
+    /// `None` when the credential is not bound to any org
+    pub(crate) orgs: Option<OrgScope>,
+    /// `None` when the credential has no redaction profile
+    pub(crate) redaction: Option<Redaction>,
+}
+
+/// Org sourcedIds (including descendants) a credential is bound to
+/// and the academic sessions associated with those orgs
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OrgScope {
+    pub(crate) orgs: HashSet<String>,
+    pub(crate) sessions: HashSet<String>,
+}
+
+impl Principal {
+    pub(crate) fn from_request(req: &tide::Request<State>) -> Result<&Self> {
+        req.ext::<Principal>().ok_or(ServerError::NoPermission)
+    }
+
+    /// Whether a single record is visible to the credential
+    pub(crate) fn permits<T: OrgScoped>(&self, item: &T) -> bool {
+        match &self.orgs {
+            Some(scope) => item.in_scope(scope),
+            None => true,
+        }
+    }
+
+    /// Whether an org sourcedId is visible to the credential
+    pub(crate) fn permits_org(&self, sourced_id: &str) -> bool {
+        match &self.orgs {
+            Some(scope) => scope.orgs.contains(sourced_id),
+            None => true,
+        }
+    }
+
+    /// Strips or masks fields of a response body per the credential redaction profile
+    pub(crate) fn redact(&self, body: &mut Value) {
+        if let Some(redaction) = &self.redaction {
+            redaction.apply(body);
+        }
+    }
+
+    /// Strips or masks fields of a single json record of the named entity
+    pub(crate) fn redact_record(&self, entity: &str, record: &mut Value) {
+        if let Some(redaction) = &self.redaction {
+            redaction.apply_entity(entity, record);
+        }
+    }
+
+    /// Whether a json record of the named entity is visible to the credential
+    pub(crate) fn permits_json(&self, entity: &str, record: &Value) -> bool {
+        match &self.orgs {
+            Some(scope) => scope.contains_json(entity, record),
+            None => true,
+        }
+    }
+
+    /// Errors unless the credential sees every record a PUT wrote, as stored before the
+    /// write and after it, keyed by sourcedId. Orgs written below its orgs become its own,
+    /// academic sessions only once its classes or courses use them
+    pub(crate) fn permits_put(
+        &self,
+        entity: &str,
+        before: &HashMap<String, String>,
+        after: &HashMap<String, String>,
+    ) -> Result<()> {
+        let scope = match &self.orgs {
+            Some(scope) => scope,
+            None => return Ok(()),
+        };
+        let parse = |records: &HashMap<String, String>| {
+            records
+                .values()
+                .map(|json| serde_json::from_str(json))
+                .collect::<serde_json::Result<Vec<Value>>>()
+        };
+        let (before, after) = (parse(before)?, parse(after)?);
+        let mut written = scope.clone();
+        if entity == "org" {
+            while let Some(child) = after.iter().find_map(|org| {
+                let org = model::Org::deserialize(org).ok()?;
+                let parent = org.parent?.sourced_id;
+                (written.orgs.contains(&parent) && !written.orgs.contains(&org.sourced_id))
+                    .then_some(org.sourced_id)
+            }) {
+                written.orgs.insert(child);
+            }
+        }
+        if before.iter().all(|r| scope.contains_json(entity, r))
+            && after.iter().all(|r| written.contains_json(entity, r))
+        {
+            return Ok(());
+        }
+        log::info!(
+            "{} may not put {} records outside of its orgs",
+            self.client_id,
+            entity
+        );
+        Err(ServerError::NoPermission)
+    }
+
+    /// Fits a change to what the credential can see, `false` if it sees neither side.
+    /// Records moving into its orgs appear created and records moving out deleted
+    pub(crate) fn scope_change(&self, change: &mut RecordChange) -> bool {
+        let visible = |record: &Option<Value>| {
+            record
+                .as_ref()
+                .is_some_and(|r| self.permits_json(&change.entity, r))
+        };
+        match (visible(&change.before), visible(&change.after)) {
+            (false, false) => return false,
+            (false, true) => {
+                change.before = None;
+                change.operation = "create".to_string();
+            }
+            (true, false) => {
+                change.after = None;
+                change.operation = "delete".to_string();
+            }
+            (true, true) => (),
+        }
+        true
+    }
+
+    /// Removes records not visible to the credential,
+    /// erroring with NoContent when nothing remains
+    pub(crate) fn retain<T: OrgScoped>(&self, items: &mut Vec<T>) -> Result<()> {
+        if let Some(scope) = &self.orgs {
+            items.retain(|i| i.in_scope(scope));
+            if items.is_empty() {
+                return Err(ServerError::NoContent);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Entities which can be associated with an org
+pub(crate) trait OrgScoped {
+    fn in_scope(&self, scope: &OrgScope) -> bool;
+}
+
+impl OrgScope {
+    /// Whether a json record of the named entity is associated with the orgs
+    fn contains_json(&self, entity: &str, record: &Value) -> bool {
+        fn in_scope<T: OrgScoped + DeserializeOwned>(record: &Value, scope: &OrgScope) -> bool {
+            T::deserialize(record).is_ok_and(|r| r.in_scope(scope))
+        }
+        match entity {
+            "academicSession" => in_scope::<model::AcademicSession>(record, self),
+            "class" => in_scope::<model::Class>(record, self),
+            "course" => in_scope::<model::Course>(record, self),
+            "enrollment" => in_scope::<model::Enrollment>(record, self),
+            "org" => in_scope::<model::Org>(record, self),
+            "period" => in_scope::<model::Period>(record, self),
+            "subject" => in_scope::<model::Subject>(record, self),
+            "user" => in_scope::<model::User>(record, self),
+            _ => false,
+        }
+    }
+
+    fn has_ref(&self, guid: &model::GUIDRef) -> bool {
+        self.orgs.contains(&guid.sourced_id)
+    }
+
+    fn has_any(&self, guids: &Option<Vec<model::GUIDRef>>) -> bool {
+        guids.iter().flatten().any(|g| self.has_ref(g))
+    }
+}
+
+impl OrgScoped for model::Org {
+    fn in_scope(&self, scope: &OrgScope) -> bool {
+        scope.orgs.contains(&self.sourced_id)
+    }
+}
+
+impl OrgScoped for model::User {
+    fn in_scope(&self, scope: &OrgScope) -> bool {
+        scope.has_any(&self.orgs)
+    }
+}
+
+impl OrgScoped for model::Class {
+    fn in_scope(&self, scope: &OrgScope) -> bool {
+        scope.has_ref(&self.school)
+    }
+}
+
+impl OrgScoped for model::Course {
+    fn in_scope(&self, scope: &OrgScope) -> bool {
+        scope.has_ref(&self.org)
+    }
+}
+
+impl OrgScoped for model::Enrollment {
+    fn in_scope(&self, scope: &OrgScope) -> bool {
+        scope.has_ref(&self.school)
+    }
+}
+
+impl OrgScoped for model::AcademicSession {
+    fn in_scope(&self, scope: &OrgScope) -> bool {
+        scope.sessions.contains(&self.sourced_id)
+    }
+}
+
+impl OrgScoped for model::Period {
+    fn in_scope(&self, scope: &OrgScope) -> bool {
+        self.orgs.iter().any(|g| scope.has_ref(g))
+    }
+}
+
+// Subjects are shared between orgs
+impl OrgScoped for model::Subject {
+    fn in_scope(&self, _scope: &OrgScope) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+fn bound_to_school1() -> Principal {
+    let set = |ids: &[&str]| ids.iter().map(|i| i.to_string()).collect();
+    Principal {
+        client_id: "vendor".to_string(),
+        orgs: Some(OrgScope {
+            orgs: set(&["district", "school1"]),
+            sessions: set(&["term1"]),
+        }),
+        redaction: None,
+    }
+}
+
+#[cfg(test)]
+fn record<T: DeserializeOwned>(fields: Value) -> T {
+    let mut record = serde_json::json!({
+        "sourcedId": "r1",
+        "status": "active",
+        "dateLastModified": "2021-01-01T00:00:00.000Z",
+    });
+    for (k, v) in fields.as_object().unwrap() {
+        record[k] = v.clone();
+    }
+    serde_json::from_value(record).unwrap()
+}
+
+#[cfg(test)]
+#[test]
+fn records_are_scoped_by_their_orgs() {
+    use serde_json::json;
+    let principal = bound_to_school1();
+    let school = |id: &str| json!({ "sourcedId": id });
+    let org =
+        |id: &str| record::<model::Org>(json!({ "sourcedId": id, "name": id, "type": "school" }));
+    assert!(principal.permits(&org("school1")));
+    assert!(!principal.permits(&org("school2")));
+    let user = |orgs: Value| {
+        record::<model::User>(json!({
+            "username": "u", "enabledUser": 1, "givenName": "A", "familyName": "B",
+            "role": "student", "orgs": orgs,
+        }))
+    };
+    assert!(principal.permits(&user(json!([school("school2"), school("school1")]))));
+    assert!(!principal.permits(&user(json!([school("school2")]))));
+    assert!(!principal.permits(&user(Value::Null)));
+    let class = |id: &str| {
+        record::<model::Class>(json!({
+            "title": "c", "classType": "scheduled", "course": school("c1"),
+            "school": school(id), "terms": [school("term1")],
+        }))
+    };
+    assert!(principal.permits(&class("school1")));
+    assert!(!principal.permits(&class("school2")));
+    let course = |id: &str| record::<model::Course>(json!({ "title": "c", "org": school(id) }));
+    assert!(principal.permits(&course("district")));
+    assert!(!principal.permits(&course("school2")));
+    let enrollment = |id: &str| {
+        record::<model::Enrollment>(json!({
+            "user": school("u1"), "class": school("c1"), "school": school(id), "role": "student",
+        }))
+    };
+    assert!(principal.permits(&enrollment("school1")));
+    assert!(!principal.permits(&enrollment("school2")));
+    let session = |id: &str| {
+        record::<model::AcademicSession>(json!({
+            "sourcedId": id, "title": "t", "startDate": "2021-01-01", "endDate": "2021-07-01",
+            "type": "term", "schoolYear": "2021",
+        }))
+    };
+    assert!(principal.permits(&session("term1")));
+    assert!(!principal.permits(&session("term2")));
+    let period = |id: &str| {
+        record::<model::Period>(json!({ "title": "p", "periodCode": "1", "orgs": [school(id)] }))
+    };
+    assert!(principal.permits(&period("school1")));
+    assert!(!principal.permits(&period("school2")));
+    let subject = record::<model::Subject>(json!({ "title": "s", "subjectCode": "s" }));
+    assert!(principal.permits(&subject));
+
+    let mut orgs = vec![org("school1"), org("school2")];
+    principal.retain(&mut orgs).unwrap();
+    assert_eq!(orgs.len(), 1);
+    let mut orgs = vec![org("school2")];
+    assert!(matches!(
+        principal.retain(&mut orgs),
+        Err(ServerError::NoContent)
+    ));
+    assert!(principal.permits_org("district") && !principal.permits_org("school2"));
+    let unbound = Principal {
+        orgs: None,
+        ..bound_to_school1()
+    };
+    assert!(unbound.permits(&org("school2")) && unbound.permits_org("school2"));
+}
+
+#[cfg(test)]
+#[test]
+fn changes_are_scoped_to_the_side_visible() {
+    use serde_json::json;
+    let principal = bound_to_school1();
+    let class = |id: &str| {
+        json!({
+            "sourcedId": "c1", "status": "active", "dateLastModified": "2021-01-01T00:00:00.000Z",
+            "title": "c", "classType": "scheduled", "course": { "sourcedId": "c1" },
+            "school": { "sourcedId": id }, "terms": [],
+        })
+    };
+    let change = |before: Option<Value>, after: Option<Value>| RecordChange {
+        id: 1,
+        timestamp: chrono::Utc::now(),
+        entity: "class".to_string(),
+        sourced_id: "c1".to_string(),
+        operation: "update".to_string(),
+        before,
+        after,
+    };
+    let mut moved_in = change(Some(class("school2")), Some(class("school1")));
+    assert!(principal.scope_change(&mut moved_in));
+    assert_eq!(moved_in.operation, "create");
+    assert!(moved_in.before.is_none());
+    let mut moved_out = change(Some(class("school1")), Some(class("school2")));
+    assert!(principal.scope_change(&mut moved_out));
+    assert_eq!(moved_out.operation, "delete");
+    assert!(moved_out.after.is_none());
+    let mut elsewhere = change(Some(class("school2")), Some(class("school2")));
+    assert!(!principal.scope_change(&mut elsewhere));
+}
+
+#[cfg(test)]
+#[test]
+fn puts_stay_within_the_orgs() {
+    use serde_json::json;
+    let principal = bound_to_school1();
+    let org = |id: &str, parent: &str| {
+        let org = json!({
+            "sourcedId": id, "status": "active", "dateLastModified": "2021-01-01T00:00:00.000Z",
+            "name": id, "type": "school", "parent": { "sourcedId": parent },
+        });
+        (id.to_string(), org.to_string())
+    };
+    let records = |orgs: &[(String, String)]| orgs.iter().cloned().collect::<HashMap<_, _>>();
+    let none = HashMap::new();
+    // new orgs below its orgs, including those below each other
+    let added = records(&[org("school3", "district"), org("dept", "school3")]);
+    assert!(principal.permits_put("org", &none, &added).is_ok());
+    // taking over an org stored elsewhere, or writing one elsewhere
+    let taken = records(&[org("school2", "district")]);
+    let stored = records(&[org("school2", "other")]);
+    assert!(principal.permits_put("org", &stored, &taken).is_err());
+    let moved = records(&[org("school9", "other")]);
+    assert!(principal.permits_put("org", &none, &moved).is_err());
+    let unbound = Principal {
+        orgs: None,
+        ..bound_to_school1()
+    };
+    assert!(unbound.permits_put("org", &stored, &moved).is_ok());
+}
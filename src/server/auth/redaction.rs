@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Replacement written over masked string fields
+const MASK: &str = "********";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub(crate) enum RedactionAction {
+    /// removes the field from the response
+    Strip,
+    /// keeps the field but hides its value
+    Mask,
+}
+
+/// A field to redact, `entity` is the singular json object name e.g. user, class, org
+/// or `*` to apply to every entity
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct RedactionRule {
+    pub(crate) entity: String,
+    pub(crate) field: String,
+    pub(crate) action: RedactionAction,
+}
+
+/// The redaction profile attached to a credential
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Redaction {
+    pub(crate) rules: Vec<RedactionRule>,
+}
+
+impl Redaction {
+    /// Redacts a response body of the form { "users": [{}] } or { "user": {} }
+    pub(crate) fn apply(&self, body: &mut Value) {
+        if let Some(wrapper) = body.as_object_mut() {
+            for (key, value) in wrapper.iter_mut() {
+                match value {
+                    Value::Array(items) => {
+                        let entity = singular(key);
+                        for item in items.iter_mut() {
+                            self.apply_entity(entity, item);
+                        }
+                    }
+                    item => self.apply_entity(key, item),
+                }
+            }
+        }
+    }
+
+    /// Redacts a single json object of the named entity
+    pub(crate) fn apply_entity(&self, entity: &str, item: &mut Value) {
+        if let Some(object) = item.as_object_mut() {
+            for rule in self
+                .rules
+                .iter()
+                .filter(|r| r.entity == "*" || r.entity == entity)
+            {
+                match rule.action {
+                    RedactionAction::Strip => {
+                        object.remove(&rule.field);
+                    }
+                    RedactionAction::Mask => {
+                        if let Some(v) = object.get_mut(&rule.field) {
+                            *v = match v {
+                                Value::String(_) => Value::String(MASK.to_string()),
+                                _ => Value::Null,
+                            };
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Maps a collection wrapper to the name of the entities within it
+fn singular(wrapper: &str) -> &str {
+    match wrapper {
+        "classes" => "class",
+        _ => wrapper.strip_suffix('s').unwrap_or(wrapper),
+    }
+}
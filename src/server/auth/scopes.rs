@@ -0,0 +1,218 @@
+use crate::server::{Result, ServerError};
+use http_types::Method;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// OneRoster 1.1 OAuth2 scopes
+/// The `createput` rostering scopes are not part of the spec and are used by the sync client,
+/// nor are the `roster-password` scopes guarding the user password hashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Scope {
+    RosterCoreReadonly,
+    RosterCoreCreateput,
+    RosterReadonly,
+    RosterCreateput,
+    RosterDemographicsReadonly,
+    RosterPasswordReadonly,
+    RosterPasswordVerify,
+    ResourceReadonly,
+    GradebookReadonly,
+    GradebookCreateput,
+    GradebookDelete,
+    AdminReadonly,
+    AdminCreate,
+    AdminDelete,
+    AdminBackup,
+}
+
+/// CRUD action granted by a scope, derived from the HTTP method of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    Readonly,
+    Createput,
+    Create,
+    Delete,
+}
+
+/// Complete scope catalogue, mirrored by the `scopes` table seeded by db/migrations
+pub(crate) const CATALOGUE: [(Scope, &str); 15] = [
+    (Scope::RosterCoreReadonly, "roster-core.readonly"),
+    (Scope::RosterCoreCreateput, "roster-core.createput"),
+    (Scope::RosterReadonly, "roster.readonly"),
+    (Scope::RosterCreateput, "roster.createput"),
+    (
+        Scope::RosterDemographicsReadonly,
+        "roster-demographics.readonly",
+    ),
+    (Scope::RosterPasswordReadonly, "roster-password.readonly"),
+    (Scope::RosterPasswordVerify, "roster-password.verify"),
+    (Scope::ResourceReadonly, "resource.readonly"),
+    (Scope::GradebookReadonly, "gradebook.readonly"),
+    (Scope::GradebookCreateput, "gradebook.createput"),
+    (Scope::GradebookDelete, "gradebook.delete"),
+    (Scope::AdminReadonly, "admin.readonly"),
+    (Scope::AdminCreate, "admin.create"),
+    (Scope::AdminDelete, "admin.delete"),
+    (Scope::AdminBackup, "admin.backup"),
+];
+
+/// Endpoints available to both `roster-core` and `roster` scopes,
+/// e.g. getAllOrgs, getUser, getAllTerms
+pub(crate) const ROSTER_CORE: &[Scope] = &[
+    Scope::RosterCoreReadonly,
+    Scope::RosterReadonly,
+    Scope::RosterCoreCreateput,
+    Scope::RosterCreateput,
+];
+
+/// Endpoints only available to the full `roster` scope, e.g. getAllEnrollments,
+/// getStudentsForSchool. `roster-core.createput` also writes them as the sync client
+/// and credentials created before the scopes were split put every collection with it
+pub(crate) const ROSTER: &[Scope] = &[
+    Scope::RosterReadonly,
+    Scope::RosterCoreCreateput,
+    Scope::RosterCreateput,
+];
+
+/// Checking a user's password without reading its hash
+pub(crate) const PASSWORD_VERIFY: &[Scope] = &[Scope::RosterPasswordVerify];
+
+/// Credential management endpoints
+pub(crate) const ADMIN: &[Scope] = &[Scope::AdminReadonly, Scope::AdminCreate, Scope::AdminDelete];
+
+/// Downloading a backup, on top of `admin.readonly` as it holds every credential
+/// hash and tenant signing key
+pub(crate) const BACKUP: &[Scope] = &[Scope::AdminBackup];
+
+impl Scope {
+    pub(crate) fn as_str(&self) -> &'static str {
+        CATALOGUE
+            .iter()
+            .find(|(s, _)| s == self)
+            .map(|(_, name)| *name)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn action(&self) -> Action {
+        match self {
+            Scope::RosterCoreReadonly
+            | Scope::RosterReadonly
+            | Scope::RosterDemographicsReadonly
+            | Scope::RosterPasswordReadonly
+            | Scope::ResourceReadonly
+            | Scope::GradebookReadonly
+            | Scope::AdminReadonly
+            | Scope::AdminBackup => Action::Readonly,
+            Scope::RosterCoreCreateput | Scope::RosterCreateput | Scope::GradebookCreateput => {
+                Action::Createput
+            }
+            Scope::RosterPasswordVerify | Scope::AdminCreate => Action::Create,
+            Scope::GradebookDelete | Scope::AdminDelete => Action::Delete,
+        }
+    }
+}
+
+impl Action {
+    /// converts endpoint methods into their relevant scope CRUD action
+    pub(crate) fn from_method(method: Method) -> Option<Self> {
+        match method {
+            Method::Get => Some(Action::Readonly),
+            Method::Put => Some(Action::Createput),
+            Method::Delete => Some(Action::Delete),
+            Method::Post => Some(Action::Create),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        CATALOGUE
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(scope, _)| *scope)
+            .ok_or_else(|| ServerError::InvalidScope(s.to_string()))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// A set of scopes parsed from a space delimited scope string
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Scopes(HashSet<Scope>);
+
+impl Scopes {
+    /// Parses a scope string, rejecting any scope not in the catalogue
+    pub(crate) fn parse_strict(s: &str) -> Result<Self> {
+        let set = s
+            .split_whitespace()
+            .map(Scope::from_str)
+            .collect::<Result<HashSet<Scope>>>()?;
+        Ok(Self(set))
+    }
+
+    /// Parses a scope string, ignoring any scope not in the catalogue
+    pub(crate) fn parse_lenient(s: &str) -> Self {
+        Self(
+            s.split_whitespace()
+                .filter_map(|s| Scope::from_str(s).ok())
+                .collect(),
+        )
+    }
+
+    pub(crate) fn contains(&self, scope: &Scope) -> bool {
+        self.0.contains(scope)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn intersection(&self, other: &Scopes) -> Scopes {
+        Self(self.0.intersection(&other.0).copied().collect())
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Scope> {
+        self.0.iter()
+    }
+
+    /// Whether any of the `required` scopes matching `action` is held
+    pub(crate) fn permits(&self, required: &[Scope], action: Action) -> bool {
+        required
+            .iter()
+            .filter(|s| s.action() == action)
+            .any(|s| self.contains(s))
+    }
+}
+
+impl fmt::Display for Scopes {
+    /// Writes the scopes in catalogue order so the output is stable
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = CATALOGUE
+            .iter()
+            .filter(|(s, _)| self.contains(s))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join(" "))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn scopes_match_exactly() {
+    let granted = Scopes::parse_lenient("roster-core.readonly xroster.readonly admin.readonlyx");
+    assert!(granted.contains(&Scope::RosterCoreReadonly));
+    assert!(!granted.contains(&Scope::RosterReadonly));
+    assert!(!granted.contains(&Scope::AdminReadonly));
+    assert!(granted.permits(ROSTER_CORE, Action::Readonly));
+    assert!(!granted.permits(ROSTER, Action::Readonly));
+    assert!(!granted.permits(ROSTER_CORE, Action::Createput));
+    assert!(Scopes::parse_strict("roster.readonly roster").is_err());
+}
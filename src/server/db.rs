@@ -1,105 +1,892 @@
 use crate::model;
-use crate::server::{auth, Result, ServerError};
-use sqlite::SqlitePoolOptions;
-use sqlx::{migrate::MigrateDatabase, sqlite};
+use crate::server::auth::principal::{OrgScope, Principal};
+use crate::server::auth::redaction::{Redaction, RedactionRule};
+use crate::server::auth::scopes::Scopes;
+use crate::server::{auth, metrics, tls, Result, ServerError};
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use sqlx::any::AnyKind;
+use sqlx::migrate::MigrateDatabase;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tide::prelude::*;
 
+mod encryption;
+mod migrate;
+mod postgres;
+mod sqlite;
+
+pub use encryption::MasterKey;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// connections serving reads, SQLite writes always go through a single extra connection
+    pub readers: u32,
+    /// how long SQLite waits on a locked database before failing the query
+    pub busy_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            readers: 4,
+            busy_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
 #[derive(Serialize)]
-pub(super) struct UserList {
+pub(crate) struct UserList {
     tag: String,
     client_id: String,
     scope: String,
+    orgs: String,
+    cert_fingerprints: String,
+    cert_subjects: String,
+    redaction: Option<String>,
+    disabled: bool,
+    expires: Option<DateTime<Utc>>,
+    last_used: Option<DateTime<Utc>>,
 }
 
-pub(super) async fn get_api_creds(
-    client_id: &String,
-    db: &sqlx::SqlitePool,
-) -> Result<super::Creds> {
-    let res = sqlx::query_as!(
-        super::Creds,
-        r#"
-        SELECT
-            c.client_id
-            , c.client_secret
-            , group_concat(s.scope,' ') AS "scope!: String"
-        FROM
-            credentials c
-            INNER JOIN credential_scopes cs ON c.id = cs.credential_id
-            INNER JOIN scopes s ON cs.scope_id = s.id
-        WHERE
-            c.client_id = ?
-            AND scope IS NOT NULL
-        GROUP BY 
-            c.client_id
-        "#,
-        client_id
-    )
-    .fetch_optional(db)
-    .await?;
-    if let Some(user) = res {
-        return Ok(user);
-    }
-    Err(ServerError::InvalidLogin)
-}
-
-pub(super) async fn get_api_users(db: &sqlx::SqlitePool) -> Result<Vec<UserList>> {
-    let rows = sqlx::query_as!(
-        UserList,
-        r#"
-        SELECT
-            c.client_id
-            , c.tag
-            , group_concat(s.scope,' ') AS "scope!: String"
-        FROM
-            credentials c
-            INNER JOIN credential_scopes cs ON c.id = cs.credential_id
-            INNER JOIN scopes s ON cs.scope_id = s.id
-        WHERE
-            scope IS NOT NULL
-        GROUP BY 
-            c.client_id
-        "#,
-    )
-    .fetch_all(db)
-    .await?;
-
-    Ok(rows)
+/// Stored credential used to verify a login attempt
+pub(crate) struct ApiCreds {
+    pub(crate) client_secret: String,
+    pub(crate) scope: String,
+    pub(crate) disabled: bool,
+    pub(crate) expires: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize)]
-pub(super) struct CreateApiUser {
+pub(crate) struct CreateApiUser {
     tag: String,
     scope: String,
+    #[serde(default)]
+    orgs: Vec<String>,
+    #[serde(default)]
+    cert_fingerprints: Vec<String>,
+    #[serde(default)]
+    cert_subjects: Vec<String>,
+    redaction: Option<String>,
 }
 
-pub(super) async fn create_api_user(
-    user: CreateApiUser,
-    db: &sqlx::SqlitePool,
-) -> Result<super::Creds> {
-    let new = auth::credentials::generate_credentials().await?;
-    let mut t = db.begin().await?;
-    sqlx::query!(
-        "INSERT INTO credentials(client_id, client_secret, tag) VALUES (?, ?, ?)",
-        new.creds.client_id,
-        new.encrypt,
-        user.tag,
-    )
-    .execute(&mut t)
-    .await?;
-    for scope in user.scope.split(' ') {
-        sqlx::query!(
-            "INSERT OR IGNORE INTO credential_scopes (credential_id, scope_id) VALUES (
-            (SELECT id FROM credentials WHERE client_id = ?),
-            (SELECT id FROM scopes WHERE scope = ?)
-            )",
-            new.creds.client_id,
-            scope,
+/// Changes to apply to an existing credential, any field left out is unchanged
+#[derive(Deserialize)]
+pub(crate) struct UpdateApiUser {
+    add_scope: Option<String>,
+    remove_scope: Option<String>,
+    orgs: Option<Vec<String>>,
+    cert_fingerprints: Option<Vec<String>>,
+    cert_subjects: Option<Vec<String>>,
+    // Some(None) removes the redaction profile
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    redaction: Option<Option<String>>,
+    disabled: Option<bool>,
+    // Some(None) clears the expiry
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    expires: Option<Option<DateTime<Utc>>>,
+}
+
+#[derive(Clone, Copy)]
+enum CertKind {
+    Fingerprint,
+    Subject,
+}
+
+impl CertKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            CertKind::Fingerprint => "fingerprint",
+            CertKind::Subject => "subject",
+        }
+    }
+
+    fn normalise(self, value: &str) -> Result<String> {
+        match self {
+            CertKind::Fingerprint => Ok(tls::normalise_fingerprint(value)),
+            CertKind::Subject => tls::normalise_subject(value),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct RedactionProfile {
+    name: String,
+    fields: Vec<RedactionRule>,
+}
+
+/// An authenticated API request as recorded in the audit trail
+#[derive(Deserialize, Serialize)]
+pub(crate) struct AuditRequest {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) client_id: String,
+    pub(crate) ip: String,
+    pub(crate) method: String,
+    pub(crate) route: String,
+    pub(crate) query: Option<String>,
+    pub(crate) status: i64,
+    pub(crate) size: Option<i64>,
+}
+
+/// A before/after snapshot of a record changed through the API
+#[derive(Serialize)]
+pub(crate) struct AuditChange {
+    timestamp: DateTime<Utc>,
+    client_id: String,
+    pub(crate) entity: String,
+    sourced_id: String,
+    operation: String,
+    pub(crate) before: Option<serde_json::Value>,
+    pub(crate) after: Option<serde_json::Value>,
+}
+
+/// A user password stored in plaintext by an earlier version and the hash replacing it
+pub(crate) struct PasswordHash {
+    pub(crate) sourced_id: String,
+    pub(crate) plaintext: String,
+    pub(crate) hash: String,
+}
+
+/// A record found by a search with the indexed text it matched
+pub(crate) struct SearchHit {
+    pub(crate) sourced_id: String,
+    pub(crate) text: String,
+}
+
+/// Filters for querying the audit trail, every field is optional
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct AuditFilter {
+    client_id: Option<String>,
+    /// route prefix for requests e.g. /ims/oneroster/v1p1/users
+    route: Option<String>,
+    entity: Option<String>,
+    sourced_id: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: u32,
+}
+
+impl Default for AuditFilter {
+    fn default() -> Self {
+        Self {
+            client_id: None,
+            route: None,
+            entity: None,
+            sourced_id: None,
+            since: None,
+            until: None,
+            limit: 100,
+        }
+    }
+}
+
+/// A tenant hosted alongside the server's own dataset
+#[derive(Serialize, sqlx::FromRow)]
+pub(crate) struct Tenant {
+    pub(crate) name: String,
+    pub(crate) hostname: Option<String>,
+    pub(crate) database: String,
+    #[serde(skip)]
+    pub(crate) private_key: String,
+    pub(crate) public_key: String,
+    pub(crate) created: DateTime<Utc>,
+}
+
+/// A tenant to provision, the database defaults to the server's with the name appended
+#[derive(Deserialize)]
+pub(crate) struct CreateTenant {
+    pub(crate) name: String,
+    pub(crate) hostname: Option<String>,
+    pub(crate) database: Option<String>,
+}
+
+/// A record change from the change log, served to incremental consumers
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RecordChange {
+    pub(crate) id: i64,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) entity: String,
+    pub(crate) sourced_id: String,
+    pub(crate) operation: String,
+    pub(crate) before: Option<serde_json::Value>,
+    pub(crate) after: Option<serde_json::Value>,
+}
+
+/// Filters for reading the change log, changes are returned oldest first
+#[derive(Deserialize)]
+#[serde(default)]
+pub(crate) struct ChangeFilter {
+    /// only changes made after this time
+    since: Option<DateTime<Utc>>,
+    /// only changes after this change id, the cursor for paging through a large sync
+    after: Option<i64>,
+    entity: Option<String>,
+    pub(crate) limit: u32,
+}
+
+impl Default for ChangeFilter {
+    fn default() -> Self {
+        Self {
+            since: None,
+            after: None,
+            entity: None,
+            limit: 100,
+        }
+    }
+}
+
+/// The json array views served as collections
+#[derive(Clone, Copy)]
+pub(crate) enum Collection {
+    AcademicSessions,
+    Classes,
+    Courses,
+    Enrollments,
+    GradingPeriods,
+    Orgs,
+    Periods,
+    Schools,
+    Students,
+    Subjects,
+    Teachers,
+    Terms,
+    Users,
+}
+
+impl Collection {
+    /// The wrapper object, change log entity and any field the records of
+    /// the collection must match, used to rebuild it from the change log
+    /// and to serve it a record at a time
+    pub(crate) fn history(
+        self,
+    ) -> (
+        &'static str,
+        &'static str,
+        Option<(&'static str, &'static str)>,
+    ) {
+        match self {
+            Collection::AcademicSessions => ("academicSessions", "academicSession", None),
+            Collection::Classes => ("classes", "class", None),
+            Collection::Courses => ("courses", "course", None),
+            Collection::Enrollments => ("enrollments", "enrollment", None),
+            Collection::GradingPeriods => (
+                "academicSessions",
+                "academicSession",
+                Some(("type", "gradingPeriod")),
+            ),
+            Collection::Orgs => ("orgs", "org", None),
+            Collection::Periods => ("periods", "period", None),
+            Collection::Schools => ("orgs", "org", Some(("type", "school"))),
+            Collection::Students => ("users", "user", Some(("role", "student"))),
+            Collection::Subjects => ("subjects", "subject", None),
+            Collection::Teachers => ("users", "user", Some(("role", "teacher"))),
+            Collection::Terms => (
+                "academicSessions",
+                "academicSession",
+                Some(("type", "term")),
+            ),
+            Collection::Users => ("users", "user", None),
+        }
+    }
+}
+
+/// The views served as a single record by sourcedId
+#[derive(Clone, Copy)]
+pub(crate) enum Record {
+    AcademicSession,
+    Class,
+    Course,
+    Enrollment,
+    GradingPeriod,
+    Org,
+    School,
+    Student,
+    Teacher,
+    Term,
+    User,
+}
+
+/// The collections filtered to a single school
+#[derive(Clone, Copy)]
+pub(crate) enum ForSchool {
+    Classes,
+    Students,
+    Teachers,
+    Enrollments,
+}
+
+/// The entities which can be upserted through their json views
+#[derive(Clone, Copy)]
+pub(crate) enum Entity {
+    AcademicSessions,
+    Classes,
+    Courses,
+    Enrollments,
+    Orgs,
+    Periods,
+    Subjects,
+    Users,
+}
+
+impl Entity {
+    /// The singular name recorded in the audit trail
+    fn name(self) -> &'static str {
+        match self {
+            Entity::AcademicSessions => "academicSession",
+            Entity::Classes => "class",
+            Entity::Courses => "course",
+            Entity::Enrollments => "enrollment",
+            Entity::Orgs => "org",
+            Entity::Periods => "period",
+            Entity::Subjects => "subject",
+            Entity::Users => "user",
+        }
+    }
+
+    /// The tables holding an entity's records, used to purge them
+    fn tables(self) -> Tables {
+        match self {
+            Entity::AcademicSessions => Tables {
+                table: "AcademicSessions",
+                links: &[],
+                linked_from: &[("ClassAcademicSessions", "academicSessionSourcedId")],
+                references: &[
+                    ("Courses", "schoolYearSourcedId"),
+                    ("AcademicSessions", "parentSourcedId"),
+                ],
+            },
+            Entity::Classes => Tables {
+                table: "Classes",
+                links: &[
+                    ("ClassGrades", "classSourcedId"),
+                    ("ClassSubjects", "classSourcedId"),
+                    ("ClassAcademicSessions", "classSourcedId"),
+                    ("ClassPeriods", "classSourcedId"),
+                ],
+                linked_from: &[],
+                references: &[("Enrollments", "classSourcedId")],
+            },
+            Entity::Courses => Tables {
+                table: "Courses",
+                links: &[
+                    ("CourseGrades", "courseSourcedId"),
+                    ("CourseSubjects", "courseSourcedId"),
+                ],
+                linked_from: &[],
+                references: &[("Classes", "courseSourcedId")],
+            },
+            Entity::Enrollments => Tables {
+                table: "Enrollments",
+                links: &[],
+                linked_from: &[],
+                references: &[],
+            },
+            Entity::Orgs => Tables {
+                table: "Orgs",
+                links: &[],
+                linked_from: &[("UserOrgs", "orgSourcedId"), ("OrgPeriods", "orgSourcedId")],
+                references: &[
+                    ("Classes", "orgSourcedId"),
+                    ("Courses", "orgSourcedId"),
+                    ("Enrollments", "orgSourcedId"),
+                    ("Orgs", "parentSourcedId"),
+                ],
+            },
+            Entity::Periods => Tables {
+                table: "Periods",
+                links: &[("OrgPeriods", "periodSourcedId")],
+                linked_from: &[("ClassPeriods", "periodSourcedId")],
+                references: &[],
+            },
+            Entity::Subjects => Tables {
+                table: "Subjects",
+                links: &[],
+                linked_from: &[
+                    ("ClassSubjects", "subjectSourcedId"),
+                    ("CourseSubjects", "subjectSourcedId"),
+                ],
+                references: &[],
+            },
+            Entity::Users => Tables {
+                table: "Users",
+                links: &[
+                    ("UserIds", "userSourcedId"),
+                    ("UserGrades", "userSourcedId"),
+                    ("UserAgents", "userSourcedId"),
+                    ("UserOrgs", "userSourcedId"),
+                ],
+                linked_from: &[("UserAgents", "agentUserSourcedId")],
+                references: &[("Enrollments", "userSourcedId")],
+            },
+        }
+    }
+}
+
+/// The order entities are purged in, records pointing at others go first
+/// so the records they held on to can follow in the same run
+const PURGE_ORDER: [Entity; 8] = [
+    Entity::Enrollments,
+    Entity::Classes,
+    Entity::Courses,
+    Entity::Users,
+    Entity::Periods,
+    Entity::Subjects,
+    Entity::AcademicSessions,
+    Entity::Orgs,
+];
+
+/// The client_id purged records are recorded against in the audit trail
+const PURGE_CLIENT_ID: &str = "purge";
+
+/// The client_id plaintext passwords replaced by their hashes are recorded against in the audit trail
+const PASSWORD_UPGRADE_CLIENT_ID: &str = "password upgrade";
+
+/// Table and column names are the same in every backend, only placeholders differ
+struct Tables {
+    table: &'static str,
+    /// link tables listing the entity's own grades, subjects, orgs etc, removed with it
+    links: &'static [(&'static str, &'static str)],
+    /// link rows of other records pointing at the entity,
+    /// active ones keep it and tobedeleted ones are removed with it
+    linked_from: &'static [(&'static str, &'static str)],
+    /// other records pointing at the entity, it is kept while any remain
+    references: &'static [(&'static str, &'static str)],
+}
+
+impl Tables {
+    /// Selects the sourcedIds of records marked tobedeleted before the `cutoff` placeholder
+    /// which nothing left in the database still points at
+    fn purgeable(&self, cutoff: &str) -> String {
+        let mut query = format!(
+            "SELECT e.sourcedId FROM {} e JOIN StatusType s ON s.id = e.statusTypeId
+            WHERE s.token = 'tobedeleted' AND e.dateLastModified < {}",
+            self.table, cutoff
+        );
+        for (table, column) in self.references {
+            query += &format!(
+                "\n AND NOT EXISTS (SELECT 1 FROM {} r WHERE r.{} = e.sourcedId)",
+                table, column
+            );
+        }
+        for (table, column) in self.linked_from {
+            query += &format!(
+                "\n AND NOT EXISTS (SELECT 1 FROM {} r JOIN StatusType rs ON rs.id = r.statusTypeId
+                WHERE r.{} = e.sourcedId AND rs.token <> 'tobedeleted')",
+                table, column
+            );
+        }
+        query
+    }
+
+    /// Sets dateLastModified to the `modified` placeholder on the records selected by `ids`,
+    /// skipping tobedeleted ones as marking them set it already and postgres rechecks
+    /// the references of a row updated twice in a transaction
+    fn touch(&self, modified: &str, ids: &str) -> String {
+        format!(
+            "UPDATE {} SET dateLastModified = {} WHERE sourcedId IN ({})
+            AND statusTypeId <> (SELECT id FROM StatusType WHERE token = 'tobedeleted')",
+            self.table, modified, ids
         )
-        .execute(&mut t)
-        .await?;
     }
-    t.commit().await?;
-    let authscopes = get_api_creds(&new.creds.client_id, db).await?;
+
+    /// Deletes the records and link rows of the sourcedIds selected by `ids`, links first
+    fn deletes(&self, ids: &str) -> Vec<String> {
+        self.links
+            .iter()
+            .chain(self.linked_from)
+            .chain(std::iter::once(&(self.table, "sourcedId")))
+            .map(|(table, column)| format!("DELETE FROM {} WHERE {} IN ({})", table, column, ids))
+            .collect()
+    }
+}
+
+/// A database backend, each implementation owns its connection pool
+/// and the SQL dialect of its views and triggers
+#[tide::utils::async_trait]
+pub(crate) trait Storage: Send + Sync {
+    /// The json object of a collection view, `None` when the view is empty
+    async fn get_collection(&self, view: Collection) -> Result<Option<String>>;
+    /// The json object of a collection as it was at `at`, rebuilt from the change log
+    async fn get_collection_as_of(
+        &self,
+        view: Collection,
+        at: DateTime<Utc>,
+    ) -> Result<Option<String>>;
+    /// Starts a read of the collections which sees them as they are now until dropped
+    async fn snapshot(&self) -> Result<Box<dyn Snapshot>>;
+    async fn get_record(&self, view: Record, id: &str) -> Result<Option<String>>;
+    async fn get_for_school(&self, view: ForSchool, school: &str) -> Result<Option<String>>;
+    /// Users, classes or orgs whose indexed text contains any of `trigrams` or has a word
+    /// starting with any of `prefixes`, at most SEARCH_CANDIDATES of them, those matching the most first
+    async fn search(
+        &self,
+        entity: Entity,
+        trigrams: &[String],
+        prefixes: &[String],
+    ) -> Result<Vec<SearchHit>>;
+    /// Upserts json records in one transaction, recording the changes to the records
+    /// with the sourcedIds in the json array `ids` in the audit trail and change log.
+    /// Nothing is written unless the principal sees those records before and after
+    async fn put_records(
+        &self,
+        entity: Entity,
+        ids: &str,
+        records: &[String],
+        principal: &Principal,
+    ) -> Result<()>;
+    /// Stored user passwords by sourcedId for the sourcedIds in the json array `ids`,
+    /// or every user with a password when `None`
+    async fn get_passwords(&self, ids: Option<&str>) -> Result<HashMap<String, String>>;
+    /// Replaces plaintext passwords with their hashes, recording the change without the plaintext,
+    /// skipping any changed since they were read. Returns how many were replaced
+    async fn set_password_hashes(&self, hashes: &[PasswordHash]) -> Result<u64>;
+    async fn get_api_creds(&self, client_id: &str) -> Result<ApiCreds>;
+    async fn get_api_users(&self) -> Result<Vec<UserList>>;
+    async fn get_api_user(&self, uuid: &str) -> Result<UserList>;
+    /// Stores a new credential with an already hashed secret
+    async fn insert_api_user(
+        &self,
+        client_id: &str,
+        secret: &str,
+        user: &CreateApiUser,
+        scopes: &Scopes,
+    ) -> Result<()>;
+    async fn modify_api_user(
+        &self,
+        uuid: &str,
+        update: &UpdateApiUser,
+        add: &Scopes,
+        remove: &Scopes,
+    ) -> Result<()>;
+    async fn set_api_secret(&self, uuid: &str, secret: &str) -> Result<()>;
+    /// Records the time of the last successful login
+    async fn touch_api_user(&self, client_id: &str) -> Result<()>;
+    async fn delete_api_user(&self, uuid: &str) -> Result<()>;
+    /// Finds the credential a TLS client certificate is bound to, by fingerprint then subject
+    async fn get_cert_client_id(&self, cert: &tls::ClientCert) -> Result<Option<String>>;
+    /// Resolves the orgs a credential is bound to, their descendants
+    /// and the academic sessions used by their classes and courses
+    async fn get_org_scope(&self, client_id: &str) -> Result<Option<OrgScope>>;
+    /// Loads the redaction profile attached to a credential
+    async fn get_redaction(&self, client_id: &str) -> Result<Option<Redaction>>;
+    async fn get_redaction_profiles(&self) -> Result<Vec<RedactionProfile>>;
+    async fn put_redaction_profile(&self, profile: RedactionProfile) -> Result<()>;
+    async fn delete_redaction_profile(&self, name: &str) -> Result<()>;
+    async fn record_request(&self, entry: &AuditRequest) -> Result<()>;
+    async fn get_audit_requests(&self, filter: &AuditFilter) -> Result<Vec<AuditRequest>>;
+    async fn get_audit_changes(&self, filter: &AuditFilter) -> Result<Vec<AuditChange>>;
+    async fn get_changes(&self, filter: &ChangeFilter) -> Result<Vec<RecordChange>>;
+    /// The id and time of the newest change log entry, every write to a record adds one
+    async fn last_change(&self) -> Result<Option<(i64, DateTime<Utc>)>>;
+    /// Deletes audit entries older than `before`, returning how many were removed
+    async fn purge_audit(&self, before: DateTime<Utc>) -> Result<u64>;
+    /// Deletes records marked tobedeleted before `before` which nothing else points at,
+    /// with their link rows, recording them as deleted. Returns how many were removed
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64>;
+    /// Lists GUIDRefs to records which do not exist, with `mark` set to a client_id the rows
+    /// holding them are also marked tobedeleted and the changes recorded against it
+    async fn check_references(&self, mark: Option<&str>) -> Result<Vec<Orphan>>;
+    async fn get_tenants(&self) -> Result<Vec<Tenant>>;
+    async fn insert_tenant(&self, tenant: &Tenant) -> Result<()>;
+    async fn delete_tenant(&self, name: &str) -> Result<()>;
+    /// Re-encrypts every personal data field under a new data key wrapped by `key`,
+    /// returning how many rows were rewritten
+    async fn rekey(&self, key: &MasterKey) -> Result<u64>;
+    /// Writes a consistent snapshot of the live database to `path`
+    async fn backup(&self, path: &str) -> Result<()>;
+    /// The schema version of the database and the version this release expects,
+    /// reading it is also how readiness checks the database answers
+    async fn schema_version(&self) -> Result<(i64, i64)>;
+    /// Errors when personal data fields are encrypted and the server has no key for them
+    fn check_keys(&self) -> Result<()>;
+    /// The number of records of each entity by its singular name, deleted ones included
+    async fn record_counts(&self) -> Result<Vec<(&'static str, i64)>>;
+    /// How busy each connection pool is
+    fn pool_stats(&self) -> Vec<PoolStats>;
+}
+
+/// The collections as they were when a snapshot was taken, read in a transaction of its own
+/// so that counting a collection and then reading it see the same records
+#[tide::utils::async_trait]
+pub(crate) trait Snapshot: Send {
+    /// How many records a collection view has
+    async fn count_collection(&mut self, view: Collection) -> Result<usize>;
+    /// The records of a collection view one json object at a time, in the order of the view
+    fn stream_collection(&mut self, view: Collection) -> BoxStream<'_, Result<String>>;
+}
+
+/// The connections of a pool and how long they have taken to acquire
+pub(crate) struct PoolStats {
+    pub(crate) name: &'static str,
+    pub(crate) size: u32,
+    pub(crate) idle: usize,
+    pub(crate) waits: metrics::Latencies,
+}
+
+/// A connection pool which times every wait for one of its connections,
+/// the waits grow once every connection is held by a query
+pub(crate) struct TimedPool<DB: sqlx::Database> {
+    name: &'static str,
+    pool: sqlx::Pool<DB>,
+    waits: Mutex<metrics::Latencies>,
+}
+
+impl<DB: sqlx::Database> TimedPool<DB> {
+    fn new(name: &'static str, pool: sqlx::Pool<DB>) -> Self {
+        Self {
+            name,
+            pool,
+            waits: Mutex::new(metrics::Latencies::default()),
+        }
+    }
+
+    async fn acquire(&self) -> Result<sqlx::pool::PoolConnection<DB>> {
+        let start = Instant::now();
+        let conn = self.pool.acquire().await;
+        self.waits.lock().unwrap().observe(start.elapsed());
+        Ok(conn?)
+    }
+
+    async fn begin(&self) -> Result<sqlx::Transaction<'static, DB>> {
+        let start = Instant::now();
+        let t = self.pool.begin().await;
+        self.waits.lock().unwrap().observe(start.elapsed());
+        Ok(t?)
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            name: self.name,
+            size: self.pool.size(),
+            idle: self.pool.num_idle(),
+            waits: self.waits.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Most records a search looks at, enough for a name shared by many records
+const SEARCH_CANDIDATES: i64 = 1000;
+
+/// A time formatted as the triggers store dateLastModified, so the two compare as text
+fn modified_timestamp(at: DateTime<Utc>) -> String {
+    at.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+}
+
+/// A GUIDRef to a record which does not exist
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Orphan {
+    /// the entity holding the reference, for link rows the entity the link belongs to
+    #[serde(skip)]
+    pub(crate) entity: Entity,
+    pub(crate) sourced_id: String,
+    /// where the reference is stored
+    pub(crate) table: &'static str,
+    pub(crate) column: &'static str,
+    /// the entity the reference should point at
+    pub(crate) references: &'static str,
+    pub(crate) missing: String,
+    /// status of the row holding the reference
+    pub(crate) status: String,
+}
+
+/// The client_id orphans marked from the cli are recorded against in the audit trail
+pub(super) const CHECK_CLIENT_ID: &str = "check";
+
+/// Groups orphans by the singular name of the entity holding them
+pub(crate) fn orphans_by_entity(orphans: Vec<Orphan>) -> BTreeMap<&'static str, Vec<Orphan>> {
+    let mut grouped: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for orphan in orphans {
+        grouped
+            .entry(orphan.entity.name())
+            .or_default()
+            .push(orphan);
+    }
+    grouped
+}
+
+/// A column holding the sourcedId of another record, the foreign keys between entities
+struct Reference {
+    table: &'static str,
+    column: &'static str,
+    /// the entity the row belongs to and its column holding that record's sourcedId
+    owner: Entity,
+    owner_column: &'static str,
+    target: Entity,
+}
+
+impl Reference {
+    /// Every reference between entities, gathered from the tables purging walks
+    fn all() -> Vec<Reference> {
+        let owner = |table: &str| {
+            PURGE_ORDER.iter().find_map(|e| {
+                let tables = e.tables();
+                match tables.table == table {
+                    true => Some((*e, "sourcedId")),
+                    false => tables
+                        .links
+                        .iter()
+                        .find(|(t, _)| *t == table)
+                        .map(|(_, c)| (*e, *c)),
+                }
+            })
+        };
+        let mut all = Vec::new();
+        for target in PURGE_ORDER {
+            let tables = target.tables();
+            let columns = tables.links.iter().chain(tables.linked_from);
+            for (table, column) in columns.chain(tables.references) {
+                let (owner, owner_column) = owner(table).expect("every table belongs to an entity");
+                all.push(Reference {
+                    table,
+                    column,
+                    owner,
+                    owner_column,
+                    target,
+                });
+            }
+        }
+        all
+    }
+
+    /// Selects the owner sourcedId, missing sourcedId and status of rows pointing at nothing
+    fn orphans(&self) -> String {
+        format!(
+            "SELECT {table}.{owner}, {table}.{column}, s.token FROM {table}
+            JOIN StatusType s ON s.id = {table}.statusTypeId
+            WHERE {table}.{column} IS NOT NULL
+            AND NOT EXISTS (SELECT 1 FROM {target} p WHERE p.sourcedId = {table}.{column})
+            ORDER BY {table}.{owner}",
+            table = self.table,
+            owner = self.owner_column,
+            column = self.column,
+            target = self.target.tables().table
+        )
+    }
+
+    /// Whether the reference is held by a record itself rather than one of its link rows
+    fn on_record(&self) -> bool {
+        self.owner_column == "sourcedId"
+    }
+
+    /// Marks the rows pointing at nothing tobedeleted,
+    /// records also get dateLastModified set to the `modified` placeholder
+    fn mark(&self, modified: &str) -> String {
+        let touch = match self.on_record() {
+            true => format!(", dateLastModified = {}", modified),
+            false => String::new(),
+        };
+        format!(
+            "UPDATE {table} SET statusTypeId = (SELECT id FROM StatusType WHERE token = 'tobedeleted'){touch}
+            WHERE {column} IS NOT NULL
+            AND statusTypeId <> (SELECT id FROM StatusType WHERE token = 'tobedeleted')
+            AND NOT EXISTS (SELECT 1 FROM {target} p WHERE p.sourcedId = {table}.{column})",
+            table = self.table,
+            column = self.column,
+            target = self.target.tables().table,
+            touch = touch
+        )
+    }
+
+    fn orphan(&self, sourced_id: String, missing: String, status: String) -> Orphan {
+        Orphan {
+            entity: self.owner,
+            sourced_id,
+            table: self.table,
+            column: self.column,
+            references: self.target.name(),
+            missing,
+            status,
+        }
+    }
+}
+
+/// The json array of sourcedIds per entity holding orphans which are not yet tobedeleted,
+/// the records whose json changes when the orphans are marked
+fn orphan_owners(orphans: &[Orphan]) -> Result<Vec<(Entity, String)>> {
+    let mut owners = Vec::new();
+    for entity in PURGE_ORDER {
+        let ids: Vec<&str> = orphans
+            .iter()
+            .filter(|o| o.entity.name() == entity.name() && o.status != "tobedeleted")
+            .map(|o| o.sourced_id.as_str())
+            .collect();
+        if !ids.is_empty() {
+            owners.push((entity, serde_json::to_string(&ids)?));
+        }
+    }
+    Ok(owners)
+}
+
+/// A change to a single record for the audit trail
+struct Change<'a> {
+    sourced_id: &'a str,
+    operation: &'static str,
+    before: Option<&'a str>,
+    after: Option<&'a str>,
+}
+
+/// The difference between two sets of snapshots keyed by sourcedId,
+/// records with an identical before and after are skipped
+fn changes<'a>(
+    before: &'a HashMap<String, String>,
+    after: &'a HashMap<String, String>,
+) -> Vec<Change<'a>> {
+    let mut changes = Vec::new();
+    for (sourced_id, new) in after {
+        let old = before.get(sourced_id);
+        let operation = match old {
+            Some(old) if old == new => continue,
+            Some(_) => "update",
+            None => "create",
+        };
+        changes.push(Change {
+            sourced_id,
+            operation,
+            before: old.map(|o| o.as_str()),
+            after: Some(new),
+        });
+    }
+    for (sourced_id, old) in before.iter().filter(|(id, _)| !after.contains_key(*id)) {
+        changes.push(Change {
+            sourced_id,
+            operation: "delete",
+            before: Some(old),
+            after: None,
+        });
+    }
+    changes
+}
+
+/// The snapshots of the `updated` users without the plaintext passwords they held before
+/// being hashed, so the history never holds them
+fn without_passwords(
+    before: HashMap<String, String>,
+    updated: &[&str],
+) -> Result<HashMap<String, String>> {
+    let mut kept = HashMap::new();
+    for (sourced_id, json) in before {
+        if !updated.contains(&sourced_id.as_str()) {
+            continue;
+        }
+        let mut user: serde_json::Value = serde_json::from_str(&json)?;
+        if let Some(user) = user.as_object_mut() {
+            user.remove("password");
+        }
+        kept.insert(sourced_id, user.to_string());
+    }
+    Ok(kept)
+}
+
+pub(super) async fn create_api_user(user: CreateApiUser, db: &dyn Storage) -> Result<super::Creds> {
+    let scopes = Scopes::parse_strict(&user.scope)?;
+    let new = auth::credentials::generate_credentials().await?;
+    db.insert_api_user(&new.creds.client_id, &new.encrypt, &user, &scopes)
+        .await?;
+    let authscopes = db.get_api_creds(&new.creds.client_id).await?;
     let out = super::Creds {
         client_id: new.creds.client_id.clone(),
         client_secret: new.creds.client_secret.clone(),
@@ -108,28 +895,42 @@ pub(super) async fn create_api_user(
     Ok(out)
 }
 
-pub(super) async fn delete_api_user(uuid: &str, db: &sqlx::SqlitePool) -> Result<()> {
-    let deleted = sqlx::query!("DELETE FROM credentials WHERE client_id = ?", uuid)
-        .execute(db)
-        .await?
-        .rows_affected();
+pub(super) async fn update_api_user(
+    uuid: &str,
+    update: UpdateApiUser,
+    db: &dyn Storage,
+) -> Result<UserList> {
+    let add = Scopes::parse_strict(update.add_scope.as_deref().unwrap_or_default())?;
+    let remove = Scopes::parse_strict(update.remove_scope.as_deref().unwrap_or_default())?;
+    db.modify_api_user(uuid, &update, &add, &remove).await?;
+    db.get_api_user(uuid).await
+}
 
-    if deleted > 0 {
-        return Ok(());
-    }
-    Err(ServerError::NoRecordDeleted)
+/// Replaces the client secret, returning the plaintext secret which is never stored
+pub(super) async fn rotate_api_secret(uuid: &str, db: &dyn Storage) -> Result<super::Creds> {
+    let (client_secret, encrypt) = auth::credentials::generate_password().await?;
+    db.set_api_secret(uuid, &encrypt).await?;
+    let authscopes = db.get_api_creds(uuid).await?;
+    Ok(super::Creds {
+        client_id: uuid.to_string(),
+        client_secret,
+        scope: authscopes.scope,
+    })
 }
 
 /// Creates a database call function to the relevant json array object view
 /// $name is the name of the function mirroring the HTTP API get request
 /// $data is the json array struct to serialize to
-/// $query is the SQL query to the relevant view
+/// $view is the collection view to query
 /// $object is the json object contained in the $data struct
 macro_rules! create_get_db {
-    ($name:ident, $data:ty, $query:literal, $object:ident) => {
-        pub(crate) async fn $name(db: &sqlx::SqlitePool) -> Result<$data> {
-            let row = sqlx::query!($query).fetch_one(db).await?;
-            if let Some(data) = row.$object {
+    ($name:ident, $data:ty, $view:expr, $object:ident) => {
+        pub(crate) async fn $name(db: &dyn Storage, as_of: Option<DateTime<Utc>>) -> Result<$data> {
+            let data = match as_of {
+                Some(at) => db.get_collection_as_of($view, at).await?,
+                None => db.get_collection($view).await?,
+            };
+            if let Some(data) = data {
                 let output: $data = serde_json::from_str(&data)?;
                 if output.$object.len() >= 1 {
                     return Ok(output);
@@ -143,91 +944,65 @@ macro_rules! create_get_db {
 create_get_db!(
     get_all_classes,
     model::Classes,
-    "SELECT classes FROM ClassesJsonArray",
+    Collection::Classes,
     classes
 );
 create_get_db!(
     get_all_academic_sessions,
     model::AcademicSessions,
-    "SELECT academicSessions AS academic_sessions FROM AcademicSessionsJsonArray",
+    Collection::AcademicSessions,
     academic_sessions
 );
 create_get_db!(
     get_all_periods,
     model::Periods,
-    "SELECT periods FROM PeriodsJsonArray",
+    Collection::Periods,
     periods
 );
-create_get_db!(
-    get_all_orgs,
-    model::Orgs,
-    "SELECT orgs FROM OrgsJsonArray",
-    orgs
-);
-create_get_db!(
-    get_all_users,
-    model::Users,
-    "SELECT users FROM UsersJsonArray",
-    users
-);
+create_get_db!(get_all_orgs, model::Orgs, Collection::Orgs, orgs);
+create_get_db!(get_all_users, model::Users, Collection::Users, users);
 create_get_db!(
     get_all_subjects,
     model::Subjects,
-    "SELECT subjects FROM SubjectsJsonArray",
+    Collection::Subjects,
     subjects
 );
 create_get_db!(
     get_all_courses,
     model::Courses,
-    "SELECT courses FROM CoursesJsonArray",
+    Collection::Courses,
     courses
 );
 create_get_db!(
     get_all_enrollments,
     model::Enrollments,
-    "SELECT enrollments FROM EnrollmentsJsonArray",
+    Collection::Enrollments,
     enrollments
 );
 create_get_db!(
     get_all_grading_periods,
     model::AcademicSessions,
-    "SELECT academicSessions AS academic_sessions FROM VwORGetAllGradingPeriods",
+    Collection::GradingPeriods,
     academic_sessions
 );
-create_get_db!(
-    get_all_schools,
-    model::Orgs,
-    "SELECT orgs FROM VwORGetAllSchools",
-    orgs
-);
-create_get_db!(
-    get_all_students,
-    model::Users,
-    "SELECT users FROM VwORGetAllStudents",
-    users
-);
-create_get_db!(
-    get_all_teachers,
-    model::Users,
-    "SELECT users FROM VwORGetAllTeachers",
-    users
-);
+create_get_db!(get_all_schools, model::Orgs, Collection::Schools, orgs);
+create_get_db!(get_all_students, model::Users, Collection::Students, users);
+create_get_db!(get_all_teachers, model::Users, Collection::Teachers, users);
 create_get_db!(
     get_all_terms,
     model::AcademicSessions,
-    "SELECT academicSessions AS academic_sessions FROM VwOrGetAllTerms",
+    Collection::Terms,
     academic_sessions
 );
 
+/// Creates a database call function for a single record or a collection for one school
+/// $method is the Storage method to call with $view and the id
 macro_rules! create_get_db_by_id {
-    ($name:ident, $data:ty, $query:literal, $object:ident) => {
-        pub(crate) async fn $name(db: &sqlx::SqlitePool, id: &str) -> Result<$data> {
-            let row = sqlx::query!($query, id).fetch_optional(db).await?;
-            if let Some(r) = row {
-                if let Some(data) = r.$object {
-                    let output: $data = serde_json::from_str(&data)?;
-                    return Ok(output);
-                }
+    ($name:ident, $data:ty, $method:ident, $view:expr) => {
+        pub(crate) async fn $name(db: &dyn Storage, id: &str) -> Result<$data> {
+            if let Some(data) = db.$method($view, id).await? {
+                let output: $data = serde_json::from_str(&data)?;
+                return Ok(output);
             }
             Err(ServerError::NoContent)
         }
@@ -237,120 +1012,81 @@ macro_rules! create_get_db_by_id {
 create_get_db_by_id!(
     get_academic_session,
     model::AcademicSessionSingle,
-    r#"SELECT academicSession AS "academic_session: String" FROM VwORGetAcademicSession WHERE json_extract(academicSession, '$.academicSession.sourcedId') = ?"#,
-    academic_session
-);
-create_get_db_by_id!(
-    get_class,
-    model::ClassSingle,
-    r#"SELECT class AS "class: String" FROM VwORGetClass WHERE json_extract(class, '$.class.sourcedId') = ?"#,
-    class
-);
-create_get_db_by_id!(
-    get_course,
-    model::CourseSingle,
-    r#"SELECT course AS "course: String" FROM VwORGetCourse WHERE json_extract(course, '$.course.sourcedId') = ?"#,
-    course
+    get_record,
+    Record::AcademicSession
 );
+create_get_db_by_id!(get_class, model::ClassSingle, get_record, Record::Class);
+create_get_db_by_id!(get_course, model::CourseSingle, get_record, Record::Course);
 create_get_db_by_id!(
     get_grading_period,
     model::AcademicSessionSingle,
-    r#"SELECT academicSession AS "academic_session: String" FROM VwORGetGradingPeriod WHERE json_extract(academicSession, '$.academicSession.sourcedId') = ?"#,
-    academic_session
+    get_record,
+    Record::GradingPeriod
 );
 create_get_db_by_id!(
     get_enrollment,
     model::EnrollmentSingle,
-    r#"SELECT enrollment AS "enrollment: String" FROM VwORGetEnrollment WHERE json_extract(enrollment, '$.enrollment.sourcedId') = ?"#,
-    enrollment
-);
-create_get_db_by_id!(
-    get_org,
-    model::OrgSingle,
-    r#"SELECT org AS "org: String" FROM VwORGetOrg WHERE json_extract(org, '$.org.sourcedId') = ?"#,
-    org
-);
-create_get_db_by_id!(
-    get_school,
-    model::OrgSingle,
-    r#"SELECT org AS "org: String" FROM VwORGetSchool WHERE json_extract(org, '$.org.sourcedId') = ?"#,
-    org
-);
-create_get_db_by_id!(
-    get_student,
-    model::UserSingle,
-    r#"SELECT user AS "user: String" FROM VwORGetStudent WHERE json_extract(user, '$.user.sourcedId') = ?"#,
-    user
-);
-create_get_db_by_id!(
-    get_teacher,
-    model::UserSingle,
-    r#"SELECT user AS "user: String" FROM VwORGetTeacher WHERE json_extract(user, '$.user.sourcedId') = ?"#,
-    user
+    get_record,
+    Record::Enrollment
 );
+create_get_db_by_id!(get_org, model::OrgSingle, get_record, Record::Org);
+create_get_db_by_id!(get_school, model::OrgSingle, get_record, Record::School);
+create_get_db_by_id!(get_student, model::UserSingle, get_record, Record::Student);
+create_get_db_by_id!(get_teacher, model::UserSingle, get_record, Record::Teacher);
 create_get_db_by_id!(
     get_term,
     model::AcademicSessionSingle,
-    r#"SELECT academicSession AS "academic_session: String" FROM VwORGetTerm WHERE json_extract(academicSession, '$.academicSession.sourcedId') = ?"#,
-    academic_session
-);
-create_get_db_by_id!(
-    get_user,
-    model::UserSingle,
-    r#"SELECT user AS "user: String" FROM VwORGetUser WHERE json_extract(user, '$.user.sourcedId') = ?"#,
-    user
+    get_record,
+    Record::Term
 );
+create_get_db_by_id!(get_user, model::UserSingle, get_record, Record::User);
 create_get_db_by_id!(
     get_classes_for_school,
     model::Classes,
-    r#"SELECT json_object('classes', json_group_array(json(class))) AS 'classes'
-    FROM ClassesJson
-    WHERE json_extract(class, '$.school.sourcedId') = ?"#,
-    classes
+    get_for_school,
+    ForSchool::Classes
 );
 create_get_db_by_id!(
     get_students_for_school,
     model::Users,
-    r#"
-    SELECT json_object('users', json_group_array(json(user))) AS 'users'
-    FROM UsersJson, json_each(json_extract(user, '$.orgs'))
-    WHERE json_extract(json_each.value, '$.sourcedId') = ?
-        AND json_extract(user, '$.role') = "student"
-    "#,
-    users
+    get_for_school,
+    ForSchool::Students
 );
 create_get_db_by_id!(
     get_teachers_for_school,
     model::Users,
-    r#"
-    SELECT json_object('users', json_group_array(json(user))) AS 'users'
-    FROM UsersJson, json_each(json_extract(user, '$.orgs'))
-    WHERE json_extract(json_each.value, '$.sourcedId') = ?
-        AND json_extract(user, '$.role') = "teacher"
-    "#,
-    users
+    get_for_school,
+    ForSchool::Teachers
 );
 create_get_db_by_id!(
     get_enrollments_for_school,
     model::Enrollments,
-    r#"
-    SELECT json_object('enrollments', json_group_array(json(enrollment))) AS 'enrollments'
-    FROM EnrollmentsJson
-    WHERE json_extract(enrollment, '$.school.sourcedId') = ?
-    "#,
-    enrollments
+    get_for_school,
+    ForSchool::Enrollments
 );
 
+/// Creates a database call function upserting records through the relevant json view
+/// $object is the json object contained in the $data struct
+/// $entity is the entity whose view is upserted
 macro_rules! create_put_db {
-    ($name:ident, $data:ty, $query:literal, $object:ident) => {
-        pub(crate) async fn $name(data: $data, db: &sqlx::SqlitePool) -> Result<()> {
-            let mut transaction = db.begin().await?;
-            for i in data.$object.iter() {
-                let json = serde_json::to_string(i)?;
-                sqlx::query!($query, json).execute(&mut transaction).await?;
-            }
-            transaction.commit().await?;
-            Ok(())
+    ($name:ident, $data:ty, $object:ident, $entity:expr) => {
+        pub(crate) async fn $name(
+            data: $data,
+            principal: &Principal,
+            db: &dyn Storage,
+        ) -> Result<()> {
+            let ids = json!(data
+                .$object
+                .iter()
+                .map(|i| &i.sourced_id)
+                .collect::<Vec<_>>())
+            .to_string();
+            let records = data
+                .$object
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            db.put_records($entity, &ids, &records, principal).await
         }
     };
 }
@@ -358,70 +1094,229 @@ macro_rules! create_put_db {
 create_put_db!(
     put_academic_sessions,
     model::AcademicSessions,
-    "INSERT INTO AcademicSessionsJson(academicSession) VALUES (json(?))",
-    academic_sessions
-);
-create_put_db!(
-    put_periods,
-    model::Periods,
-    "INSERT INTO PeriodsJson(period) VALUES (json(?))",
-    periods
-);
-create_put_db!(
-    put_subjects,
-    model::Subjects,
-    "INSERT INTO SubjectsJson(subject) VALUES (json(?))",
-    subjects
-);
-create_put_db!(
-    put_classes,
-    model::Classes,
-    "INSERT INTO ClassesJson(class) VALUES (json(?))",
-    classes
-);
-create_put_db!(
-    put_courses,
-    model::Courses,
-    "INSERT INTO CoursesJson(course) VALUES (json(?))",
-    courses
-);
-create_put_db!(
-    put_orgs,
-    model::Orgs,
-    "INSERT INTO OrgsJson(org) VALUES (json(?))",
-    orgs
-);
-create_put_db!(
-    put_users,
-    model::Users,
-    "INSERT INTO UsersJson(user) VALUES (json(?))",
-    users
+    academic_sessions,
+    Entity::AcademicSessions
 );
+create_put_db!(put_periods, model::Periods, periods, Entity::Periods);
+create_put_db!(put_subjects, model::Subjects, subjects, Entity::Subjects);
+create_put_db!(put_classes, model::Classes, classes, Entity::Classes);
+create_put_db!(put_courses, model::Courses, courses, Entity::Courses);
+create_put_db!(put_orgs, model::Orgs, orgs, Entity::Orgs);
+create_put_db!(put_user_records, model::Users, users, Entity::Users);
 create_put_db!(
     put_enrollments,
     model::Enrollments,
-    "INSERT INTO EnrollmentsJson(enrollment) VALUES (json(?))",
-    enrollments
+    enrollments,
+    Entity::Enrollments
 );
 
-pub(super) async fn init(path: &str, create: bool) -> Result<sqlx::Pool<sqlx::Sqlite>> {
-    init_db(path, create).await?;
-    let pool = connect(path).await?;
+/// Stores users with their passwords hashed, a password matching the user's stored hash
+/// keeps that hash so an unchanged user is not recorded as changed
+pub(crate) async fn put_users(
+    mut data: model::Users,
+    principal: &Principal,
+    db: &dyn Storage,
+) -> Result<()> {
+    let ids = json!(data
+        .users
+        .iter()
+        .filter(|u| u.password.is_some())
+        .map(|u| &u.sourced_id)
+        .collect::<Vec<_>>())
+    .to_string();
+    let stored = db.get_passwords(Some(&ids)).await?;
+    data.users = auth::passwords::hash_users(data.users, stored).await?;
+    put_user_records(data, principal, db).await
+}
+
+/// Turns the --database option into a connection url,
+/// anything other than a postgres or sqlite url is a path to an sqlite file
+pub(super) fn database_url(database: &str) -> String {
+    if database.starts_with("postgres://")
+        || database.starts_with("postgresql://")
+        || database.starts_with("sqlite:")
+    {
+        database.to_string()
+    } else {
+        format!("sqlite:{}", database)
+    }
+}
+
+pub(super) async fn init(
+    url: &str,
+    create: bool,
+    pool: &PoolConfig,
+    key: Option<&MasterKey>,
+) -> Result<Arc<dyn Storage>> {
+    init_db(url, create).await?;
+    migrate::run(url).await?;
+    let db = connect(url, pool, key).await?;
     if create {
-        init_schema(&pool).await?;
-        init_admin(&pool).await?;
+        init_admin(db.as_ref()).await?;
+    }
+    Ok(db)
+}
+
+/// Creates or opens a tenant's database, returning root admin credentials when it had none
+pub(super) async fn provision(
+    url: &str,
+    pool: &PoolConfig,
+    key: Option<&MasterKey>,
+) -> Result<(Arc<dyn Storage>, Option<super::Creds>)> {
+    init_db(url, true).await?;
+    migrate::run(url).await?;
+    let db = connect(url, pool, key).await?;
+    let creds = create_root_admin(db.as_ref()).await?;
+    Ok((db, creds))
+}
+
+/// The url of a tenant's database when none is given, the server's own with `_<name>` appended
+/// to the file name or database name
+pub(super) fn tenant_database_url(url: &str, name: &str) -> Result<String> {
+    match url.parse::<AnyKind>()? {
+        AnyKind::Sqlite => {
+            let path = std::path::Path::new(sqlite::file_path(url));
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let file = match path.extension() {
+                Some(ext) => format!("{}_{}.{}", stem, name, ext.to_string_lossy()),
+                None => format!("{}_{}", stem, name),
+            };
+            Ok(format!("sqlite:{}", path.with_file_name(file).display()))
+        }
+        AnyKind::Postgres => {
+            let mut url =
+                http_types::Url::parse(url).map_err(|_| ServerError::InvalidParameters)?;
+            let database = format!("{}_{}", url.path().trim_start_matches('/'), name);
+            url.set_path(&database);
+            Ok(url.to_string())
+        }
+    }
+}
+
+/// Where a database lives, for telling whether two urls name the same database
+#[derive(Debug, PartialEq)]
+pub(super) enum Location {
+    /// the absolute path of a SQLite file with any symlinks resolved
+    File(std::path::PathBuf),
+    /// a Postgres database by the host and port of its server
+    Database { server: String, name: String },
+}
+
+impl Location {
+    pub(super) fn of(url: &str) -> Result<Self> {
+        let invalid = || ServerError::InvalidTenant(format!("invalid database {}", url));
+        match url.parse::<AnyKind>()? {
+            AnyKind::Sqlite => {
+                let path = std::path::Path::new(sqlite::file_path(url));
+                if let Ok(path) = std::fs::canonicalize(path) {
+                    return Ok(Location::File(path));
+                }
+                // a file yet to be created, its directory has to exist
+                let file = path.file_name().ok_or_else(invalid)?;
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => std::path::Path::new("."),
+                };
+                let dir = std::fs::canonicalize(dir).map_err(|_| invalid())?;
+                Ok(Location::File(dir.join(file)))
+            }
+            AnyKind::Postgres => {
+                let url = http_types::Url::parse(url).map_err(|_| invalid())?;
+                let host = url.host_str().unwrap_or("localhost").to_ascii_lowercase();
+                Ok(Location::Database {
+                    server: format!("{}:{}", host, url.port().unwrap_or(5432)),
+                    name: url.path().trim_start_matches('/').to_string(),
+                })
+            }
+        }
+    }
+
+    /// Whether a tenant's database at this location is kept beside the server's `host` one:
+    /// a file in the same directory or a database on the same server named `<host name>_...`
+    pub(super) fn is_beside(&self, host: &Location) -> bool {
+        match (self, host) {
+            (Location::File(path), Location::File(host)) => path.parent() == host.parent(),
+            (
+                Location::Database { server, name },
+                Location::Database {
+                    server: host_server,
+                    name: host_name,
+                },
+            ) => server == host_server && name.starts_with(&format!("{}_", host_name)),
+            _ => false,
+        }
     }
-    Ok(pool)
 }
 
-async fn init_db(path: &str, create: bool) -> Result<()> {
+/// Connects to a migrated database, with a `key` its personal data fields are encrypted
+/// and a data key is created if it has none
+async fn connect(
+    url: &str,
+    pool: &PoolConfig,
+    key: Option<&MasterKey>,
+) -> Result<Arc<dyn Storage>> {
+    let db: Arc<dyn Storage> = match url.parse::<AnyKind>()? {
+        AnyKind::Sqlite => Arc::new(sqlite::SqliteStorage::connect(url, pool, key).await?),
+        AnyKind::Postgres => Arc::new(postgres::PgStorage::connect(url, pool, key).await?),
+    };
+    Ok(db)
+}
+
+/// Writes a snapshot of the database to `path` without stopping the server
+pub(super) async fn backup(url: &str, path: &str) -> Result<()> {
+    if std::path::Path::new(path).exists() {
+        return Err(ServerError::InvalidBackup(format!(
+            "{} already exists",
+            path
+        )));
+    }
+    init_db(url, false).await?;
+    let db = connect(url, &PoolConfig::default(), None).await?;
+    db.backup(path).await
+}
+
+/// Checks the integrity and schema version of a backup then swaps it in,
+/// the server must be stopped first
+pub(super) async fn restore(url: &str, path: &str) -> Result<()> {
+    if !std::path::Path::new(path).is_file() {
+        return Err(ServerError::InvalidBackup(format!(
+            "{} is not a file",
+            path
+        )));
+    }
+    match url.parse::<AnyKind>()? {
+        AnyKind::Sqlite => sqlite::restore(url, path).await,
+        AnyKind::Postgres => postgres::restore(url, path).await,
+    }
+}
+
+/// Re-encrypts the personal data fields of a database under a new data key,
+/// wrapped by `new_key` when given to replace the master key, the server must be stopped first
+pub(super) async fn rekey(url: &str, key: &MasterKey, new_key: Option<&MasterKey>) -> Result<u64> {
+    init_db(url, false).await?;
+    migrate::run(url).await?;
+    let db = connect(url, &PoolConfig::default(), Some(key)).await?;
+    db.rekey(new_key.unwrap_or(key)).await
+}
+
+/// Applies pending migrations to an existing database, or with `dry_run` only lists them
+pub(super) async fn migrate(url: &str, dry_run: bool) -> Result<Vec<String>> {
+    init_db(url, false).await?;
+    let migrations = match dry_run {
+        true => migrate::pending(url).await?,
+        false => migrate::run(url).await?,
+    };
+    Ok(migrations.iter().map(|m| m.name.to_string()).collect())
+}
+
+async fn init_db(url: &str, create: bool) -> Result<()> {
     log::info!("seeking database...");
-    let exist = sqlx::Sqlite::database_exists(path).await?;
+    let exist = sqlx::Any::database_exists(url).await?;
     if exist {
         log::info!("found existing database");
     } else if create {
         log::info!("no existing database, creating...");
-        sqlx::Sqlite::create_database(path).await?;
+        sqlx::Any::create_database(url).await?;
     } else {
         log::info!("no existing database found, exiting...");
         return Err(ServerError::NoDatabaseFound);
@@ -429,22 +1324,8 @@ async fn init_db(path: &str, create: bool) -> Result<()> {
     Ok(())
 }
 
-async fn init_schema(pool: &sqlx::SqlitePool) -> Result<()> {
-    let mut t = pool.begin().await?;
-    sqlx::query_file!("db/schema.sql").execute(&mut t).await?;
-    sqlx::query_file!("db/init.sql").execute(&mut t).await?;
-    t.commit().await?;
-    Ok(())
-}
-
-async fn init_admin(pool: &sqlx::SqlitePool) -> Result<()> {
-    let exists = get_api_users(pool).await?.is_empty();
-    if exists {
-        let user = CreateApiUser {
-            tag: "root admin".to_string(),
-            scope: "admin.readonly roster-core.readonly roster-core.createput".to_string(),
-        };
-        let account = create_api_user(user, pool).await?;
+async fn init_admin(db: &dyn Storage) -> Result<()> {
+    if let Some(account) = create_root_admin(db).await? {
         println!(
             "The root admin account is shown below, \
             this will never be provided again so please store these details securely. \
@@ -457,11 +1338,55 @@ async fn init_admin(pool: &sqlx::SqlitePool) -> Result<()> {
     Ok(())
 }
 
-pub(super) async fn connect(path: &str) -> Result<sqlx::Pool<sqlx::Sqlite>> {
-    log::info!("connecting to database...");
-    let pool = SqlitePoolOptions::new()
-        .max_connections(1)
-        .connect(path)
-        .await?;
-    Ok(pool)
+/// Creates a credential with every scope when the database has none
+async fn create_root_admin(db: &dyn Storage) -> Result<Option<super::Creds>> {
+    let empty = db.get_api_users().await?.is_empty();
+    if empty {
+        let user = CreateApiUser {
+            tag: "root admin".to_string(),
+            orgs: Vec::new(),
+            cert_fingerprints: Vec::new(),
+            cert_subjects: Vec::new(),
+            redaction: None,
+            scope: "admin.readonly admin.create admin.delete admin.backup \
+                roster-core.readonly roster-core.createput \
+                roster.readonly roster.createput"
+                .to_string(),
+        };
+        return Ok(Some(create_api_user(user, db).await?));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+#[test]
+fn purge_order_follows_references() {
+    for (i, entity) in PURGE_ORDER.iter().enumerate() {
+        let tables = entity.tables();
+        for (table, _) in tables.references.iter().filter(|(t, _)| *t != tables.table) {
+            let referrer = PURGE_ORDER.iter().position(|e| e.tables().table == *table);
+            assert!(
+                referrer.is_some_and(|r| r < i),
+                "{} purged before {}",
+                tables.table,
+                table
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn plaintext_passwords_stay_out_of_the_history() -> Result<()> {
+    let before: HashMap<String, String> = [
+        ("u1", r#"{"sourcedId":"u1","password":"hunter2"}"#),
+        ("u2", r#"{"sourcedId":"u2","password":"legacy"}"#),
+    ]
+    .iter()
+    .map(|(id, json)| (id.to_string(), json.to_string()))
+    .collect();
+    let kept = without_passwords(before, &["u1"])?;
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept["u1"], r#"{"sourcedId":"u1"}"#);
+    Ok(())
 }
@@ -0,0 +1,315 @@
+use super::Entity;
+use crate::server::{Result, ServerError};
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Starts an encrypted value, followed by `<data key id>:<base64 nonce, ciphertext and tag>`
+const PREFIX: &str = "enc:1:";
+/// User fields holding personal data, the identifiers in userIds are encrypted as well
+const USER_FIELDS: [&str; 4] = ["email", "sms", "phone", "password"];
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Binds wrapped data keys to their purpose so a wrapped key cannot pass for a field value
+const WRAP_AAD: &[u8] = b"oneroster data key";
+
+/// The key wrapping the data keys stored in the database, 32 hex encoded random bytes
+/// supplied to the server and never stored with the data
+#[derive(Clone)]
+pub struct MasterKey([u8; KEY_LEN]);
+
+impl MasterKey {
+    pub(crate) fn from_hex(hex: &str) -> Result<Self> {
+        let mut key = [0; KEY_LEN];
+        hex::decode_to_slice(hex.trim(), &mut key).map_err(|_| {
+            ServerError::Encryption("the encryption key must be 64 hex characters".to_string())
+        })?;
+        Ok(Self(key))
+    }
+
+    /// Generates a data key, returning it wrapped for storage
+    pub(crate) fn new_data_key(&self) -> Result<String> {
+        let mut key = [0; KEY_LEN];
+        rand_bytes(&mut key)?;
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        Ok(base64::encode_block(&seal(
+            &self.0, &nonce, WRAP_AAD, &key,
+        )?))
+    }
+
+    fn unwrap(&self, wrapped: &str) -> Result<Vec<u8>> {
+        let sealed = base64::decode_block(wrapped)?;
+        open(&self.0, WRAP_AAD, &sealed).map_err(|_| {
+            ServerError::Encryption("the encryption key does not match the database".to_string())
+        })
+    }
+}
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MasterKey(..)")
+    }
+}
+
+/// A data key as stored in the data_keys table
+pub(crate) struct DataKey {
+    pub(crate) id: i64,
+    pub(crate) wrapped: String,
+}
+
+/// Keys derived from a data key, one encrypting values and one deriving their nonces
+struct Key {
+    cipher: [u8; KEY_LEN],
+    nonce: [u8; KEY_LEN],
+}
+
+impl Key {
+    fn derive(data_key: &[u8]) -> Result<Self> {
+        Ok(Self {
+            cipher: hmac(data_key, b"cipher")?,
+            nonce: hmac(data_key, b"nonce")?,
+        })
+    }
+}
+
+/// Encrypts the personal data fields of records before they are stored and decrypts them
+/// when read, records pass through unchanged when the server has no encryption key
+#[derive(Default)]
+pub(crate) struct Fields {
+    /// data keys by id, values are written with the newest
+    keys: BTreeMap<i64, Key>,
+    /// the database has data keys but the server was not given the key to unwrap them
+    locked: bool,
+}
+
+impl Fields {
+    pub(crate) fn unlock(master: Option<&MasterKey>, stored: Vec<DataKey>) -> Result<Self> {
+        let master = match master {
+            Some(master) => master,
+            None => {
+                if !stored.is_empty() {
+                    log::warn!("database has encrypted fields but no encryption key was given");
+                }
+                return Ok(Self {
+                    keys: BTreeMap::new(),
+                    locked: !stored.is_empty(),
+                });
+            }
+        };
+        let mut keys = BTreeMap::new();
+        for key in stored {
+            keys.insert(key.id, Key::derive(&master.unwrap(&key.wrapped)?)?);
+        }
+        Ok(Self {
+            keys,
+            locked: false,
+        })
+    }
+
+    /// Encrypts the personal data fields of an entity's json record,
+    /// the same value under the same data key always encrypts the same so unchanged
+    /// records still compare equal in the change log
+    pub(crate) fn seal(&self, entity: Entity, json: &str) -> Result<String> {
+        if !matches!(entity, Entity::Users) {
+            return Ok(json.to_string());
+        }
+        self.check_unlocked()?;
+        let (id, key) = match self.keys.iter().next_back() {
+            Some(newest) => newest,
+            None => return Ok(json.to_string()),
+        };
+        let mut user: Value = serde_json::from_str(json)?;
+        for field in USER_FIELDS.iter() {
+            if let Some(value) = user.get_mut(*field) {
+                seal_value(*id, key, value)?;
+            }
+        }
+        if let Some(Value::Array(ids)) = user.get_mut("userIds") {
+            for value in ids.iter_mut().filter_map(|i| i.get_mut("identifier")) {
+                seal_value(*id, key, value)?;
+            }
+        }
+        Ok(user.to_string())
+    }
+
+    /// Encrypts a single column value the way `seal` encrypts it within a record
+    pub(crate) fn seal_field(&self, value: &str) -> Result<String> {
+        self.check_unlocked()?;
+        let mut value = Value::String(value.to_string());
+        if let Some((id, key)) = self.keys.iter().next_back() {
+            seal_value(*id, key, &mut value)?;
+        }
+        Ok(value.as_str().unwrap_or_default().to_string())
+    }
+
+    /// Decrypts a single column value, erroring rather than passing on a value it cannot read
+    pub(crate) fn open_field(&self, value: String) -> Result<String> {
+        self.check_unlocked()?;
+        let mut value = Value::String(value);
+        self.open_value(&mut value)?;
+        Ok(value.as_str().unwrap_or_default().to_string())
+    }
+
+    /// Decrypts every encrypted value in a json document
+    pub(crate) fn open(&self, json: Option<String>) -> Result<Option<String>> {
+        match json {
+            Some(json) if !self.keys.is_empty() && json.contains(PREFIX) => {
+                Ok(Some(self.parse(&json)?.to_string()))
+            }
+            json => Ok(json),
+        }
+    }
+
+    /// Parses a json document, decrypting its encrypted values
+    pub(crate) fn parse(&self, json: &str) -> Result<Value> {
+        let mut value = serde_json::from_str(json)?;
+        self.open_value(&mut value)?;
+        Ok(value)
+    }
+
+    fn open_value(&self, value: &mut Value) -> Result<()> {
+        match value {
+            Value::String(s) if s.starts_with(PREFIX) && !self.keys.is_empty() => {
+                *s = self.decrypt(&s[PREFIX.len()..])?;
+            }
+            Value::Array(values) => {
+                for v in values {
+                    self.open_value(v)?;
+                }
+            }
+            Value::Object(values) => {
+                for v in values.values_mut() {
+                    self.open_value(v)?;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Re-encrypts a single column value under `to`'s data key, plaintext values are encrypted
+    pub(crate) fn reseal(&self, to: &Fields, value: Option<String>) -> Result<Option<String>> {
+        let mut value = match value {
+            Some(value) => Value::String(value),
+            None => return Ok(None),
+        };
+        self.open_value(&mut value)?;
+        if let Some((id, key)) = to.keys.iter().next_back() {
+            seal_value(*id, key, &mut value)?;
+        }
+        Ok(value.as_str().map(str::to_string))
+    }
+
+    /// Re-encrypts a user json record under `to`'s data key
+    pub(crate) fn reseal_user(&self, to: &Fields, json: &str) -> Result<String> {
+        to.seal(Entity::Users, &self.parse(json)?.to_string())
+    }
+
+    /// Errors when the database has encrypted fields and the server was not given their key
+    pub(crate) fn check_unlocked(&self) -> Result<()> {
+        if self.locked {
+            return Err(ServerError::Encryption(
+                "the database has encrypted fields, start the server with its key".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn decrypt(&self, value: &str) -> Result<String> {
+        let invalid = || ServerError::Encryption("unreadable encrypted value".to_string());
+        let (id, sealed) = value.split_once(':').ok_or_else(invalid)?;
+        let id: i64 = id.parse().map_err(|_| invalid())?;
+        let key = self.keys.get(&id).ok_or_else(invalid)?;
+        let sealed = base64::decode_block(sealed).map_err(|_| invalid())?;
+        let plain = open(&key.cipher, &[], &sealed).map_err(|_| invalid())?;
+        String::from_utf8(plain).map_err(|_| invalid())
+    }
+}
+
+fn seal_value(id: i64, key: &Key, value: &mut Value) -> Result<()> {
+    if let Value::String(s) = value {
+        let nonce = hmac(&key.nonce, s.as_bytes())?;
+        let sealed = seal(&key.cipher, &nonce[..NONCE_LEN], &[], s.as_bytes())?;
+        *s = format!("{}{}:{}", PREFIX, id, base64::encode_block(&sealed));
+    }
+    Ok(())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<[u8; KEY_LEN]> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    let mut out = [0; KEY_LEN];
+    out.copy_from_slice(&signer.sign_to_vec()?);
+    Ok(out)
+}
+
+/// AES-256-GCM, returning the nonce, ciphertext and tag
+fn seal(key: &[u8], nonce: &[u8], aad: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+    let mut tag = [0; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        plain,
+        &mut tag,
+    )?;
+    Ok([nonce, &ciphertext, &tag].concat())
+}
+
+fn open(key: &[u8], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(ServerError::Encryption("truncated value".to_string()));
+    }
+    let (nonce, rest) = sealed.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    Ok(decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        aad,
+        ciphertext,
+        tag,
+    )?)
+}
+
+#[cfg(test)]
+#[test]
+fn sealed_users_open_and_rekey() -> Result<()> {
+    let master = MasterKey::from_hex(&"ab".repeat(32))?;
+    let fields = |id| -> Result<Fields> {
+        let wrapped = master.new_data_key()?;
+        Fields::unlock(Some(&master), vec![DataKey { id, wrapped }])
+    };
+    let old = fields(1)?;
+    let user = r#"{"sourcedId":"u1","email":"a@b.org","userIds":[{"identifier":"a1"}]}"#;
+    let sealed = old.seal(Entity::Users, user)?;
+    assert!(!sealed.contains("a@b.org") && !sealed.contains("\"a1\""));
+    assert!(sealed.contains("\"sourcedId\":\"u1\""));
+    assert_eq!(sealed, old.seal(Entity::Users, user)?);
+    let opened = old.parse(&sealed)?;
+    assert_eq!(opened, serde_json::from_str::<Value>(user)?);
+
+    let new = fields(2)?;
+    let resealed = old.reseal_user(&new, &sealed)?;
+    assert!(resealed.contains("enc:1:2:"));
+    assert!(old.parse(&resealed).is_err());
+    assert_eq!(new.parse(&resealed)?, opened);
+
+    let wrong = MasterKey::from_hex(&"cd".repeat(32))?;
+    let stored = DataKey {
+        id: 1,
+        wrapped: master.new_data_key()?,
+    };
+    assert!(Fields::unlock(Some(&wrong), vec![stored]).is_err());
+    Ok(())
+}
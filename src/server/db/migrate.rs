@@ -0,0 +1,194 @@
+use crate::server::{Result, ServerError};
+use chrono::Utc;
+use sqlx::any::{AnyKind, AnyPool, AnyPoolOptions};
+use sqlx::Executor;
+
+/// A schema change from db/migrations, applied once in version order
+#[derive(Debug)]
+pub(crate) struct Migration {
+    pub(crate) version: i64,
+    pub(crate) name: &'static str,
+    sql: &'static str,
+}
+
+macro_rules! migration {
+    ($backend:literal, $version:literal, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!(
+                "../../../db/migrations/",
+                $backend,
+                "/",
+                $name,
+                ".sql"
+            )),
+        }
+    };
+}
+
+/// Every migration this release knows about per backend, append only
+const SQLITE: &[Migration] = &[
+    migration!("sqlite", 1, "0001_baseline"),
+    migration!("sqlite", 2, "0002_credential_management"),
+    migration!("sqlite", 3, "0003_credential_orgs"),
+    migration!("sqlite", 4, "0004_redaction_profiles"),
+    migration!("sqlite", 5, "0005_audit"),
+    migration!("sqlite", 6, "0006_credential_certs"),
+    migration!("sqlite", 7, "0007_change_log"),
+    migration!("sqlite", 8, "0008_tenants"),
+    migration!("sqlite", 9, "0009_data_keys"),
+    migration!("sqlite", 10, "0010_user_passwords"),
+    migration!("sqlite", 11, "0011_search"),
+    migration!("sqlite", 12, "0012_backup_scope"),
+];
+
+const POSTGRES: &[Migration] = &[
+    migration!("postgres", 1, "0001_baseline"),
+    migration!("postgres", 2, "0002_change_log"),
+    migration!("postgres", 3, "0003_foreign_keys"),
+    migration!("postgres", 4, "0004_tenants"),
+    migration!("postgres", 5, "0005_data_keys"),
+    migration!("postgres", 6, "0006_user_passwords"),
+    migration!("postgres", 7, "0007_backup_scope"),
+];
+
+fn migrations(kind: &AnyKind) -> &'static [Migration] {
+    match kind {
+        AnyKind::Sqlite => SQLITE,
+        AnyKind::Postgres => POSTGRES,
+    }
+}
+
+/// The schema version this release migrates databases to
+pub(crate) fn latest(kind: &AnyKind) -> i64 {
+    migrations(kind).last().map_or(0, |m| m.version)
+}
+
+async fn connect(url: &str) -> Result<(AnyKind, AnyPool)> {
+    let kind = url.parse::<AnyKind>()?;
+    let pool = AnyPoolOptions::new()
+        .max_connections(1)
+        .connect(url)
+        .await?;
+    Ok((kind, pool))
+}
+
+async fn table_exists(kind: &AnyKind, pool: &AnyPool, name: &str) -> Result<bool> {
+    let query = match kind {
+        AnyKind::Sqlite => "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = $1",
+        AnyKind::Postgres => {
+            "SELECT count(*) FROM information_schema.tables
+            WHERE table_schema = current_schema() AND table_name = $1"
+        }
+    };
+    let count: i64 = sqlx::query_scalar(query).bind(name).fetch_one(pool).await?;
+    Ok(count > 0)
+}
+
+/// The schema version of the database, databases created by --init before
+/// versioned migrations have no schema_version table and are the baseline
+async fn current_version(kind: &AnyKind, pool: &AnyPool) -> Result<i64> {
+    if table_exists(kind, pool, "schema_version").await? {
+        let version: Option<i64> = sqlx::query_scalar("SELECT max(version) FROM schema_version")
+            .fetch_one(pool)
+            .await?;
+        Ok(version.unwrap_or(0))
+    } else if table_exists(kind, pool, "credentials").await? {
+        Ok(1)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Errors if a schema version is from a newer release
+pub(crate) fn check_version(kind: &AnyKind, version: i64) -> Result<()> {
+    if version > latest(kind) {
+        return Err(ServerError::SchemaTooNew(version, latest(kind)));
+    }
+    Ok(())
+}
+
+async fn pending_on(kind: &AnyKind, pool: &AnyPool) -> Result<Vec<&'static Migration>> {
+    let version = current_version(kind, pool).await?;
+    check_version(kind, version)?;
+    Ok(migrations(kind)
+        .iter()
+        .filter(|m| m.version > version)
+        .collect())
+}
+
+/// Migrations not yet applied to the database at `url`,
+/// errors if the database was migrated by a newer release
+pub(crate) async fn pending(url: &str) -> Result<Vec<&'static Migration>> {
+    let (kind, pool) = connect(url).await?;
+    let pending = pending_on(&kind, &pool).await;
+    pool.close().await;
+    pending
+}
+
+/// The schema version of the database at `url`, 0 if it has no oneroster schema
+pub(crate) async fn version(url: &str) -> Result<i64> {
+    let (kind, pool) = connect(url).await?;
+    let version = current_version(&kind, &pool).await;
+    pool.close().await;
+    version
+}
+
+/// Applies pending migrations to the database at `url`, each in its own transaction
+pub(crate) async fn run(url: &str) -> Result<Vec<&'static Migration>> {
+    let (kind, pool) = connect(url).await?;
+    let applied = apply(&kind, &pool).await;
+    pool.close().await;
+    applied
+}
+
+async fn apply(kind: &AnyKind, pool: &AnyPool) -> Result<Vec<&'static Migration>> {
+    let version = current_version(kind, pool).await?;
+    let pending = pending_on(kind, pool).await?;
+    let unversioned = !table_exists(kind, pool, "schema_version").await?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version bigint PRIMARY KEY
+            , name text NOT NULL
+            , applied text NOT NULL -- RFC3339
+        )",
+    )
+    .execute(pool)
+    .await?;
+    if unversioned && version > 0 {
+        // record the baseline of a database created before versioned migrations
+        for m in migrations(kind).iter().filter(|m| m.version <= version) {
+            sqlx::query("INSERT INTO schema_version (version, name, applied) VALUES ($1, $2, $3)")
+                .bind(m.version)
+                .bind(m.name)
+                .bind(Utc::now().to_rfc3339())
+                .execute(pool)
+                .await?;
+        }
+    }
+    for m in &pending {
+        log::info!("applying migration {}", m.name);
+        let mut t = pool.begin().await?;
+        t.execute(m.sql).await?;
+        sqlx::query("INSERT INTO schema_version (version, name, applied) VALUES ($1, $2, $3)")
+            .bind(m.version)
+            .bind(m.name)
+            .bind(Utc::now().to_rfc3339())
+            .execute(&mut t)
+            .await?;
+        t.commit().await?;
+    }
+    Ok(pending)
+}
+
+#[cfg(test)]
+#[test]
+fn migrations_are_ordered() {
+    for list in [SQLITE, POSTGRES] {
+        for (i, m) in list.iter().enumerate() {
+            assert_eq!(m.version, i as i64 + 1);
+            assert!(m.name.starts_with(&format!("{:04}_", m.version)));
+        }
+    }
+}
@@ -0,0 +1,1659 @@
+use super::encryption::{DataKey, Fields};
+use super::{
+    changes, migrate, modified_timestamp, orphan_owners, without_passwords, ApiCreds, AuditChange,
+    AuditFilter, AuditRequest, CertKind, ChangeFilter, Collection, CreateApiUser, Entity,
+    ForSchool, MasterKey, Orphan, PasswordHash, PoolConfig, PoolStats, Record, RecordChange,
+    RedactionProfile, Reference, SearchHit, Snapshot, Storage, Tenant, TimedPool, UpdateApiUser,
+    UserList, PASSWORD_UPGRADE_CLIENT_ID, PURGE_CLIENT_ID, PURGE_ORDER, SEARCH_CANDIDATES,
+};
+use crate::server::auth::principal::{OrgScope, Principal};
+use crate::server::auth::redaction::{Redaction, RedactionAction, RedactionRule};
+use crate::server::auth::scopes::Scopes;
+use crate::server::{tls, Result, ServerError};
+use chrono::{DateTime, Utc};
+use futures::stream::{BoxStream, StreamExt};
+use sqlx::any::AnyKind;
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteSynchronous,
+};
+use sqlx::Connection;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub(crate) struct SqliteStorage {
+    /// concurrent readers, in WAL mode these never wait on the writer
+    read: TimedPool<sqlx::Sqlite>,
+    /// SQLite allows one writer at a time so writes queue here instead of on the file lock
+    write: TimedPool<sqlx::Sqlite>,
+    fields: Arc<Fields>,
+}
+
+impl SqliteStorage {
+    pub(crate) async fn connect(
+        url: &str,
+        config: &PoolConfig,
+        key: Option<&MasterKey>,
+    ) -> Result<Self> {
+        log::info!("connecting to database...");
+        let options = SqliteConnectOptions::from_str(url)?
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(config.busy_timeout)
+            // off by default in sqlite itself, and the schema relies on it to reject dangling GUIDRefs
+            .foreign_keys(true);
+        let write = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options.clone())
+            .await?;
+        let read = SqlitePoolOptions::new()
+            .max_connections(config.readers.max(1))
+            .connect_with(options.read_only(true))
+            .await?;
+        let fields = Fields::unlock(key, data_keys(&write, key).await?)?;
+        Ok(Self {
+            read: TimedPool::new("read", read),
+            write: TimedPool::new("write", write),
+            fields: Arc::new(fields),
+        })
+    }
+}
+
+/// The stored data keys, creating the first when the server has an encryption key
+async fn data_keys(pool: &SqlitePool, key: Option<&MasterKey>) -> Result<Vec<DataKey>> {
+    if let Some(key) = key {
+        let wrapped = key.new_data_key()?;
+        let created = Utc::now().to_rfc3339();
+        sqlx::query!(
+            "INSERT INTO data_keys (wrapped_key, created)
+            SELECT ?, ? WHERE NOT EXISTS (SELECT 1 FROM data_keys)",
+            wrapped,
+            created,
+        )
+        .execute(pool)
+        .await?;
+    }
+    let rows = sqlx::query!(r#"SELECT id AS "id!: i64", wrapped_key FROM data_keys"#)
+        .fetch_all(pool)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| DataKey {
+            id: r.id,
+            wrapped: r.wrapped_key,
+        })
+        .collect())
+}
+
+async fn add_api_user_scope(
+    client_id: &str,
+    scope: &str,
+    t: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT OR IGNORE INTO credential_scopes (credential_id, scope_id)
+        SELECT c.id, s.id FROM credentials c, scopes s
+        WHERE c.client_id = ? AND s.scope = ?
+            AND NOT EXISTS (
+                SELECT 1 FROM credential_scopes WHERE credential_id = c.id AND scope_id = s.id
+            )",
+        client_id,
+        scope,
+    )
+    .execute(t)
+    .await?;
+    Ok(())
+}
+
+/// Replaces the orgs a credential is bound to, an empty list removes the restriction
+async fn set_api_user_orgs(
+    client_id: &str,
+    orgs: &[String],
+    t: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM credential_orgs
+        WHERE credential_id = (SELECT id FROM credentials WHERE client_id = ?)",
+        client_id
+    )
+    .execute(&mut *t)
+    .await?;
+    for org in orgs {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO credential_orgs (credential_id, org_sourced_id)
+            SELECT id, ? FROM credentials WHERE client_id = ?",
+            org,
+            client_id
+        )
+        .execute(&mut *t)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Replaces the TLS client certificates of one kind which identify a credential
+async fn set_api_user_certs(
+    client_id: &str,
+    kind: CertKind,
+    values: &[String],
+    t: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<()> {
+    let kind_name = kind.as_str();
+    sqlx::query!(
+        "DELETE FROM credential_certs
+        WHERE credential_id = (SELECT id FROM credentials WHERE client_id = ?) AND kind = ?",
+        client_id,
+        kind_name
+    )
+    .execute(&mut *t)
+    .await?;
+    for value in values {
+        let value = kind.normalise(value)?;
+        sqlx::query!(
+            "INSERT INTO credential_certs (credential_id, kind, value)
+            SELECT id, ?, ? FROM credentials WHERE client_id = ?",
+            kind_name,
+            value,
+            client_id
+        )
+        .execute(&mut *t)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Attaches a redaction profile by name, `None` removes it
+async fn set_api_user_redaction(
+    client_id: &str,
+    profile: Option<&str>,
+    t: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<()> {
+    if let Some(name) = profile {
+        let found = sqlx::query!("SELECT id FROM redaction_profiles WHERE name = ?", name)
+            .fetch_optional(&mut *t)
+            .await?;
+        if found.is_none() {
+            return Err(ServerError::UnknownObject);
+        }
+    }
+    sqlx::query!(
+        "UPDATE credentials
+        SET redaction_profile_id = (SELECT id FROM redaction_profiles WHERE name = ?)
+        WHERE client_id = ?",
+        profile,
+        client_id
+    )
+    .execute(&mut *t)
+    .await?;
+    Ok(())
+}
+
+/// Selects the sourcedId and json of the records of an entity in a json array of sourcedIds
+async fn snapshot(
+    entity: Entity,
+    ids: &str,
+    t: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<HashMap<String, String>> {
+    macro_rules! rows {
+        ($query:literal) => {
+            sqlx::query!($query, ids)
+                .fetch_all(&mut *t)
+                .await?
+                .into_iter()
+                .map(|r| (r.sourced_id, r.data))
+                .collect()
+        };
+    }
+    Ok(match entity {
+        Entity::AcademicSessions => rows!(
+            r#"SELECT json_extract(academicSession, '$.sourcedId') AS "sourced_id!: String", academicSession AS "data!: String"
+            FROM AcademicSessionsJson WHERE json_extract(academicSession, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+        Entity::Periods => rows!(
+            r#"SELECT json_extract(period, '$.sourcedId') AS "sourced_id!: String", period AS "data!: String"
+            FROM PeriodsJson WHERE json_extract(period, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+        Entity::Subjects => rows!(
+            r#"SELECT json_extract(subject, '$.sourcedId') AS "sourced_id!: String", subject AS "data!: String"
+            FROM SubjectsJson WHERE json_extract(subject, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+        Entity::Classes => rows!(
+            r#"SELECT json_extract(class, '$.sourcedId') AS "sourced_id!: String", class AS "data!: String"
+            FROM ClassesJson WHERE json_extract(class, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+        Entity::Courses => rows!(
+            r#"SELECT json_extract(course, '$.sourcedId') AS "sourced_id!: String", course AS "data!: String"
+            FROM CoursesJson WHERE json_extract(course, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+        Entity::Orgs => rows!(
+            r#"SELECT json_extract(org, '$.sourcedId') AS "sourced_id!: String", org AS "data!: String"
+            FROM OrgsJson WHERE json_extract(org, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+        Entity::Users => rows!(
+            r#"SELECT json_extract(user, '$.sourcedId') AS "sourced_id!: String", user AS "data!: String"
+            FROM UsersJson WHERE json_extract(user, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+        Entity::Enrollments => rows!(
+            r#"SELECT json_extract(enrollment, '$.sourcedId') AS "sourced_id!: String", enrollment AS "data!: String"
+            FROM EnrollmentsJson WHERE json_extract(enrollment, '$.sourcedId') IN (SELECT value FROM json_each(?))"#
+        ),
+    })
+}
+
+/// Records the differences between two snapshots in the audit trail and change log
+async fn record_changes(
+    entity: Entity,
+    client_id: &str,
+    before: &HashMap<String, String>,
+    after: &HashMap<String, String>,
+    t: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<()> {
+    let timestamp = Utc::now().to_rfc3339();
+    let name = entity.name();
+    for change in changes(before, after) {
+        sqlx::query!(
+            "INSERT INTO audit_changes (timestamp, client_id, entity, sourced_id, operation, before, after)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+            timestamp,
+            client_id,
+            name,
+            change.sourced_id,
+            change.operation,
+            change.before,
+            change.after,
+        )
+        .execute(&mut *t)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO change_log (timestamp, entity, sourced_id, operation, before, after)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            timestamp,
+            name,
+            change.sourced_id,
+            change.operation,
+            change.before,
+            change.after,
+        )
+        .execute(&mut *t)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Every GUIDRef to a record which does not exist
+async fn orphans(conn: &mut sqlx::SqliteConnection) -> Result<Vec<Orphan>> {
+    let mut found = Vec::new();
+    for reference in Reference::all() {
+        let rows: Vec<(String, String, String)> = sqlx::query_as(&reference.orphans())
+            .fetch_all(&mut *conn)
+            .await?;
+        for (sourced_id, missing, status) in rows {
+            found.push(reference.orphan(sourced_id, missing, status));
+        }
+    }
+    Ok(found)
+}
+
+/// Marks the rows holding orphaned GUIDRefs tobedeleted and records the change to their records.
+/// sqlite counts a row's deferred references as new whenever another of its foreign key columns
+/// changes, so a row with a dangling parent can only be marked on a connection with them off
+async fn mark_orphans(conn: &mut sqlx::SqliteConnection, client_id: &str) -> Result<Vec<Orphan>> {
+    let mut t = conn.begin().await?;
+    let owners = orphan_owners(&orphans(&mut t).await?)?;
+    let mut before = Vec::new();
+    for (entity, ids) in &owners {
+        before.push(snapshot(*entity, ids, &mut t).await?);
+    }
+    let modified = modified_timestamp(Utc::now());
+    for reference in Reference::all() {
+        let mark = reference.mark("?");
+        let query = sqlx::query(&mark);
+        match reference.on_record() {
+            true => query.bind(&modified),
+            false => query,
+        }
+        .execute(&mut t)
+        .await?;
+    }
+    for ((entity, ids), before) in owners.iter().zip(&before) {
+        let touch = entity.tables().touch("?", "SELECT value FROM json_each(?)");
+        sqlx::query(&touch)
+            .bind(&modified)
+            .bind(ids)
+            .execute(&mut t)
+            .await?;
+        let after = snapshot(*entity, ids, &mut t).await?;
+        record_changes(*entity, client_id, before, &after, &mut t).await?;
+    }
+    let found = orphans(&mut t).await?;
+    t.commit().await?;
+    Ok(found)
+}
+
+/// Inserts a single json record into the entity's view, its trigger upserts the tables
+async fn upsert(
+    entity: Entity,
+    json: &str,
+    t: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+) -> Result<()> {
+    macro_rules! insert {
+        ($query:literal) => {
+            sqlx::query!($query, json).execute(&mut *t).await?
+        };
+    }
+    match entity {
+        Entity::AcademicSessions => {
+            insert!("INSERT INTO AcademicSessionsJson(academicSession) VALUES (json(?))")
+        }
+        Entity::Periods => insert!("INSERT INTO PeriodsJson(period) VALUES (json(?))"),
+        Entity::Subjects => insert!("INSERT INTO SubjectsJson(subject) VALUES (json(?))"),
+        Entity::Classes => insert!("INSERT INTO ClassesJson(class) VALUES (json(?))"),
+        Entity::Courses => insert!("INSERT INTO CoursesJson(course) VALUES (json(?))"),
+        Entity::Orgs => insert!("INSERT INTO OrgsJson(org) VALUES (json(?))"),
+        Entity::Users => insert!("INSERT INTO UsersJson(user) VALUES (json(?))"),
+        Entity::Enrollments => insert!("INSERT INTO EnrollmentsJson(enrollment) VALUES (json(?))"),
+    };
+    Ok(())
+}
+
+#[tide::utils::async_trait]
+impl Storage for SqliteStorage {
+    async fn get_collection(&self, view: Collection) -> Result<Option<String>> {
+        let mut db = self.read.acquire().await?;
+        macro_rules! json {
+            ($query:literal) => {
+                sqlx::query_scalar!($query).fetch_one(&mut *db).await?
+            };
+        }
+        self.fields.open(match view {
+            Collection::Classes => json!(r#"SELECT classes AS "classes?: String" FROM ClassesJsonArray"#),
+            Collection::AcademicSessions => json!(
+                r#"SELECT academicSessions AS "academic_sessions?: String" FROM AcademicSessionsJsonArray"#
+            ),
+            Collection::Periods => json!(r#"SELECT periods AS "periods?: String" FROM PeriodsJsonArray"#),
+            Collection::Orgs => json!(r#"SELECT orgs AS "orgs?: String" FROM OrgsJsonArray"#),
+            Collection::Users => json!(r#"SELECT users AS "users?: String" FROM UsersJsonArray"#),
+            Collection::Subjects => {
+                json!(r#"SELECT subjects AS "subjects?: String" FROM SubjectsJsonArray"#)
+            }
+            Collection::Courses => json!(r#"SELECT courses AS "courses?: String" FROM CoursesJsonArray"#),
+            Collection::Enrollments => {
+                json!(r#"SELECT enrollments AS "enrollments?: String" FROM EnrollmentsJsonArray"#)
+            }
+            Collection::GradingPeriods => json!(
+                r#"SELECT academicSessions AS "academic_sessions?: String" FROM VwORGetAllGradingPeriods"#
+            ),
+            Collection::Schools => json!(r#"SELECT orgs AS "orgs?: String" FROM VwORGetAllSchools"#),
+            Collection::Students => json!(r#"SELECT users AS "users?: String" FROM VwORGetAllStudents"#),
+            Collection::Teachers => json!(r#"SELECT users AS "users?: String" FROM VwORGetAllTeachers"#),
+            Collection::Terms => json!(
+                r#"SELECT academicSessions AS "academic_sessions?: String" FROM VwOrGetAllTerms"#
+            ),
+        })
+    }
+
+    async fn get_collection_as_of(
+        &self,
+        view: Collection,
+        at: DateTime<Utc>,
+    ) -> Result<Option<String>> {
+        let (wrapper, entity, field) = view.history();
+        let (field, value) = match field {
+            Some((field, value)) => (Some(format!("$.{}", field)), Some(value)),
+            None => (None, None),
+        };
+        // the latest version of each record written by `at`, deleted records have no after
+        let json = sqlx::query_scalar(
+            "SELECT json_object(?1, json_group_array(json(after)))
+            FROM (
+                SELECT c.after FROM change_log c
+                WHERE c.entity = ?2
+                    AND c.id = (
+                        SELECT max(id) FROM change_log
+                        WHERE entity = c.entity AND sourced_id = c.sourced_id AND timestamp <= ?3
+                    )
+                    AND c.after IS NOT NULL
+                    AND (?4 IS NULL OR json_extract(c.after, ?4) = ?5)
+                ORDER BY c.sourced_id
+            )",
+        )
+        .bind(wrapper)
+        .bind(entity)
+        .bind(at.to_rfc3339())
+        .bind(field)
+        .bind(value)
+        .fetch_one(&mut *self.read.acquire().await?)
+        .await?;
+        self.fields.open(json)
+    }
+
+    async fn snapshot(&self) -> Result<Box<dyn Snapshot>> {
+        let t = self.read.begin().await?;
+        Ok(Box::new(SqliteSnapshot {
+            t,
+            fields: self.fields.clone(),
+        }))
+    }
+
+    async fn get_record(&self, view: Record, id: &str) -> Result<Option<String>> {
+        let mut db = self.read.acquire().await?;
+        macro_rules! json {
+            ($query:literal) => {
+                sqlx::query_scalar!($query, id)
+                    .fetch_optional(&mut *db)
+                    .await?
+                    .flatten()
+            };
+        }
+        self.fields.open(match view {
+            Record::AcademicSession => json!(
+                r#"SELECT academicSession AS "academic_session?: String" FROM VwORGetAcademicSession WHERE json_extract(academicSession, '$.academicSession.sourcedId') = ?"#
+            ),
+            Record::Class => json!(
+                r#"SELECT class AS "class?: String" FROM VwORGetClass WHERE json_extract(class, '$.class.sourcedId') = ?"#
+            ),
+            Record::Course => json!(
+                r#"SELECT course AS "course?: String" FROM VwORGetCourse WHERE json_extract(course, '$.course.sourcedId') = ?"#
+            ),
+            Record::GradingPeriod => json!(
+                r#"SELECT academicSession AS "academic_session?: String" FROM VwORGetGradingPeriod WHERE json_extract(academicSession, '$.academicSession.sourcedId') = ?"#
+            ),
+            Record::Enrollment => json!(
+                r#"SELECT enrollment AS "enrollment?: String" FROM VwORGetEnrollment WHERE json_extract(enrollment, '$.enrollment.sourcedId') = ?"#
+            ),
+            Record::Org => json!(
+                r#"SELECT org AS "org?: String" FROM VwORGetOrg WHERE json_extract(org, '$.org.sourcedId') = ?"#
+            ),
+            Record::School => json!(
+                r#"SELECT org AS "org?: String" FROM VwORGetSchool WHERE json_extract(org, '$.org.sourcedId') = ?"#
+            ),
+            Record::Student => json!(
+                r#"SELECT user AS "user?: String" FROM VwORGetStudent WHERE json_extract(user, '$.user.sourcedId') = ?"#
+            ),
+            Record::Teacher => json!(
+                r#"SELECT user AS "user?: String" FROM VwORGetTeacher WHERE json_extract(user, '$.user.sourcedId') = ?"#
+            ),
+            Record::Term => json!(
+                r#"SELECT academicSession AS "academic_session?: String" FROM VwORGetTerm WHERE json_extract(academicSession, '$.academicSession.sourcedId') = ?"#
+            ),
+            Record::User => json!(
+                r#"SELECT user AS "user?: String" FROM VwORGetUser WHERE json_extract(user, '$.user.sourcedId') = ?"#
+            ),
+        })
+    }
+
+    async fn get_for_school(&self, view: ForSchool, school: &str) -> Result<Option<String>> {
+        let mut db = self.read.acquire().await?;
+        macro_rules! json {
+            ($query:literal) => {
+                sqlx::query_scalar!($query, school)
+                    .fetch_optional(&mut *db)
+                    .await?
+                    .flatten()
+            };
+        }
+        self.fields.open(match view {
+            ForSchool::Classes => json!(
+                r#"SELECT json_object('classes', json_group_array(json(class))) AS "classes?: String"
+                FROM ClassesJson
+                WHERE json_extract(class, '$.school.sourcedId') = ?"#
+            ),
+            ForSchool::Students => json!(
+                r#"
+                SELECT json_object('users', json_group_array(json(user))) AS "users?: String"
+                FROM UsersJson, json_each(json_extract(user, '$.orgs'))
+                WHERE json_extract(json_each.value, '$.sourcedId') = ?
+                    AND json_extract(user, '$.role') = "student"
+                "#
+            ),
+            ForSchool::Teachers => json!(
+                r#"
+                SELECT json_object('users', json_group_array(json(user))) AS "users?: String"
+                FROM UsersJson, json_each(json_extract(user, '$.orgs'))
+                WHERE json_extract(json_each.value, '$.sourcedId') = ?
+                    AND json_extract(user, '$.role') = "teacher"
+                "#
+            ),
+            ForSchool::Enrollments => json!(
+                r#"
+                SELECT json_object('enrollments', json_group_array(json(enrollment))) AS "enrollments?: String"
+                FROM EnrollmentsJson
+                WHERE json_extract(enrollment, '$.school.sourcedId') = ?
+                "#
+            ),
+        })
+    }
+
+    async fn search(
+        &self,
+        entity: Entity,
+        trigrams: &[String],
+        prefixes: &[String],
+    ) -> Result<Vec<SearchHit>> {
+        let (index, records) = match entity {
+            Entity::Users => (
+                "UsersSearch",
+                "SELECT u.sourcedId AS sourced_id
+                    , s.name || ' ' || s.username || ' ' || coalesce(s.email, '') || ' ' || coalesce(s.identifiers, '') AS text
+                FROM UsersSearch s
+                INNER JOIN Users u ON u.id = s.rowid",
+            ),
+            Entity::Classes => (
+                "ClassesSearch",
+                "SELECT c.sourcedId AS sourced_id, s.title || ' ' || coalesce(s.classCode, '') AS text
+                FROM ClassesSearch s
+                INNER JOIN Classes c ON c.id = s.rowid",
+            ),
+            Entity::Orgs => (
+                "OrgsSearch",
+                "SELECT sourcedId AS sourced_id, name || ' ' || coalesce(identifier, '') AS text
+                FROM OrgsSearch",
+            ),
+            _ => return Err(ServerError::InvalidParameters),
+        };
+        // the index finds the records holding any of the trigrams,
+        // words too short to have one are looked for in every record
+        let matching = match trigrams.is_empty() {
+            true => String::new(),
+            false => format!("WHERE {} MATCH ?1", index),
+        };
+        let query = format!(
+            "SELECT sourced_id, text FROM (
+                SELECT r.*
+                    , (SELECT count(*) FROM json_each(?2) t WHERE instr(lower(r.text), t.value) > 0)
+                    + (SELECT count(*) FROM json_each(?3) p WHERE ' ' || r.text LIKE '% ' || p.value || '%')
+                    AS matched
+                FROM ({} {}) AS r
+            )
+            WHERE matched > 0
+            ORDER BY matched DESC
+            LIMIT ?4",
+            records, matching
+        );
+        // trigrams hold letters and digits only so quoting them is enough
+        let terms = trigrams
+            .iter()
+            .map(|t| format!("\"{}\"", t))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+        let hits = sqlx::query_as(&query)
+            .bind(terms)
+            .bind(serde_json::to_string(trigrams)?)
+            .bind(serde_json::to_string(prefixes)?)
+            .bind(SEARCH_CANDIDATES)
+            .fetch_all(&mut *self.read.acquire().await?)
+            .await?;
+        Ok(hits
+            .into_iter()
+            .map(|(sourced_id, text)| SearchHit { sourced_id, text })
+            .collect())
+    }
+
+    async fn put_records(
+        &self,
+        entity: Entity,
+        ids: &str,
+        records: &[String],
+        principal: &Principal,
+    ) -> Result<()> {
+        let mut t = self.write.begin().await?;
+        let before = snapshot(entity, ids, &mut t).await?;
+        for json in records {
+            upsert(entity, &self.fields.seal(entity, json)?, &mut t).await?;
+        }
+        let after = snapshot(entity, ids, &mut t).await?;
+        // dropping the transaction rolls the write back
+        principal.permits_put(entity.name(), &before, &after)?;
+        record_changes(entity, &principal.client_id, &before, &after, &mut t).await?;
+        t.commit().await?;
+        Ok(())
+    }
+
+    async fn get_passwords(&self, ids: Option<&str>) -> Result<HashMap<String, String>> {
+        let rows = sqlx::query!(
+            r#"SELECT sourcedId AS "sourced_id!: String", password AS "password!: String"
+            FROM Users
+            WHERE password IS NOT NULL
+                AND (? IS NULL OR sourcedId IN (SELECT value FROM json_each(?)))"#,
+            ids,
+            ids,
+        )
+        .fetch_all(&mut *self.read.acquire().await?)
+        .await?;
+        rows.into_iter()
+            .map(|r| Ok((r.sourced_id, self.fields.open_field(r.password)?)))
+            .collect()
+    }
+
+    async fn set_password_hashes(&self, hashes: &[PasswordHash]) -> Result<u64> {
+        let mut t = self.write.begin().await?;
+        let ids = serde_json::to_string(&hashes.iter().map(|h| &h.sourced_id).collect::<Vec<_>>())?;
+        let before = snapshot(Entity::Users, &ids, &mut t).await?;
+        let mut updated = Vec::new();
+        for h in hashes {
+            let hash = self.fields.seal_field(&h.hash)?;
+            let plaintext = self.fields.seal_field(&h.plaintext)?;
+            let replaced = sqlx::query!(
+                "UPDATE Users SET password = ? WHERE sourcedId = ? AND password = ?",
+                hash,
+                h.sourced_id,
+                plaintext,
+            )
+            .execute(&mut t)
+            .await?
+            .rows_affected();
+            if replaced > 0 {
+                updated.push(h.sourced_id.as_str());
+            }
+        }
+        // the change log versions GET responses, so the new hashes are recorded like any write
+        let before = without_passwords(before, &updated)?;
+        let after = snapshot(Entity::Users, &serde_json::to_string(&updated)?, &mut t).await?;
+        record_changes(
+            Entity::Users,
+            PASSWORD_UPGRADE_CLIENT_ID,
+            &before,
+            &after,
+            &mut t,
+        )
+        .await?;
+        t.commit().await?;
+        Ok(updated.len() as u64)
+    }
+
+    async fn get_api_creds(&self, client_id: &str) -> Result<ApiCreds> {
+        let res = sqlx::query_as!(
+            ApiCreds,
+            r#"
+            SELECT
+                c.client_secret
+                , coalesce(group_concat(s.scope,' '), '') AS "scope!: String"
+                , c.disabled AS "disabled: bool"
+                , c.expires AS "expires?: DateTime<Utc>"
+            FROM
+                credentials c
+                LEFT JOIN credential_scopes cs ON c.id = cs.credential_id
+                LEFT JOIN scopes s ON cs.scope_id = s.id
+            WHERE
+                c.client_id = ?
+            GROUP BY
+                c.client_id
+            "#,
+            client_id
+        )
+        .fetch_optional(&mut *self.read.acquire().await?)
+        .await?;
+        if let Some(user) = res {
+            return Ok(user);
+        }
+        Err(ServerError::InvalidLogin)
+    }
+
+    async fn get_api_users(&self) -> Result<Vec<UserList>> {
+        let rows = sqlx::query_as!(
+            UserList,
+            r#"
+            SELECT
+                c.client_id
+                , c.tag
+                , coalesce(group_concat(s.scope,' '), '') AS "scope!: String"
+                , coalesce((
+                    SELECT group_concat(org_sourced_id, ' ') FROM credential_orgs WHERE credential_id = c.id
+                ), '') AS "orgs!: String"
+                , coalesce((
+                    SELECT group_concat(value, ' ') FROM credential_certs
+                    WHERE credential_id = c.id AND kind = 'fingerprint'
+                ), '') AS "cert_fingerprints!: String"
+                , coalesce((
+                    SELECT group_concat(value, '; ') FROM credential_certs
+                    WHERE credential_id = c.id AND kind = 'subject'
+                ), '') AS "cert_subjects!: String"
+                , (SELECT name FROM redaction_profiles WHERE id = c.redaction_profile_id) AS "redaction?: String"
+                , c.disabled AS "disabled: bool"
+                , c.expires AS "expires?: DateTime<Utc>"
+                , c.last_used AS "last_used?: DateTime<Utc>"
+            FROM
+                credentials c
+                LEFT JOIN credential_scopes cs ON c.id = cs.credential_id
+                LEFT JOIN scopes s ON cs.scope_id = s.id
+            GROUP BY
+                c.client_id
+            "#,
+        )
+        .fetch_all(&mut *self.read.acquire().await?)
+        .await?;
+
+        Ok(rows)
+    }
+
+    async fn get_api_user(&self, uuid: &str) -> Result<UserList> {
+        let row = sqlx::query_as!(
+            UserList,
+            r#"
+            SELECT
+                c.client_id
+                , c.tag
+                , coalesce(group_concat(s.scope,' '), '') AS "scope!: String"
+                , coalesce((
+                    SELECT group_concat(org_sourced_id, ' ') FROM credential_orgs WHERE credential_id = c.id
+                ), '') AS "orgs!: String"
+                , coalesce((
+                    SELECT group_concat(value, ' ') FROM credential_certs
+                    WHERE credential_id = c.id AND kind = 'fingerprint'
+                ), '') AS "cert_fingerprints!: String"
+                , coalesce((
+                    SELECT group_concat(value, '; ') FROM credential_certs
+                    WHERE credential_id = c.id AND kind = 'subject'
+                ), '') AS "cert_subjects!: String"
+                , (SELECT name FROM redaction_profiles WHERE id = c.redaction_profile_id) AS "redaction?: String"
+                , c.disabled AS "disabled: bool"
+                , c.expires AS "expires?: DateTime<Utc>"
+                , c.last_used AS "last_used?: DateTime<Utc>"
+            FROM
+                credentials c
+                LEFT JOIN credential_scopes cs ON c.id = cs.credential_id
+                LEFT JOIN scopes s ON cs.scope_id = s.id
+            WHERE
+                c.client_id = ?
+            GROUP BY
+                c.client_id
+            "#,
+            uuid
+        )
+        .fetch_optional(&mut *self.read.acquire().await?)
+        .await?;
+        row.ok_or(ServerError::UnknownObject)
+    }
+
+    async fn insert_api_user(
+        &self,
+        client_id: &str,
+        secret: &str,
+        user: &CreateApiUser,
+        scopes: &Scopes,
+    ) -> Result<()> {
+        let mut t = self.write.begin().await?;
+        sqlx::query!(
+            "INSERT INTO credentials(client_id, client_secret, tag) VALUES (?, ?, ?)",
+            client_id,
+            secret,
+            user.tag,
+        )
+        .execute(&mut t)
+        .await?;
+        for scope in scopes.iter().map(|s| s.as_str()) {
+            add_api_user_scope(client_id, scope, &mut t).await?;
+        }
+        set_api_user_orgs(client_id, &user.orgs, &mut t).await?;
+        set_api_user_certs(
+            client_id,
+            CertKind::Fingerprint,
+            &user.cert_fingerprints,
+            &mut t,
+        )
+        .await?;
+        set_api_user_certs(client_id, CertKind::Subject, &user.cert_subjects, &mut t).await?;
+        set_api_user_redaction(client_id, user.redaction.as_deref(), &mut t).await?;
+        t.commit().await?;
+        Ok(())
+    }
+
+    async fn modify_api_user(
+        &self,
+        uuid: &str,
+        update: &UpdateApiUser,
+        add: &Scopes,
+        remove: &Scopes,
+    ) -> Result<()> {
+        let mut t = self.write.begin().await?;
+        let found = sqlx::query!("SELECT id FROM credentials WHERE client_id = ?", uuid)
+            .fetch_optional(&mut t)
+            .await?;
+        if found.is_none() {
+            return Err(ServerError::UnknownObject);
+        }
+        for scope in add.iter().map(|s| s.as_str()) {
+            add_api_user_scope(uuid, scope, &mut t).await?;
+        }
+        for scope in remove.iter().map(|s| s.as_str()) {
+            sqlx::query!(
+                "DELETE FROM credential_scopes
+                WHERE credential_id = (SELECT id FROM credentials WHERE client_id = ?)
+                    AND scope_id = (SELECT id FROM scopes WHERE scope = ?)",
+                uuid,
+                scope,
+            )
+            .execute(&mut t)
+            .await?;
+        }
+        if let Some(orgs) = &update.orgs {
+            set_api_user_orgs(uuid, orgs, &mut t).await?;
+        }
+        if let Some(fingerprints) = &update.cert_fingerprints {
+            set_api_user_certs(uuid, CertKind::Fingerprint, fingerprints, &mut t).await?;
+        }
+        if let Some(subjects) = &update.cert_subjects {
+            set_api_user_certs(uuid, CertKind::Subject, subjects, &mut t).await?;
+        }
+        if let Some(redaction) = &update.redaction {
+            set_api_user_redaction(uuid, redaction.as_deref(), &mut t).await?;
+        }
+        if let Some(disabled) = update.disabled {
+            sqlx::query!(
+                "UPDATE credentials SET disabled = ? WHERE client_id = ?",
+                disabled,
+                uuid
+            )
+            .execute(&mut t)
+            .await?;
+        }
+        if let Some(expires) = update.expires {
+            let expires = expires.map(|e| e.to_rfc3339());
+            sqlx::query!(
+                "UPDATE credentials SET expires = ? WHERE client_id = ?",
+                expires,
+                uuid
+            )
+            .execute(&mut t)
+            .await?;
+        }
+        t.commit().await?;
+        Ok(())
+    }
+
+    async fn set_api_secret(&self, uuid: &str, secret: &str) -> Result<()> {
+        let updated = sqlx::query!(
+            "UPDATE credentials SET client_secret = ? WHERE client_id = ?",
+            secret,
+            uuid
+        )
+        .execute(&mut *self.write.acquire().await?)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Err(ServerError::UnknownObject);
+        }
+        Ok(())
+    }
+
+    async fn touch_api_user(&self, client_id: &str) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+        sqlx::query!(
+            "UPDATE credentials SET last_used = ? WHERE client_id = ?",
+            now,
+            client_id
+        )
+        .execute(&mut *self.write.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_api_user(&self, uuid: &str) -> Result<()> {
+        let deleted = sqlx::query!("DELETE FROM credentials WHERE client_id = ?", uuid)
+            .execute(&mut *self.write.acquire().await?)
+            .await?
+            .rows_affected();
+
+        if deleted > 0 {
+            return Ok(());
+        }
+        Err(ServerError::NoRecordDeleted)
+    }
+
+    async fn get_cert_client_id(&self, cert: &tls::ClientCert) -> Result<Option<String>> {
+        let row = sqlx::query!(
+            "SELECT c.client_id FROM credential_certs cc
+            INNER JOIN credentials c ON c.id = cc.credential_id
+            WHERE (cc.kind = 'fingerprint' AND cc.value = ?)
+                OR (cc.kind = 'subject' AND cc.value = ?)
+            ORDER BY cc.kind
+            LIMIT 1",
+            cert.fingerprint,
+            cert.subject
+        )
+        .fetch_optional(&mut *self.read.acquire().await?)
+        .await?;
+        Ok(row.map(|r| r.client_id))
+    }
+
+    async fn get_org_scope(&self, client_id: &str) -> Result<Option<OrgScope>> {
+        let mut db = self.read.acquire().await?;
+        let bound = sqlx::query!(
+            r#"SELECT count(*) AS "count!: i64" FROM credential_orgs co
+            INNER JOIN credentials c ON c.id = co.credential_id
+            WHERE c.client_id = ?"#,
+            client_id
+        )
+        .fetch_one(&mut *db)
+        .await?;
+        if bound.count == 0 {
+            return Ok(None);
+        }
+        // runtime checked as the query macros cannot describe recursive CTEs
+        let orgs: Vec<String> = sqlx::query_scalar(
+            r#"
+            WITH RECURSIVE scoped(sourcedId) AS (
+                SELECT co.org_sourced_id FROM credential_orgs co
+                INNER JOIN credentials c ON c.id = co.credential_id
+                WHERE c.client_id = ?
+                UNION
+                SELECT Orgs.sourcedId FROM Orgs
+                INNER JOIN scoped ON Orgs.parentSourcedId = scoped.sourcedId
+            )
+            SELECT sourcedId FROM scoped
+            "#,
+        )
+        .bind(client_id)
+        .fetch_all(&mut *db)
+        .await?;
+        let sessions: Vec<String> = sqlx::query_scalar(
+            r#"
+            WITH RECURSIVE scoped(sourcedId) AS (
+                SELECT co.org_sourced_id FROM credential_orgs co
+                INNER JOIN credentials c ON c.id = co.credential_id
+                WHERE c.client_id = ?
+                UNION
+                SELECT Orgs.sourcedId FROM Orgs
+                INNER JOIN scoped ON Orgs.parentSourcedId = scoped.sourcedId
+            ), sessions(sourcedId) AS (
+                SELECT cas.academicSessionSourcedId FROM ClassAcademicSessions cas
+                INNER JOIN Classes ON Classes.sourcedId = cas.classSourcedId
+                WHERE Classes.orgSourcedId IN scoped
+                UNION
+                SELECT schoolYearSourcedId FROM Courses
+                WHERE orgSourcedId IN scoped AND schoolYearSourcedId IS NOT NULL
+                UNION
+                SELECT a.parentSourcedId FROM AcademicSessions a
+                INNER JOIN sessions ON a.sourcedId = sessions.sourcedId
+                WHERE a.parentSourcedId IS NOT NULL
+            )
+            SELECT sourcedId FROM sessions
+            "#,
+        )
+        .bind(client_id)
+        .fetch_all(&mut *db)
+        .await?;
+        Ok(Some(OrgScope {
+            orgs: orgs.into_iter().collect(),
+            sessions: sessions.into_iter().collect(),
+        }))
+    }
+
+    async fn get_redaction(&self, client_id: &str) -> Result<Option<Redaction>> {
+        let rules = sqlx::query_as!(
+            RedactionRule,
+            r#"
+            SELECT
+                f.entity
+                , f.field
+                , f.action AS "action: RedactionAction"
+            FROM
+                redaction_profile_fields f
+                INNER JOIN credentials c ON c.redaction_profile_id = f.profile_id
+            WHERE
+                c.client_id = ?
+            "#,
+            client_id
+        )
+        .fetch_all(&mut *self.read.acquire().await?)
+        .await?;
+        if rules.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Redaction { rules }))
+    }
+
+    async fn get_redaction_profiles(&self) -> Result<Vec<RedactionProfile>> {
+        let mut db = self.read.acquire().await?;
+        let names = sqlx::query!("SELECT name FROM redaction_profiles ORDER BY name")
+            .fetch_all(&mut *db)
+            .await?;
+        let mut profiles = Vec::new();
+        for row in names {
+            let fields = sqlx::query_as!(
+                RedactionRule,
+                r#"
+                SELECT
+                    f.entity
+                    , f.field
+                    , f.action AS "action: RedactionAction"
+                FROM
+                    redaction_profile_fields f
+                    INNER JOIN redaction_profiles p ON p.id = f.profile_id
+                WHERE
+                    p.name = ?
+                ORDER BY
+                    f.entity, f.field
+                "#,
+                row.name
+            )
+            .fetch_all(&mut *db)
+            .await?;
+            profiles.push(RedactionProfile {
+                name: row.name,
+                fields,
+            });
+        }
+        Ok(profiles)
+    }
+
+    async fn put_redaction_profile(&self, profile: RedactionProfile) -> Result<()> {
+        let mut t = self.write.begin().await?;
+        sqlx::query!(
+            "INSERT OR IGNORE INTO redaction_profiles (name) VALUES (?)",
+            profile.name
+        )
+        .execute(&mut t)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM redaction_profile_fields
+            WHERE profile_id = (SELECT id FROM redaction_profiles WHERE name = ?)",
+            profile.name
+        )
+        .execute(&mut t)
+        .await?;
+        for rule in profile.fields {
+            sqlx::query!(
+                "INSERT OR REPLACE INTO redaction_profile_fields (profile_id, entity, field, action)
+                SELECT id, ?, ?, ? FROM redaction_profiles WHERE name = ?",
+                rule.entity,
+                rule.field,
+                rule.action,
+                profile.name
+            )
+            .execute(&mut t)
+            .await?;
+        }
+        t.commit().await?;
+        Ok(())
+    }
+
+    async fn delete_redaction_profile(&self, name: &str) -> Result<()> {
+        let mut t = self.write.begin().await?;
+        // cleared explicitly as foreign keys may not be enforced
+        sqlx::query!(
+            "UPDATE credentials SET redaction_profile_id = NULL
+            WHERE redaction_profile_id = (SELECT id FROM redaction_profiles WHERE name = ?)",
+            name
+        )
+        .execute(&mut t)
+        .await?;
+        sqlx::query!(
+            "DELETE FROM redaction_profile_fields
+            WHERE profile_id = (SELECT id FROM redaction_profiles WHERE name = ?)",
+            name
+        )
+        .execute(&mut t)
+        .await?;
+        let deleted = sqlx::query!("DELETE FROM redaction_profiles WHERE name = ?", name)
+            .execute(&mut t)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            return Err(ServerError::NoRecordDeleted);
+        }
+        t.commit().await?;
+        Ok(())
+    }
+
+    async fn record_request(&self, entry: &AuditRequest) -> Result<()> {
+        let timestamp = entry.timestamp.to_rfc3339();
+        sqlx::query!(
+            "INSERT INTO audit_requests (timestamp, client_id, ip, method, route, query, status, size)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            timestamp,
+            entry.client_id,
+            entry.ip,
+            entry.method,
+            entry.route,
+            entry.query,
+            entry.status,
+            entry.size,
+        )
+        .execute(&mut *self.write.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn get_audit_requests(&self, filter: &AuditFilter) -> Result<Vec<AuditRequest>> {
+        let since = filter.since.map(|s| s.to_rfc3339());
+        let until = filter.until.map(|u| u.to_rfc3339());
+        let rows = sqlx::query_as!(
+            AuditRequest,
+            r#"
+            SELECT
+                timestamp AS "timestamp: DateTime<Utc>"
+                , client_id
+                , ip
+                , method
+                , route
+                , query
+                , status
+                , size
+            FROM
+                audit_requests
+            WHERE
+                (?1 IS NULL OR client_id = ?1)
+                AND (?2 IS NULL OR route LIKE ?2 || '%')
+                AND (?3 IS NULL OR timestamp >= ?3)
+                AND (?4 IS NULL OR timestamp <= ?4)
+            ORDER BY
+                id DESC
+            LIMIT ?5
+            "#,
+            filter.client_id,
+            filter.route,
+            since,
+            until,
+            filter.limit,
+        )
+        .fetch_all(&mut *self.read.acquire().await?)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn get_audit_changes(&self, filter: &AuditFilter) -> Result<Vec<AuditChange>> {
+        let since = filter.since.map(|s| s.to_rfc3339());
+        let until = filter.until.map(|u| u.to_rfc3339());
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                timestamp AS "timestamp: DateTime<Utc>"
+                , client_id
+                , entity
+                , sourced_id
+                , operation
+                , before
+                , after
+            FROM
+                audit_changes
+            WHERE
+                (?1 IS NULL OR client_id = ?1)
+                AND (?2 IS NULL OR entity = ?2)
+                AND (?3 IS NULL OR sourced_id = ?3)
+                AND (?4 IS NULL OR timestamp >= ?4)
+                AND (?5 IS NULL OR timestamp <= ?5)
+            ORDER BY
+                id DESC
+            LIMIT ?6
+            "#,
+            filter.client_id,
+            filter.entity,
+            filter.sourced_id,
+            since,
+            until,
+            filter.limit,
+        )
+        .fetch_all(&mut *self.read.acquire().await?)
+        .await?;
+        let mut changes = Vec::new();
+        for r in rows {
+            changes.push(AuditChange {
+                timestamp: r.timestamp,
+                client_id: r.client_id,
+                entity: r.entity,
+                sourced_id: r.sourced_id,
+                operation: r.operation,
+                before: r.before.map(|b| self.fields.parse(&b)).transpose()?,
+                after: r.after.map(|a| self.fields.parse(&a)).transpose()?,
+            });
+        }
+        Ok(changes)
+    }
+
+    async fn get_changes(&self, filter: &ChangeFilter) -> Result<Vec<RecordChange>> {
+        let since = filter.since.map(|s| s.to_rfc3339());
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                id AS "id!: i64"
+                , timestamp AS "timestamp: DateTime<Utc>"
+                , entity
+                , sourced_id
+                , operation
+                , before
+                , after
+            FROM
+                change_log
+            WHERE
+                (?1 IS NULL OR timestamp > ?1)
+                AND (?2 IS NULL OR id > ?2)
+                AND (?3 IS NULL OR entity = ?3)
+            ORDER BY
+                id
+            LIMIT ?4
+            "#,
+            since,
+            filter.after,
+            filter.entity,
+            filter.limit,
+        )
+        .fetch_all(&mut *self.read.acquire().await?)
+        .await?;
+        let mut changes = Vec::new();
+        for r in rows {
+            changes.push(RecordChange {
+                id: r.id,
+                timestamp: r.timestamp,
+                entity: r.entity,
+                sourced_id: r.sourced_id,
+                operation: r.operation,
+                before: r.before.map(|b| self.fields.parse(&b)).transpose()?,
+                after: r.after.map(|a| self.fields.parse(&a)).transpose()?,
+            });
+        }
+        Ok(changes)
+    }
+
+    async fn last_change(&self) -> Result<Option<(i64, DateTime<Utc>)>> {
+        let row = sqlx::query!(
+            r#"SELECT id AS "id!: i64", timestamp AS "timestamp!: DateTime<Utc>"
+            FROM change_log ORDER BY id DESC LIMIT 1"#
+        )
+        .fetch_optional(&mut *self.read.acquire().await?)
+        .await?;
+        Ok(row.map(|r| (r.id, r.timestamp)))
+    }
+
+    async fn purge_audit(&self, before: DateTime<Utc>) -> Result<u64> {
+        let before = before.to_rfc3339();
+        let mut t = self.write.begin().await?;
+        let requests = sqlx::query!("DELETE FROM audit_requests WHERE timestamp < ?", before)
+            .execute(&mut t)
+            .await?
+            .rows_affected();
+        let changes = sqlx::query!("DELETE FROM audit_changes WHERE timestamp < ?", before)
+            .execute(&mut t)
+            .await?
+            .rows_affected();
+        t.commit().await?;
+        Ok(requests + changes)
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64> {
+        let cutoff = modified_timestamp(before);
+        let mut t = self.write.begin().await?;
+        let mut purged = 0;
+        for entity in PURGE_ORDER {
+            let tables = entity.tables();
+            let purgeable = tables.purgeable("?");
+            let deletes = tables.deletes("SELECT value FROM json_each(?)");
+            // repeated as purging an org or session can free its tobedeleted parent
+            loop {
+                let ids: Vec<String> = sqlx::query_scalar(&purgeable)
+                    .bind(&cutoff)
+                    .fetch_all(&mut t)
+                    .await?;
+                if ids.is_empty() {
+                    break;
+                }
+                purged += ids.len() as u64;
+                let ids = serde_json::to_string(&ids)?;
+                let before = snapshot(entity, &ids, &mut t).await?;
+                for delete in &deletes {
+                    sqlx::query(delete).bind(&ids).execute(&mut t).await?;
+                }
+                record_changes(entity, PURGE_CLIENT_ID, &before, &HashMap::new(), &mut t).await?;
+            }
+        }
+        t.commit().await?;
+        Ok(purged)
+    }
+
+    async fn check_references(&self, mark: Option<&str>) -> Result<Vec<Orphan>> {
+        match mark {
+            Some(client_id) => {
+                // taken out of the pool so it is closed rather than handed back with foreign keys
+                // off, whether marking finishes or not. Writes meanwhile open a new connection and
+                // wait on the file lock
+                let mut conn = self.write.acquire().await?.release();
+                sqlx::query("PRAGMA foreign_keys = OFF")
+                    .execute(&mut conn)
+                    .await?;
+                let marked = mark_orphans(&mut conn, client_id).await;
+                conn.close().await?;
+                marked
+            }
+            None => orphans(&mut *self.read.acquire().await?).await,
+        }
+    }
+
+    async fn get_tenants(&self) -> Result<Vec<Tenant>> {
+        sqlx::query_as!(
+            Tenant,
+            r#"SELECT name AS "name!", hostname, database, private_key, public_key, created AS "created: DateTime<Utc>"
+            FROM tenants ORDER BY name"#
+        )
+        .fetch_all(&mut *self.read.acquire().await?)
+        .await?
+        .into_iter()
+        .map(|tenant| {
+            Ok(Tenant {
+                private_key: self.fields.open_field(tenant.private_key)?,
+                ..tenant
+            })
+        })
+        .collect()
+    }
+
+    async fn insert_tenant(&self, tenant: &Tenant) -> Result<()> {
+        let created = tenant.created.to_rfc3339();
+        let private_key = self.fields.seal_field(&tenant.private_key)?;
+        sqlx::query!(
+            "INSERT INTO tenants (name, hostname, database, private_key, public_key, created)
+            VALUES (?, ?, ?, ?, ?, ?)",
+            tenant.name,
+            tenant.hostname,
+            tenant.database,
+            private_key,
+            tenant.public_key,
+            created
+        )
+        .execute(&mut *self.write.acquire().await?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_tenant(&self, name: &str) -> Result<()> {
+        let deleted = sqlx::query!("DELETE FROM tenants WHERE name = ?", name)
+            .execute(&mut *self.write.acquire().await?)
+            .await?
+            .rows_affected();
+        if deleted > 0 {
+            return Ok(());
+        }
+        Err(ServerError::NoRecordDeleted)
+    }
+
+    async fn rekey(&self, key: &MasterKey) -> Result<u64> {
+        let mut t = self.write.begin().await?;
+        let wrapped = key.new_data_key()?;
+        let created = Utc::now().to_rfc3339();
+        let key_id = sqlx::query!(
+            "INSERT INTO data_keys (wrapped_key, created) VALUES (?, ?)",
+            wrapped,
+            created,
+        )
+        .execute(&mut t)
+        .await?
+        .last_insert_rowid();
+        let to = Fields::unlock(
+            Some(key),
+            vec![DataKey {
+                id: key_id,
+                wrapped,
+            }],
+        )?;
+        let mut count = 0;
+        let users = sqlx::query!("SELECT id, email, sms, phone, password FROM Users")
+            .fetch_all(&mut t)
+            .await?;
+        for r in users {
+            let email = self.fields.reseal(&to, r.email)?;
+            let sms = self.fields.reseal(&to, r.sms)?;
+            let phone = self.fields.reseal(&to, r.phone)?;
+            let password = self.fields.reseal(&to, r.password)?;
+            sqlx::query!(
+                "UPDATE Users SET email = ?, sms = ?, phone = ?, password = ? WHERE id = ?",
+                email,
+                sms,
+                phone,
+                password,
+                r.id,
+            )
+            .execute(&mut t)
+            .await?;
+            count += 1;
+        }
+        let ids = sqlx::query!("SELECT id, identifier FROM UserIds")
+            .fetch_all(&mut t)
+            .await?;
+        for r in ids {
+            let identifier = self.fields.reseal(&to, Some(r.identifier))?;
+            sqlx::query!(
+                "UPDATE UserIds SET identifier = ? WHERE id = ?",
+                identifier,
+                r.id
+            )
+            .execute(&mut t)
+            .await?;
+            count += 1;
+        }
+        // earlier versions of users kept in the history
+        for table in ["change_log", "audit_changes"].iter() {
+            let select = format!("SELECT id, before, after FROM {} WHERE entity = ?", table);
+            let update = format!("UPDATE {} SET before = ?, after = ? WHERE id = ?", table);
+            let rows: Vec<(i64, Option<String>, Option<String>)> = sqlx::query_as(&select)
+                .bind(Entity::Users.name())
+                .fetch_all(&mut t)
+                .await?;
+            for (id, before, after) in rows {
+                let reseal = |json: Option<String>| {
+                    json.map(|j| self.fields.reseal_user(&to, &j)).transpose()
+                };
+                sqlx::query(&update)
+                    .bind(reseal(before)?)
+                    .bind(reseal(after)?)
+                    .bind(id)
+                    .execute(&mut t)
+                    .await?;
+                count += 1;
+            }
+        }
+        let tenants = sqlx::query!(r#"SELECT name AS "name!", private_key FROM tenants"#)
+            .fetch_all(&mut t)
+            .await?;
+        for r in tenants {
+            let private_key = self.fields.reseal(&to, Some(r.private_key))?;
+            sqlx::query!(
+                "UPDATE tenants SET private_key = ? WHERE name = ?",
+                private_key,
+                r.name
+            )
+            .execute(&mut t)
+            .await?;
+            count += 1;
+        }
+        sqlx::query!("DELETE FROM data_keys WHERE id <> ?", key_id)
+            .execute(&mut t)
+            .await?;
+        t.commit().await?;
+        Ok(count)
+    }
+
+    async fn backup(&self, path: &str) -> Result<()> {
+        // VACUUM INTO reads a single snapshot so the writer carries on while it runs
+        sqlx::query("VACUUM INTO ?")
+            .bind(path)
+            .execute(&mut *self.read.acquire().await?)
+            .await?;
+        Ok(())
+    }
+
+    async fn schema_version(&self) -> Result<(i64, i64)> {
+        let version: Option<i64> = sqlx::query_scalar("SELECT max(version) FROM schema_version")
+            .fetch_one(&mut *self.read.acquire().await?)
+            .await?;
+        Ok((version.unwrap_or(0), migrate::latest(&AnyKind::Sqlite)))
+    }
+
+    fn check_keys(&self) -> Result<()> {
+        self.fields.check_unlocked()
+    }
+
+    async fn record_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        let mut counts = Vec::new();
+        for entity in PURGE_ORDER {
+            let query = format!("SELECT count(*) FROM {}", entity.tables().table);
+            let count: i64 = sqlx::query_scalar(&query)
+                .fetch_one(&mut *self.read.acquire().await?)
+                .await?;
+            counts.push((entity.name(), count));
+        }
+        Ok(counts)
+    }
+
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        vec![self.read.stats(), self.write.stats()]
+    }
+}
+
+/// The query selecting the records of a collection view, one json object per row
+fn collection_query(view: Collection) -> &'static str {
+    match view {
+        Collection::AcademicSessions => "SELECT academicSession FROM AcademicSessionsJson",
+        Collection::Classes => "SELECT class FROM ClassesJson",
+        Collection::Courses => "SELECT course FROM CoursesJson",
+        Collection::Enrollments => "SELECT enrollment FROM EnrollmentsJson",
+        Collection::GradingPeriods => {
+            "SELECT academicSession FROM AcademicSessionsJson
+            WHERE json_extract(academicSession, '$.type') = 'gradingPeriod'"
+        }
+        Collection::Orgs => "SELECT org FROM OrgsJson",
+        Collection::Periods => "SELECT period FROM PeriodsJson",
+        Collection::Schools => {
+            "SELECT org FROM OrgsJson WHERE json_extract(org, '$.type') = 'school'"
+        }
+        Collection::Students => {
+            r#"SELECT "user" FROM UsersJson WHERE json_extract("user", '$.role') = 'student'"#
+        }
+        Collection::Subjects => "SELECT subject FROM SubjectsJson",
+        Collection::Teachers => {
+            r#"SELECT "user" FROM UsersJson WHERE json_extract("user", '$.role') = 'teacher'"#
+        }
+        Collection::Terms => {
+            "SELECT academicSession FROM AcademicSessionsJson
+            WHERE json_extract(academicSession, '$.type') = 'term'"
+        }
+        Collection::Users => r#"SELECT "user" FROM UsersJson"#,
+    }
+}
+
+/// A read transaction on one of the readers, in WAL mode it sees the database
+/// as it was at its first read however long it is held
+struct SqliteSnapshot {
+    t: sqlx::Transaction<'static, sqlx::Sqlite>,
+    fields: Arc<Fields>,
+}
+
+#[tide::utils::async_trait]
+impl Snapshot for SqliteSnapshot {
+    async fn count_collection(&mut self, view: Collection) -> Result<usize> {
+        let query = format!(
+            "SELECT count(*) FROM ({}) AS records",
+            collection_query(view)
+        );
+        let count: i64 = sqlx::query_scalar(&query).fetch_one(&mut self.t).await?;
+        Ok(count as usize)
+    }
+
+    fn stream_collection(&mut self, view: Collection) -> BoxStream<'_, Result<String>> {
+        let fields = &self.fields;
+        sqlx::query_scalar::<_, String>(collection_query(view))
+            .fetch(&mut self.t)
+            .map(move |row| Ok(fields.open(Some(row?))?.unwrap_or_default()))
+            .boxed()
+    }
+}
+
+/// The database file a sqlite: url points at
+pub(crate) fn file_path(url: &str) -> &str {
+    let path = url.trim_start_matches("sqlite:").trim_start_matches("//");
+    path.split('?').next().unwrap_or(path)
+}
+
+/// Copies a backup beside the database, verifies the copy and renames it into place
+pub(crate) async fn restore(url: &str, backup: &str) -> Result<()> {
+    let target = file_path(url);
+    let staged = format!("{}.restore", target);
+    async_std::fs::copy(backup, &staged).await?;
+    if let Err(e) = verify(&staged).await {
+        async_std::fs::remove_file(&staged).await?;
+        return Err(e);
+    }
+    // a leftover write-ahead log would be replayed over the restored database
+    for suffix in &["-wal", "-shm"] {
+        let path = format!("{}{}", target, suffix);
+        if async_std::path::Path::new(&path).exists().await {
+            async_std::fs::remove_file(&path).await?;
+        }
+    }
+    async_std::fs::rename(&staged, target).await?;
+    Ok(())
+}
+
+/// Runs SQLite's integrity check over a database file
+async fn integrity_check(path: &str) -> sqlx::Result<Vec<String>> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(SqliteConnectOptions::new().filename(path))
+        .await?;
+    let rows = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(&pool)
+        .await;
+    pool.close().await;
+    rows
+}
+
+/// Checks a database file is intact and holds a schema this release can migrate
+async fn verify(path: &str) -> Result<()> {
+    match integrity_check(path).await {
+        Ok(rows) if rows == ["ok"] => (),
+        Ok(rows) => return Err(ServerError::InvalidBackup(rows.join("; "))),
+        Err(e) => return Err(ServerError::InvalidBackup(e.to_string())),
+    }
+    let version = migrate::version(&format!("sqlite:{}", path)).await?;
+    if version == 0 {
+        return Err(ServerError::InvalidBackup(
+            "no oneroster schema found".to_string(),
+        ));
+    }
+    migrate::check_version(&AnyKind::Sqlite, version)
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn orphans_are_reported_and_marked() -> Result<()> {
+    use super::{provision, CHECK_CLIENT_ID};
+    use sqlx::ConnectOptions;
+    let dir = std::env::temp_dir().join(format!("oneroster-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir)?;
+    let url = super::database_url(&dir.join("check.db").to_string_lossy());
+    let config = PoolConfig::default();
+    provision(&url, &config, None).await?;
+    // a database written before foreign keys were enforced
+    let mut conn = SqliteConnectOptions::from_str(&url)?
+        .foreign_keys(false)
+        .connect()
+        .await?;
+    let mut t = conn.begin().await?;
+    let user = r#"{"sourcedId":"u1","status":"active","dateLastModified":"2020-01-01T00:00:00Z",
+        "username":"jon","enabledUser":1,"givenName":"Jon","familyName":"Smith","role":"student",
+        "orgs":[{"sourcedId":"gone"}]}"#;
+    let school = r#"{"sourcedId":"s1","status":"active","dateLastModified":"2020-01-01T00:00:00Z",
+        "name":"School","type":"school","parent":{"sourcedId":"d1"}}"#;
+    upsert(Entity::Users, user, &mut t).await?;
+    upsert(Entity::Orgs, school, &mut t).await?;
+    t.commit().await?;
+    conn.close().await?;
+
+    let db = SqliteStorage::connect(&url, &config, None).await?;
+    let found = db.check_references(None).await?;
+    let mut missing: Vec<_> = found
+        .iter()
+        .map(|o| (o.sourced_id.as_str(), o.references, o.missing.as_str()))
+        .collect();
+    missing.sort_unstable();
+    assert_eq!(missing, [("s1", "org", "d1"), ("u1", "org", "gone")]);
+    assert!(found.iter().all(|o| o.status == "active"));
+    let marked = db.check_references(Some(CHECK_CLIENT_ID)).await?;
+    assert_eq!(marked.len(), 2);
+    assert!(marked.iter().all(|o| o.status == "tobedeleted"));
+    // the pool's writer still refuses dangling references
+    let enforced: i64 = sqlx::query_scalar("PRAGMA foreign_keys")
+        .fetch_one(&mut *db.write.acquire().await?)
+        .await?;
+    assert_eq!(enforced, 1);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -11,6 +11,7 @@ pub enum ServerError {
     Jwt(jsonwebtoken::errors::Error),
     Regex(regex::Error),
     Json(serde_json::Error),
+    Csv(csv::Error),
     Io(std::io::Error),
     OpenSsl(openssl::error::ErrorStack),
     InvalidLogin,
@@ -23,6 +24,20 @@ pub enum ServerError {
     InvalidParameters,
     InvalidBlankSelectionField,
     NoDatabaseFound,
+    InvalidScope(String),
+    UnknownObject,
+    CredentialDisabled,
+    CredentialExpired,
+    TooManyRequests(u64),
+    UnknownClientCertificate,
+    ClientCertificateMismatch,
+    UnsupportedKey(String),
+    SchemaTooNew(i64, i64),
+    InvalidBackup(String),
+    CommandFailed(String),
+    InvalidTenant(String),
+    InvalidSubject(String),
+    Encryption(String),
 }
 
 impl fmt::Display for ServerError {
@@ -34,6 +49,7 @@ impl fmt::Display for ServerError {
             ServerError::Jwt(ref e) => e.fmt(f),
             ServerError::Regex(ref e) => e.fmt(f),
             ServerError::Json(ref e) => e.fmt(f),
+            ServerError::Csv(ref e) => e.fmt(f),
             ServerError::Io(ref e) => e.fmt(f),
             ServerError::OpenSsl(ref e) => e.fmt(f),
             ServerError::InvalidLogin => write!(f, "Invalid username/password"),
@@ -48,6 +64,30 @@ impl fmt::Display for ServerError {
             ServerError::NoDatabaseFound => {
                 write!(f, "No database found, check path or use --init to create")
             }
+            ServerError::InvalidScope(ref s) => write!(f, "Unknown scope: {}", s),
+            ServerError::UnknownObject => write!(f, "No record found"),
+            ServerError::CredentialDisabled => write!(f, "Credential is disabled"),
+            ServerError::CredentialExpired => write!(f, "Credential has expired"),
+            ServerError::UnknownClientCertificate => {
+                write!(f, "Client certificate is not bound to a credential")
+            }
+            ServerError::ClientCertificateMismatch => {
+                write!(f, "Client certificate does not match the bearer token")
+            }
+            ServerError::UnsupportedKey(ref s) => write!(f, "Unsupported JWT key: {}", s),
+            ServerError::SchemaTooNew(db, known) => write!(
+                f,
+                "Database schema version {} is newer than this release supports ({}), upgrade oneroster",
+                db, known
+            ),
+            ServerError::InvalidBackup(ref s) => write!(f, "Backup rejected: {}", s),
+            ServerError::CommandFailed(ref s) => write!(f, "External command failed: {}", s),
+            ServerError::InvalidTenant(ref s) => write!(f, "Tenant rejected: {}", s),
+            ServerError::InvalidSubject(ref s) => write!(f, "Invalid certificate subject: {}", s),
+            ServerError::Encryption(ref s) => write!(f, "Field encryption failed: {}", s),
+            ServerError::TooManyRequests(secs) => {
+                write!(f, "Too many requests, retry after {} seconds", secs)
+            }
         }
     }
 }
@@ -61,6 +101,7 @@ impl error::Error for ServerError {
             ServerError::Jwt(ref e) => Some(e),
             ServerError::Regex(ref e) => Some(e),
             ServerError::Json(ref e) => Some(e),
+            ServerError::Csv(ref e) => Some(e),
             ServerError::Io(ref e) => Some(e),
             ServerError::OpenSsl(ref e) => Some(e),
             _ => None,
@@ -86,6 +127,7 @@ into_error!(std::time::SystemTimeError, ServerError::Time);
 into_error!(jsonwebtoken::errors::Error, ServerError::Jwt);
 into_error!(regex::Error, ServerError::Regex);
 into_error!(serde_json::Error, ServerError::Json);
+into_error!(csv::Error, ServerError::Csv);
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
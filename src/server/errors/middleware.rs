@@ -25,7 +25,11 @@ impl tide::Middleware<server::State> for tide::utils::After<ApiError> {
                 ServerError::NoAuthorizedScopes
                 | ServerError::NoBearerToken
                 | ServerError::NoPermission
-                | ServerError::InvalidLogin => {
+                | ServerError::InvalidLogin
+                | ServerError::CredentialDisabled
+                | ServerError::CredentialExpired
+                | ServerError::UnknownClientCertificate
+                | ServerError::ClientCertificateMismatch => {
                     let ep = ErrorPayload {
                         code_major: CodeMajor::Failure,
                         code_minor: CodeMinor::Unauthorized,
@@ -45,7 +49,7 @@ impl tide::Middleware<server::State> for tide::utils::After<ApiError> {
                     r.set_status(403);
                     r.set_body(json!(ep));
                 }
-                ServerError::NoRecordDeleted => {
+                ServerError::NoRecordDeleted | ServerError::UnknownObject => {
                     let ep = ErrorPayload {
                         code_major: CodeMajor::Failure,
                         code_minor: CodeMinor::UnknownObject,
@@ -119,7 +123,10 @@ impl tide::Middleware<server::State> for tide::utils::After<ApiError> {
                 }
                 ServerError::InvalidFilterField
                 | ServerError::InvalidParameters
-                | ServerError::InvalidBlankSelectionField => {
+                | ServerError::InvalidBlankSelectionField
+                | ServerError::InvalidScope(_)
+                | ServerError::InvalidTenant(_)
+                | ServerError::InvalidSubject(_) => {
                     let ep = ErrorPayload {
                         code_major: CodeMajor::Failure,
                         code_minor: CodeMinor::InvalidData,
@@ -129,6 +136,18 @@ impl tide::Middleware<server::State> for tide::utils::After<ApiError> {
                     r.set_status(400);
                     r.set_body(json!(ep));
                 }
+                ServerError::TooManyRequests(secs) => {
+                    let retry_after = secs.to_string();
+                    let ep = ErrorPayload {
+                        code_major: CodeMajor::Failure,
+                        code_minor: CodeMinor::ServerBusy,
+                        description: Some(format!("{}", err)),
+                        severity: Severity::Error,
+                    };
+                    r.set_status(429);
+                    r.insert_header("retry-after", retry_after);
+                    r.set_body(json!(ep));
+                }
                 ServerError::NoContent => {
                     r.set_status(204);
                 }
@@ -0,0 +1,82 @@
+use crate::server::auth::principal::Principal;
+use crate::server::negotiate::Format;
+use crate::server::{Result, State};
+use chrono::{DateTime, Utc};
+use openssl::sha::Sha256;
+use tide::{Request, Response, ResponseBuilder, StatusCode};
+
+/// How HTTP dates are written, always in GMT
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// The ETag and Last-Modified of a GET response.
+/// Every write to a record adds to the change log so its newest entry versions the whole
+/// database, the ETag also covers the url, the format and what the credential is allowed to see
+pub(crate) struct Validators {
+    etag: String,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    pub(crate) async fn new(req: &Request<State>, principal: &Principal) -> Result<Self> {
+        let last = req.state().db.last_change().await?;
+        let mut hash = Sha256::new();
+        if let Some((id, at)) = last {
+            hash.update(format!("{}\n{}\n", id, at.to_rfc3339()).as_bytes());
+        }
+        hash.update(req.url().as_str().as_bytes());
+        hash.update(format!("{:?}", Format::from_request(req)).as_bytes());
+        hash.update(view(principal).as_bytes());
+        Ok(Self {
+            etag: format!("W/\"{}\"", hex::encode(&hash.finish()[..16])),
+            last_modified: last.map(|(_, at)| at),
+        })
+    }
+
+    /// A 304 response when the client already holds the current response.
+    /// If-Modified-Since is only looked at without If-None-Match, and `*` matches nothing as
+    /// this runs before the handler knows whether the url names anything
+    pub(crate) fn not_modified(&self, req: &Request<State>) -> Option<Response> {
+        let current = match req.header("If-None-Match") {
+            Some(tags) => tags
+                .iter()
+                .flat_map(|v| v.as_str().split(','))
+                .map(|t| t.trim().trim_start_matches("W/"))
+                .any(|t| t == self.etag.trim_start_matches("W/")),
+            None => match (req.header("If-Modified-Since"), self.last_modified) {
+                (Some(since), Some(modified)) => DateTime::parse_from_rfc2822(since.as_str())
+                    .map(|since| modified.timestamp() <= since.timestamp())
+                    .unwrap_or(false),
+                _ => false,
+            },
+        };
+        if !current {
+            return None;
+        }
+        let res = Response::builder(StatusCode::NotModified);
+        Some(self.headers(res).build())
+    }
+
+    pub(crate) fn headers(&self, res: ResponseBuilder) -> ResponseBuilder {
+        let res = res.header("etag", self.etag.as_str());
+        match self.last_modified {
+            Some(at) => res.header("last-modified", at.format(HTTP_DATE).to_string()),
+            None => res,
+        }
+    }
+}
+
+/// The orgs and redaction a response was built for, in a stable order
+fn view(principal: &Principal) -> String {
+    let mut view = vec![principal.client_id.clone()];
+    if let Some(scope) = &principal.orgs {
+        for ids in [&scope.orgs, &scope.sessions].iter() {
+            let mut ids: Vec<&String> = ids.iter().collect();
+            ids.sort();
+            view.push(format!("{:?}", ids));
+        }
+    }
+    if let Some(redaction) = &principal.redaction {
+        view.push(format!("{:?}", redaction.rules));
+    }
+    view.join("\n")
+}
@@ -0,0 +1,218 @@
+use crate::server::{Result, ServerError, State};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Entries are pruned once a table grows past this size
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    /// failed logins allowed per client_id or IP before locking out, 0 disables lockout
+    pub login_attempts: u32,
+    /// how long a client_id or IP is locked out for
+    pub lockout: Duration,
+    /// requests allowed per client_id or IP within `window`, 0 disables rate limiting
+    pub requests: u32,
+    pub window: Duration,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            login_attempts: 5,
+            lockout: Duration::from_secs(300),
+            requests: 600,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    last_failed: Instant,
+    locked_until: Option<Instant>,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// In memory login lockout and fixed window request counters keyed by client_id or IP
+pub(crate) struct Limiter {
+    config: LimitConfig,
+    failures: Mutex<HashMap<String, Failures>>,
+    requests: Mutex<HashMap<String, Window>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(config: LimitConfig) -> Self {
+        Self {
+            config,
+            failures: Mutex::new(HashMap::new()),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Errors with the seconds remaining if any of the keys are locked out
+    pub(crate) fn check_login(&self, keys: &[String]) -> Result<()> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        for key in keys {
+            if let Some(until) = failures.get(key).and_then(|f| f.locked_until) {
+                if until > now {
+                    return Err(ServerError::TooManyRequests(retry_after(until, now)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a failed login, locking out keys which reach the attempt limit
+    pub(crate) fn login_failed(&self, keys: &[String]) {
+        if self.config.login_attempts == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() > PRUNE_THRESHOLD {
+            // a lockout ends no later than this after the failure which started it,
+            // so only keys nobody has been guessing at lately are forgotten
+            let lockout = self.config.lockout;
+            failures.retain(|_, f| now.duration_since(f.last_failed) < lockout);
+        }
+        for key in keys {
+            let entry = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last_failed: now,
+                locked_until: None,
+            });
+            // failures older than a lockout are forgotten rather than adding up forever
+            if now.duration_since(entry.last_failed) >= self.config.lockout {
+                entry.count = 0;
+            }
+            entry.last_failed = now;
+            if entry.locked_until.is_some_and(|u| u <= now) {
+                entry.locked_until = None;
+            }
+            entry.count += 1;
+            if entry.count >= self.config.login_attempts {
+                log::warn!("locking out {} after {} failed logins", key, entry.count);
+                entry.count = 0;
+                entry.locked_until = Some(now + self.config.lockout);
+            }
+        }
+    }
+
+    /// Clears the failure count of a key after a successful login
+    pub(crate) fn login_succeeded(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    /// Counts a request against a key, erroring once the window limit is exceeded
+    pub(crate) fn hit(&self, key: &str) -> Result<()> {
+        if self.config.requests == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        if requests.len() > PRUNE_THRESHOLD {
+            let window = self.config.window;
+            requests.retain(|_, w| now.duration_since(w.start) < window);
+        }
+        let entry = requests.entry(key.to_string()).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(entry.start) >= self.config.window {
+            entry.start = now;
+            entry.count = 0;
+        }
+        entry.count += 1;
+        if entry.count > self.config.requests {
+            return Err(ServerError::TooManyRequests(retry_after(
+                entry.start + self.config.window,
+                now,
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn retry_after(until: Instant, now: Instant) -> u64 {
+    until.duration_since(now).as_secs().max(1)
+}
+
+/// The IP address of the connected client without the port
+pub(crate) fn client_ip(req: &tide::Request<State>) -> String {
+    let peer = req.peer_addr().unwrap_or("unknown");
+    match peer.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_string(),
+    }
+}
+
+/// Applies the per IP request limit to every request
+pub(crate) struct RateLimit {}
+
+impl RateLimit {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for RateLimit {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let key = format!("ip:{}", client_ip(&req));
+        req.state().limits.hit(&key)?;
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn lockout_after_failed_logins() {
+    let limiter = Limiter::new(LimitConfig {
+        login_attempts: 2,
+        ..LimitConfig::default()
+    });
+    let keys = vec!["client:a".to_string(), "ip:127.0.0.1".to_string()];
+    limiter.login_failed(&keys);
+    assert!(limiter.check_login(&keys).is_ok());
+    limiter.login_failed(&keys);
+    assert!(limiter.check_login(&keys).is_err());
+    assert!(limiter.check_login(&["client:b".to_string()]).is_ok());
+}
+
+#[cfg(test)]
+#[test]
+fn recent_failures_survive_pruning() {
+    let limiter = Limiter::new(LimitConfig {
+        login_attempts: 2,
+        ..LimitConfig::default()
+    });
+    let key = vec!["client:a".to_string()];
+    limiter.login_failed(&key);
+    for i in 0..=PRUNE_THRESHOLD {
+        limiter.login_failed(&[format!("ip:10.0.{}.{}", i / 256, i % 256)]);
+    }
+    limiter.login_failed(&key);
+    assert!(limiter.check_login(&key).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn old_failures_are_forgotten() {
+    let limiter = Limiter::new(LimitConfig {
+        login_attempts: 2,
+        lockout: Duration::from_millis(50),
+        ..LimitConfig::default()
+    });
+    let key = vec!["client:a".to_string()];
+    limiter.login_failed(&key);
+    std::thread::sleep(Duration::from_millis(60));
+    limiter.login_failed(&key);
+    assert!(limiter.check_login(&key).is_ok());
+}
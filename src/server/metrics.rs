@@ -0,0 +1,360 @@
+use crate::server::{db, Result, State};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds in seconds of the latency histogram buckets
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long record counts are reused between scrapes, counting reads every table
+const COUNTS_TTL: Duration = Duration::from_secs(60);
+
+/// Records stored of each entity by its name
+type RecordCounts = Vec<(&'static str, i64)>;
+
+/// Where the oneroster api is mounted, successful PUTs below it are syncs
+const IMS_PREFIX: &str = "/ims/oneroster/v1p1/";
+
+/// The routes mount adds, requests are counted by route rather than path so ids
+/// do not each get a series of their own. Any other path is counted as "other"
+const ROUTES: &[&str] = &[
+    "/",
+    "/healthz",
+    "/readyz",
+    "/metrics",
+    "/auth/login",
+    "/auth/check_token",
+    "/ims/oneroster/v1p1",
+    "/ims/oneroster/v1p1/orgs",
+    "/ims/oneroster/v1p1/orgs/:id",
+    "/ims/oneroster/v1p1/schools",
+    "/ims/oneroster/v1p1/schools/:id",
+    "/ims/oneroster/v1p1/schools/:id/classes",
+    "/ims/oneroster/v1p1/schools/:id/students",
+    "/ims/oneroster/v1p1/schools/:id/teachers",
+    "/ims/oneroster/v1p1/schools/:id/enrollments",
+    "/ims/oneroster/v1p1/classes",
+    "/ims/oneroster/v1p1/classes/:id",
+    "/ims/oneroster/v1p1/academicSessions",
+    "/ims/oneroster/v1p1/academicSessions/:id",
+    "/ims/oneroster/v1p1/gradingPeriods",
+    "/ims/oneroster/v1p1/gradingPeriods/:id",
+    "/ims/oneroster/v1p1/periods",
+    "/ims/oneroster/v1p1/subjects",
+    "/ims/oneroster/v1p1/courses",
+    "/ims/oneroster/v1p1/courses/:id",
+    "/ims/oneroster/v1p1/users",
+    "/ims/oneroster/v1p1/users/:id",
+    "/ims/oneroster/v1p1/users/:id/verifyPassword",
+    "/ims/oneroster/v1p1/students",
+    "/ims/oneroster/v1p1/students/:id",
+    "/ims/oneroster/v1p1/teachers",
+    "/ims/oneroster/v1p1/teachers/:id",
+    "/ims/oneroster/v1p1/changes",
+    "/ims/oneroster/v1p1/terms",
+    "/ims/oneroster/v1p1/terms/:id",
+    "/ims/oneroster/v1p1/enrollments",
+    "/ims/oneroster/v1p1/enrollments/:id",
+    "/admin/users",
+    "/admin/user",
+    "/admin/user/:uuid",
+    "/admin/user/:uuid/secret",
+    "/admin/redactions",
+    "/admin/redaction",
+    "/admin/redaction/:name",
+    "/admin/audit/requests",
+    "/admin/audit/changes",
+    "/admin/backup",
+    "/admin/orphans",
+    "/admin/tenants",
+    "/admin/tenants/:name",
+];
+
+/// The route a request path was served by, "other" when it matches none of them
+fn route(path: &str) -> &'static str {
+    let path = match path.strip_suffix('/') {
+        Some(path) if !path.is_empty() => path,
+        _ => path,
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+    ROUTES
+        .iter()
+        .find(|route| {
+            let pattern: Vec<&str> = route.split('/').collect();
+            pattern.len() == segments.len()
+                && pattern
+                    .iter()
+                    .zip(&segments)
+                    .all(|(p, s)| p == s || (p.starts_with(':') && !s.is_empty()))
+        })
+        .copied()
+        .unwrap_or("other")
+}
+
+/// How many times something happened and how long it took, such as the requests of one
+/// method, route and status
+#[derive(Default, Clone)]
+pub(crate) struct Latencies {
+    /// observations within each bucket's bound, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Latencies {
+    pub(crate) fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|b| seconds <= *b) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Figures of the server since it started, rendered in the prometheus text format
+/// along with the state of the database when scraped
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// whether /metrics is served, it needs no token
+    pub(crate) exposed: bool,
+    requests: Mutex<BTreeMap<(String, &'static str, u16), Latencies>>,
+    login_failures: AtomicU64,
+    last_sync: Mutex<Option<DateTime<Utc>>>,
+    /// the record counts of the last scrape and when they were taken
+    counts: Mutex<Option<(Instant, RecordCounts)>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(exposed: bool) -> Self {
+        Self {
+            exposed,
+            ..Self::default()
+        }
+    }
+
+    fn observe(&self, method: String, route: &'static str, status: u16, elapsed: Duration) {
+        let mut requests = self.requests.lock().unwrap();
+        requests
+            .entry((method, route, status))
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub(crate) fn login_failed(&self) {
+        self.login_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics as a prometheus scrape, the last sync falls back to the newest change
+    /// to a record until one happens after the server started
+    pub(crate) async fn render(&self, db: &dyn db::Storage) -> Result<String> {
+        let mut out = String::new();
+        self.render_requests(&mut out);
+        family(
+            &mut out,
+            "oneroster_login_failures_total",
+            "counter",
+            "Logins refused for a wrong client_id or secret",
+        );
+        let failures = self.login_failures.load(Ordering::Relaxed);
+        let _ = writeln!(out, "oneroster_login_failures_total {}", failures);
+
+        family(
+            &mut out,
+            "oneroster_records",
+            "gauge",
+            "Records stored of each entity, including those marked tobedeleted",
+        );
+        for (entity, count) in self.record_counts(db).await? {
+            let _ = writeln!(out, "oneroster_records{{entity=\"{}\"}} {}", entity, count);
+        }
+
+        let pools = db.pool_stats();
+        family(
+            &mut out,
+            "oneroster_db_pool_connections",
+            "gauge",
+            "Open database connections of each pool by whether they are in use",
+        );
+        for pool in pools.iter() {
+            let busy = (pool.size as usize).saturating_sub(pool.idle);
+            for (state, count) in [("busy", busy), ("idle", pool.idle)].iter() {
+                let _ = writeln!(
+                    out,
+                    "oneroster_db_pool_connections{{pool=\"{}\",state=\"{}\"}} {}",
+                    pool.name, state, count
+                );
+            }
+        }
+        family(
+            &mut out,
+            "oneroster_db_pool_wait_seconds",
+            "histogram",
+            "Time taken to acquire a connection from each pool",
+        );
+        for pool in pools.iter() {
+            let labels = format!("pool=\"{}\"", pool.name);
+            histogram(
+                &mut out,
+                "oneroster_db_pool_wait_seconds",
+                &labels,
+                &pool.waits,
+            );
+        }
+
+        let last_sync = *self.last_sync.lock().unwrap();
+        let last_sync = match last_sync {
+            Some(at) => Some(at),
+            None => db.last_change().await?.map(|(_, at)| at),
+        };
+        if let Some(at) = last_sync {
+            family(
+                &mut out,
+                "oneroster_last_sync_timestamp_seconds",
+                "gauge",
+                "When records were last written by a successful PUT",
+            );
+            let _ = writeln!(
+                out,
+                "oneroster_last_sync_timestamp_seconds {}",
+                at.timestamp_millis() as f64 / 1000.0
+            );
+        }
+        Ok(out)
+    }
+
+    fn render_requests(&self, out: &mut String) {
+        let requests = self.requests.lock().unwrap();
+        family(
+            out,
+            "oneroster_http_requests_total",
+            "counter",
+            "Requests served by method, route and status",
+        );
+        for ((method, route, status), latencies) in requests.iter() {
+            let _ = writeln!(
+                out,
+                "oneroster_http_requests_total{{{}}} {}",
+                labels(method, route, *status),
+                latencies.count
+            );
+        }
+        family(
+            out,
+            "oneroster_http_request_duration_seconds",
+            "histogram",
+            "Time taken to respond by method, route and status",
+        );
+        for ((method, route, status), latencies) in requests.iter() {
+            let labels = labels(method, route, *status);
+            histogram(
+                out,
+                "oneroster_http_request_duration_seconds",
+                &labels,
+                latencies,
+            );
+        }
+    }
+
+    /// The records of each entity, counted at most once every COUNTS_TTL
+    async fn record_counts(&self, db: &dyn db::Storage) -> Result<RecordCounts> {
+        if let Some((at, counts)) = &*self.counts.lock().unwrap() {
+            if at.elapsed() < COUNTS_TTL {
+                return Ok(counts.clone());
+            }
+        }
+        let counts = db.record_counts().await?;
+        *self.counts.lock().unwrap() = Some((Instant::now(), counts.clone()));
+        Ok(counts)
+    }
+}
+
+/// The HELP and TYPE lines introducing a metric
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// The cumulative buckets, sum and count of a histogram series
+fn histogram(out: &mut String, name: &str, labels: &str, latencies: &Latencies) {
+    let mut cumulative = 0;
+    for (bound, count) in BUCKETS.iter().zip(latencies.buckets.iter()) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"{}\"}} {}",
+            name, labels, bound, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_bucket{{{},le=\"+Inf\"}} {}",
+        name, labels, latencies.count
+    );
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, latencies.sum);
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, latencies.count);
+}
+
+fn labels(method: &str, route: &str, status: u16) -> String {
+    format!(
+        "method=\"{}\",route=\"{}\",status=\"{}\"",
+        method, route, status
+    )
+}
+
+/// Counts every request by route and final status with the time taken to respond
+pub(crate) struct Measure {}
+
+impl Measure {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for Measure {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let start = Instant::now();
+        let metrics = req.state().metrics.clone();
+        let method = req.method().to_string();
+        let route = route(req.url().path());
+        let res = next.run(req).await;
+        let status = res.status();
+        metrics.observe(method.clone(), route, status.into(), start.elapsed());
+        if method == "PUT" && route.starts_with(IMS_PREFIX) && status.is_success() {
+            *metrics.last_sync.lock().unwrap() = Some(Utc::now());
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn requests_are_counted_by_route() {
+    assert_eq!(
+        route("/ims/oneroster/v1p1/users/u1"),
+        "/ims/oneroster/v1p1/users/:id"
+    );
+    assert_eq!(
+        route("/ims/oneroster/v1p1/schools/s1/classes/"),
+        "/ims/oneroster/v1p1/schools/:id/classes"
+    );
+    assert_eq!(route("/"), "/");
+    assert_eq!(route("/ims/oneroster/v1p1/users//"), "other");
+    assert_eq!(route("/wp-login.php"), "other");
+    let metrics = Metrics::default();
+    let users = route("/ims/oneroster/v1p1/users");
+    metrics.observe("GET".to_string(), users, 200, Duration::from_millis(30));
+    metrics.observe("GET".to_string(), users, 200, Duration::from_secs(20));
+    let mut out = String::new();
+    metrics.render_requests(&mut out);
+    let labels = "method=\"GET\",route=\"/ims/oneroster/v1p1/users\",status=\"200\"";
+    assert!(out.contains(&format!("oneroster_http_requests_total{{{}}} 2\n", labels)));
+    assert!(out.contains(&format!("_bucket{{{},le=\"0.05\"}} 1\n", labels)));
+    assert!(out.contains(&format!("_bucket{{{},le=\"+Inf\"}} 2\n", labels)));
+}
@@ -0,0 +1,223 @@
+use crate::server::State;
+use async_compression::futures::bufread::{BrotliEncoder, GzipEncoder, ZlibEncoder};
+use async_compression::Level;
+use async_std::io::BufReader;
+use http_types::mime::{self, Mime};
+use tide::{Body, Request, StatusCode};
+
+/// Bodies shorter than this are sent as they are, compressing them saves next to nothing
+const MIN_LENGTH: usize = 1024;
+
+/// How a collection is rendered, from the Accept header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    /// The format the client prefers, json when it accepts none of them
+    pub(crate) fn from_request(req: &Request<State>) -> Self {
+        let accept = match header(req, "Accept") {
+            Some(accept) => accept,
+            None => return Format::Json,
+        };
+        let ranges = qualities(&accept);
+        let mut best = (Format::Json, 0.0, 0);
+        for format in [Format::Json, Format::Csv, Format::Ndjson].iter() {
+            // a format takes the quality of the range naming it most closely,
+            // which also decides between formats of the same quality
+            let matched = ranges
+                .iter()
+                .filter_map(|(range, q)| format.matches(range).map(|s| (s, *q)))
+                .max_by_key(|(specificity, _)| *specificity);
+            if let Some((specificity, q)) = matched {
+                if q > best.1 || (q == best.1 && specificity > best.2) {
+                    best = (*format, q, specificity);
+                }
+            }
+        }
+        best.0
+    }
+
+    pub(crate) fn mime(self) -> Mime {
+        match self {
+            Format::Json => mime::JSON,
+            Format::Csv => "text/csv; charset=utf-8".parse().unwrap_or(mime::PLAIN),
+            Format::Ndjson => "application/x-ndjson".parse().unwrap_or(mime::PLAIN),
+        }
+    }
+
+    /// How closely a media range names the format, `None` when it does not
+    fn matches(self, range: &str) -> Option<u8> {
+        let names: &[&str] = match self {
+            Format::Json => &["application/json"],
+            Format::Csv => &["text/csv"],
+            Format::Ndjson => &["application/x-ndjson", "application/ndjson"],
+        };
+        if names.contains(&range) {
+            return Some(2);
+        }
+        match range.split_once('/') {
+            Some(("*", "*")) => Some(0),
+            Some((kind, "*")) if names.iter().any(|n| n.starts_with(&format!("{}/", kind))) => {
+                Some(1)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A content coding the server compresses responses with, from the Accept-Encoding header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The coding the client prefers, the order here breaking ties. `None` for no compression
+    fn negotiate(accept: &str) -> Option<Self> {
+        let accepted = qualities(accept);
+        let quality = |token: &str| {
+            accepted
+                .iter()
+                .find(|(t, _)| t == token)
+                .or_else(|| accepted.iter().find(|(t, _)| t == "*"))
+                .map(|(_, q)| *q)
+        };
+        // identity is only preferred over compression when asked for by name
+        let identity = accepted
+            .iter()
+            .find(|(t, _)| t == "identity")
+            .map_or(0.0, |(_, q)| *q);
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate].iter() {
+            match quality(encoding.token()) {
+                Some(q) if q > 0.0 && q >= identity && best.is_none_or(|(_, b)| q > b) => {
+                    best = Some((*encoding, q));
+                }
+                _ => (),
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// Compresses a body as it is read
+    fn encode(self, body: Body) -> Body {
+        let mime = body.mime().clone();
+        let mut encoded = match self {
+            // brotli's default is its slowest setting, 4 is about as fast as gzip and smaller
+            Encoding::Brotli => Body::from_reader(
+                BufReader::new(BrotliEncoder::with_quality(body, Level::Precise(4))),
+                None,
+            ),
+            Encoding::Gzip => Body::from_reader(BufReader::new(GzipEncoder::new(body)), None),
+            // http's deflate is the zlib format rather than a raw deflate stream
+            Encoding::Deflate => Body::from_reader(BufReader::new(ZlibEncoder::new(body)), None),
+        };
+        encoded.set_mime(mime);
+        encoded
+    }
+}
+
+/// Every value of a header joined as one list
+fn header(req: &Request<State>, name: &str) -> Option<String> {
+    req.header(name).map(|values| {
+        values
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    })
+}
+
+/// The lowercase tokens of a header like Accept or Accept-Encoding with their q values
+fn qualities(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let token = parts.next()?.trim().to_lowercase();
+            if token.is_empty() {
+                return None;
+            }
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((token, q))
+        })
+        .collect()
+}
+
+/// Compresses response bodies with the coding the client prefers
+pub(crate) struct Compress {}
+
+impl Compress {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for Compress {
+    async fn handle(&self, req: Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let encoding = header(&req, "Accept-Encoding").and_then(|a| Encoding::negotiate(&a));
+        let mut res = next.run(req).await;
+        res.append_header("vary", "accept-encoding");
+        let encoding = match encoding {
+            Some(encoding) => encoding,
+            None => return Ok(res),
+        };
+        let small = res.len().is_some_and(|len| len < MIN_LENGTH);
+        let bodiless = matches!(
+            res.status(),
+            StatusCode::NoContent | StatusCode::NotModified
+        );
+        if small || bodiless || res.header("content-encoding").is_some() {
+            return Ok(res);
+        }
+        let body = encoding.encode(res.take_body());
+        res.set_body(body);
+        res.insert_header("content-encoding", encoding.token());
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn preferred_codings_and_formats() {
+    assert_eq!(
+        Encoding::negotiate("gzip, deflate, br"),
+        Some(Encoding::Brotli)
+    );
+    assert_eq!(
+        Encoding::negotiate("gzip;q=1, br;q=0.5"),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(Encoding::negotiate("*;q=0.8, br;q=0"), Some(Encoding::Gzip));
+    assert_eq!(Encoding::negotiate("identity, gzip;q=0.5"), None);
+    assert_eq!(
+        Encoding::negotiate("deflate;q=0.5"),
+        Some(Encoding::Deflate)
+    );
+    assert_eq!(Encoding::negotiate("compress"), None);
+    assert_eq!(Format::Csv.matches("text/csv"), Some(2));
+    assert_eq!(Format::Csv.matches("text/*"), Some(1));
+    assert_eq!(Format::Ndjson.matches("text/*"), None);
+    assert_eq!(
+        qualities("text/csv;q=0.5, */*"),
+        vec![("text/csv".to_string(), 0.5), ("*/*".to_string(), 1.0)]
+    );
+}
@@ -1,4 +1,7 @@
+use super::tenant::Prefix;
 use super::{Result, ServerError, State};
+use crate::logging;
+use chrono::{DateTime, Utc};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
@@ -10,6 +13,9 @@ pub(crate) struct Parameters {
     pub(crate) sort: Option<String>,
     pub(crate) filter: Option<String>, // name=bob AND age>20
     pub(crate) fields: Option<String>, // name,age
+    pub(crate) search: Option<String>, // jon smith
+    #[serde(rename = "asOf")]
+    pub(crate) as_of: Option<DateTime<Utc>>, // 2021-03-01T09:00:00Z
 }
 
 impl Default for Parameters {
@@ -20,6 +26,8 @@ impl Default for Parameters {
             sort: None,
             filter: None,
             fields: None,
+            search: None,
+            as_of: None,
         }
     }
 }
@@ -31,8 +39,7 @@ pub(crate) async fn apply_parameters(
 ) -> Result<(String, String)> {
     let (query, length) = parameter_builder(&params, wrapper).await?;
     let output = jq_rs::run(&query, &json).map_err(|e| {
-        log::debug!("{}", json);
-        log::debug!("jq error: {}: query: {}", e, query);
+        log::debug!("jq error: {}: query: {}", e, logging::mask_literals(&query));
         ServerError::InvalidParameters
     })?;
     let total = jq_rs::run(&length, &json).map_err(|_| ServerError::InvalidParameters)?;
@@ -65,10 +72,28 @@ async fn parameter_builder(params: &Parameters, wrapper: &str) -> Result<(String
     }
     builder.push_str("} ");
 
-    log::debug!("parameter jq builder: {}", builder);
+    log::debug!("parameter jq builder: {}", logging::mask_literals(&builder));
     Ok((builder, len))
 }
 
+/// The jq program run on each record of a streamed collection,
+/// `None` when the records are returned as they are
+pub(crate) async fn record_program(params: &Parameters) -> Result<Option<String>> {
+    let mut program = Vec::new();
+    if let Some(fields) = parse_fields(params).await {
+        program.push(format!("{{ {} }}", fields));
+    }
+    if let Some(filter) = parse_filter(params).await? {
+        program.push(format!("select({})", filter));
+    }
+    if program.is_empty() {
+        return Ok(None);
+    }
+    let program = program.join(" | ");
+    log::debug!("record jq program: {}", logging::mask_literals(&program));
+    Ok(Some(program))
+}
+
 async fn parse_sort(params: &Parameters) -> Option<String> {
     if let Some(q_sort) = &params.sort {
         return Some(q_sort.to_owned());
@@ -98,7 +123,6 @@ async fn parse_filter(params: &Parameters) -> Result<Option<String>> {
                     predicate = "==";
                 };
                 let filter = format!(".{} {} \"{}\"", &cap[1], predicate, &cap[3].trim());
-                log::debug!("filter: {}", filter);
                 filters.push(filter);
             }
         }
@@ -137,14 +161,16 @@ pub(super) async fn link_header_builder(
     params: &Parameters,
     data_len: usize,
 ) -> String {
-    println!("{:?}", req.url());
     let mut link = String::from("");
+    // tenants served under a path prefix link back through it
+    let prefix = req.ext::<Prefix>().map(|p| p.0.as_str()).unwrap_or("");
     if params.offset <= data_len as u32 {
         link = format!(
-            "<{}://{}:{}/ims/oneroster/v1p1{}?offset={}&limit={}>; rel=\"next\",",
+            "<{}://{}:{}{}/ims/oneroster/v1p1{}?offset={}&limit={}>; rel=\"next\",",
             req.url().scheme(),
-            req.url().domain().unwrap_or("localhost"),
-            req.url().port().unwrap_or(443),
+            req.url().host_str().unwrap_or("localhost"),
+            req.url().port_or_known_default().unwrap_or(443),
+            prefix,
             req.url().path(),
             params.offset + params.limit,
             params.limit
@@ -0,0 +1,170 @@
+use crate::server::State;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// An IP address or CIDR range e.g. 10.0.0.0/8, fd00::/8 or 192.168.1.10
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid IP address or CIDR range: {}", s);
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl IpRange {
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Proxies whose X-Forwarded-For/Proto/Host headers are believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    pub ranges: Vec<IpRange>,
+    /// trust every connection, used when listening on a unix socket only the proxy can reach
+    pub unix: bool,
+}
+
+impl TrustedProxies {
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.ranges.iter().any(|r| r.contains(ip))
+    }
+
+    fn trusts_peer(&self, peer: Option<&str>) -> bool {
+        match peer.and_then(|p| p.parse::<SocketAddr>().ok()) {
+            Some(addr) => self.trusts(&addr.ip()),
+            None => self.unix,
+        }
+    }
+
+    /// The rightmost address in X-Forwarded-For which is not a trusted proxy
+    fn client(&self, forwarded_for: &str) -> Option<IpAddr> {
+        let hops: Vec<IpAddr> = forwarded_for
+            .split(',')
+            .filter_map(|h| h.trim().parse().ok())
+            .collect();
+        hops.iter()
+            .rev()
+            .find(|ip| !self.trusts(ip))
+            .or_else(|| hops.first())
+            .copied()
+    }
+}
+
+/// The value the nearest proxy added to a header each proxy appends to, like X-Forwarded-For
+fn nearest(values: &str) -> Option<String> {
+    values
+        .rsplit(',')
+        .next()
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+/// Rewrites the peer address and URL of requests from a trusted proxy
+/// so rate limiting, audit and link headers see the real client and public URL
+pub(crate) struct Forwarded {
+    proxies: TrustedProxies,
+}
+
+impl Forwarded {
+    pub(crate) fn new(proxies: TrustedProxies) -> Self {
+        Self { proxies }
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for Forwarded {
+    async fn handle(
+        &self,
+        mut req: tide::Request<State>,
+        next: tide::Next<'_, State>,
+    ) -> tide::Result {
+        if self.proxies.trusts_peer(req.peer_addr()) {
+            let header = |name| req.header(name).map(|h| h.last().as_str().to_string());
+            let forwarded_for = header("x-forwarded-for");
+            let proto = header("x-forwarded-proto").and_then(|p| nearest(&p));
+            let host = header("x-forwarded-host").and_then(|h| nearest(&h));
+            let inner: &mut http_types::Request = req.as_mut();
+            if let Some(client) = forwarded_for.and_then(|f| self.proxies.client(&f)) {
+                // port 0 as the client port is not forwarded
+                inner.set_peer_addr(Some(SocketAddr::new(client, 0)));
+            }
+            let scheme = proto.unwrap_or_else(|| inner.url().scheme().to_string());
+            let host = host.unwrap_or_else(|| {
+                let url = inner.url();
+                match url.port() {
+                    Some(port) => format!("{}:{}", url.host_str().unwrap_or("localhost"), port),
+                    None => url.host_str().unwrap_or("localhost").to_string(),
+                }
+            });
+            match http_types::Url::parse(&format!("{}://{}", scheme, host)) {
+                Ok(public) => {
+                    let url = inner.url_mut();
+                    // scheme changes between special schemes e.g. http to https always succeed
+                    let _ = url.set_scheme(public.scheme());
+                    let _ = url.set_host(public.host_str());
+                    let _ = url.set_port(public.port());
+                }
+                Err(e) => log::warn!("ignoring invalid forwarded host {}: {}", host, e),
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn forwarded_client_skips_trusted_hops() {
+    let proxies = TrustedProxies {
+        ranges: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+        unix: false,
+    };
+    assert_eq!(
+        proxies.client("203.0.113.7, 198.51.100.2, 10.1.2.3"),
+        Some("198.51.100.2".parse().unwrap())
+    );
+    assert_eq!(
+        proxies.client("10.0.0.1, 10.0.0.2"),
+        Some("10.0.0.1".parse().unwrap())
+    );
+    assert!(proxies.trusts_peer(Some("[::1]:5000")));
+    assert!(!proxies.trusts_peer(Some("192.168.0.1:5000")));
+    assert!(!proxies.trusts_peer(None));
+    assert_eq!(nearest("http, https"), Some("https".to_string()));
+    assert_eq!(
+        nearest("evil.example.com,oneroster.example.com"),
+        Some("oneroster.example.com".to_string())
+    );
+    assert_eq!(nearest("https, "), None);
+}
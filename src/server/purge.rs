@@ -0,0 +1,41 @@
+use crate::server::db;
+use async_std::task::JoinHandle;
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often tobedeleted records are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+
+#[derive(Debug, Clone, Default)]
+pub struct PurgeConfig {
+    /// days since dateLastModified a tobedeleted record is kept for, 0 keeps them forever
+    pub retention_days: u32,
+}
+
+/// Hard deletes tobedeleted records older than `retention_days`, returning how many were removed
+pub(crate) async fn purge(retention_days: u32, db: &dyn db::Storage) -> crate::server::Result<u64> {
+    let before = Utc::now() - chrono::Duration::days(retention_days.into());
+    let count = db.purge_deleted(before).await?;
+    log::info!(
+        "purged {} tobedeleted records last modified before {}",
+        count,
+        before
+    );
+    Ok(count)
+}
+
+/// Purges tobedeleted records now and then once a day, `None` when retention is off
+pub(crate) fn spawn_purge(config: PurgeConfig, db: Arc<dyn db::Storage>) -> Option<JoinHandle<()>> {
+    if config.retention_days == 0 {
+        return None;
+    }
+    Some(async_std::task::spawn(async move {
+        loop {
+            if let Err(e) = purge(config.retention_days, db.as_ref()).await {
+                log::error!("could not purge tobedeleted records: {}", e);
+            }
+            async_std::task::sleep(PURGE_INTERVAL).await;
+        }
+    }))
+}
@@ -0,0 +1,241 @@
+use crate::server::negotiate::Format;
+use crate::server::Result;
+use serde_json::Value;
+
+/// Writes the records of a collection as a json object, a json record per line
+/// or csv with a column per field
+pub(crate) struct Writer {
+    format: Format,
+    columns: Vec<String>,
+    written: usize,
+}
+
+impl Writer {
+    /// Csv columns are the requested fields or else every field of the entity
+    pub(crate) fn new(format: Format, wrapper: &str, fields: Option<&str>) -> Self {
+        let columns = match fields {
+            Some(fields) => fields.split(',').map(|f| f.trim().to_string()).collect(),
+            None => columns(wrapper).iter().map(|c| c.to_string()).collect(),
+        };
+        Self {
+            format,
+            columns,
+            written: 0,
+        }
+    }
+
+    pub(crate) fn head(&self, wrapper: &str) -> Result<Vec<u8>> {
+        match self.format {
+            Format::Json => Ok(format!("{{\"{}\":[", wrapper).into_bytes()),
+            Format::Ndjson => Ok(Vec::new()),
+            Format::Csv => row(self.columns.iter().map(String::as_str)),
+        }
+    }
+
+    /// Appends a json record to `out`
+    pub(crate) fn record(&mut self, record: &str, out: &mut Vec<u8>) -> Result<()> {
+        match self.format {
+            Format::Json => {
+                if self.written > 0 {
+                    out.push(b',');
+                }
+                out.extend_from_slice(record.as_bytes());
+            }
+            Format::Ndjson => {
+                out.extend_from_slice(record.as_bytes());
+                out.push(b'\n');
+            }
+            Format::Csv => {
+                let record: Value = serde_json::from_str(record)?;
+                let cells: Vec<String> = self
+                    .columns
+                    .iter()
+                    .map(|c| record.get(c).map(cell).unwrap_or_default())
+                    .collect();
+                out.extend(row(cells.iter().map(String::as_str))?);
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    pub(crate) fn tail(&self) -> &'static [u8] {
+        match self.format {
+            Format::Json => b"]}\n",
+            Format::Ndjson | Format::Csv => b"",
+        }
+    }
+}
+
+/// Renders a collection built whole as a json object of the form { "users": [{}] },
+/// json is returned as it is
+pub(crate) fn render(
+    format: Format,
+    output: String,
+    wrapper: &str,
+    fields: Option<&str>,
+) -> Result<String> {
+    if format == Format::Json {
+        return Ok(output);
+    }
+    let mut writer = Writer::new(format, wrapper, fields);
+    let mut out = writer.head(wrapper)?;
+    let output: Value = serde_json::from_str(&output)?;
+    for record in output[wrapper].as_array().into_iter().flatten() {
+        writer.record(&record.to_string(), &mut out)?;
+    }
+    out.extend_from_slice(writer.tail());
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// The fields of each entity in the order they are serialized
+fn columns(wrapper: &str) -> &'static [&'static str] {
+    match wrapper {
+        "academicSessions" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "title",
+            "startDate",
+            "endDate",
+            "type",
+            "parent",
+            "children",
+            "schoolYear",
+        ],
+        "classes" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "title",
+            "classCode",
+            "classType",
+            "location",
+            "grades",
+            "subjects",
+            "course",
+            "school",
+            "terms",
+            "subjectCodes",
+            "periods",
+            "resources",
+        ],
+        "courses" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "title",
+            "schoolYear",
+            "courseCode",
+            "grades",
+            "subjects",
+            "org",
+            "subjectCodes",
+        ],
+        "enrollments" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "user",
+            "class",
+            "school",
+            "role",
+            "primary",
+            "beginDate",
+            "endDate",
+        ],
+        "orgs" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "name",
+            "type",
+            "identifier",
+            "parent",
+            "children",
+        ],
+        "periods" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "title",
+            "periodCode",
+            "description",
+            "orgs",
+        ],
+        "subjects" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "title",
+            "subjectCode",
+        ],
+        "users" => &[
+            "sourcedId",
+            "status",
+            "dateLastModified",
+            "username",
+            "userIds",
+            "enabledUser",
+            "givenName",
+            "familyName",
+            "middleName",
+            "role",
+            "identifier",
+            "email",
+            "sms",
+            "phone",
+            "agents",
+            "orgs",
+            "grades",
+            "password",
+        ],
+        _ => &["sourcedId"],
+    }
+}
+
+/// A field as a csv cell, references are written as their sourcedId and lists comma separated
+/// as in the OneRoster csv binding, user ids as {type:identifier}
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values.iter().map(cell).collect::<Vec<_>>().join(","),
+        Value::Object(object) => match object.get("sourcedId") {
+            Some(id) => cell(id),
+            None => format!(
+                "{{{}}}",
+                object.values().map(cell).collect::<Vec<_>>().join(":")
+            ),
+        },
+        other => other.to_string(),
+    }
+}
+
+fn row<'a>(cells: impl Iterator<Item = &'a str>) -> Result<Vec<u8>> {
+    let mut csv = csv::Writer::from_writer(Vec::new());
+    csv.write_record(cells)?;
+    csv.into_inner().map_err(|e| e.into_error().into())
+}
+
+#[cfg(test)]
+#[test]
+fn collections_render_as_csv_and_ndjson() -> Result<()> {
+    let output = r#"{"users":[{"sourcedId":"u1","givenName":"Jon, Jr","enabledUser":1,
+        "orgs":[{"href":"orgs/s1","sourcedId":"s1","type":"org"},{"sourcedId":"s2"}],
+        "userIds":[{"type":"upn","identifier":"jon@x"}]}]}"#;
+    let fields = "sourcedId,givenName,orgs,userIds,enabledUser";
+    let csv = render(Format::Csv, output.to_string(), "users", Some(fields))?;
+    assert_eq!(
+        csv,
+        "sourcedId,givenName,orgs,userIds,enabledUser\nu1,\"Jon, Jr\",\"s1,s2\",{upn:jon@x},1\n"
+    );
+    let ndjson = render(Format::Ndjson, output.to_string(), "users", None)?;
+    assert_eq!(ndjson.lines().count(), 1);
+    assert!(ndjson.starts_with(r#"{"sourcedId":"u1""#));
+    assert_eq!(
+        render(Format::Json, output.to_string(), "users", None)?,
+        output
+    );
+    Ok(())
+}
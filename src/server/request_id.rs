@@ -0,0 +1,52 @@
+use crate::logging;
+use crate::server::State;
+use uuid::Uuid;
+
+/// Longest X-Request-Id taken from a client, longer ones are replaced
+const MAX_LENGTH: usize = 128;
+
+/// Whether an X-Request-Id from a client or proxy can go into the logs as it is
+fn usable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:/+=".contains(c))
+}
+
+/// Tags the log lines written while serving a request with its id, the X-Request-Id sent
+/// with it or else a new one, and returns the id in the response's X-Request-Id
+pub(crate) struct RequestId {}
+
+impl RequestId {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for RequestId {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let id = req
+            .header("x-request-id")
+            .map(|values| values.last().as_str().trim().to_string())
+            .filter(|id| usable(id))
+            .unwrap_or_else(|| Uuid::new_v4().to_hyphenated().to_string());
+        logging::set_request_id(Some(id.clone()));
+        let mut res = next.run(req).await;
+        // the connection's next request may not set one before logging
+        logging::set_request_id(None);
+        res.insert_header("x-request-id", id);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn request_ids_from_clients_are_checked() {
+    assert!(usable("4bf92f3577b34da6a3ce929d0e0e4736"));
+    assert!(usable("req-1:edge/2"));
+    assert!(!usable(""));
+    assert!(!usable("abc\n[ERROR] forged"));
+    assert!(!usable(&"a".repeat(MAX_LENGTH + 1)));
+}
@@ -0,0 +1,163 @@
+use crate::server::db::{Entity, Storage};
+use crate::server::{Result, ServerError};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// How alike every search word and its closest word in a record must be for the record to match,
+/// about one letter wrong in five
+const MIN_SIMILARITY: f64 = 0.75;
+
+/// The entity searched by a collection endpoint, from its json wrapper
+pub(crate) fn entity(wrapper: &str) -> Result<Entity> {
+    match wrapper {
+        "users" => Ok(Entity::Users),
+        "classes" => Ok(Entity::Classes),
+        "orgs" => Ok(Entity::Orgs),
+        _ => Err(ServerError::InvalidParameters),
+    }
+}
+
+/// Scores the records matching a search by sourcedId.
+/// The index finds records sharing three letters in a row with the search, or with a word starting
+/// with a search word too short for that, which are then scored by the edit distance of each
+/// search word to their closest word so misspellings match
+pub(crate) async fn rank(
+    db: &dyn Storage,
+    entity: Entity,
+    search: &str,
+) -> Result<HashMap<String, f64>> {
+    let query = words(search);
+    if query.is_empty() {
+        return Err(ServerError::InvalidParameters);
+    }
+    let trigrams = trigrams(&query);
+    let prefixes = prefixes(&query);
+    let mut ranks = HashMap::new();
+    for hit in db.search(entity, &trigrams, &prefixes).await? {
+        if let Some(score) = score(&query, &hit.text) {
+            ranks.insert(hit.sourced_id, score);
+        }
+    }
+    Ok(ranks)
+}
+
+/// Keeps the records with a rank, best first
+pub(crate) fn order<T, F>(items: &mut Vec<T>, ranks: &HashMap<String, f64>, sourced_id: F)
+where
+    F: Fn(&T) -> &str,
+{
+    items.retain(|i| ranks.contains_key(sourced_id(i)));
+    let rank = |i: &T| ranks.get(sourced_id(i)).copied().unwrap_or_default();
+    items.sort_by(|a, b| rank(b).partial_cmp(&rank(a)).unwrap_or(Ordering::Equal));
+}
+
+/// Lowercase runs of letters and digits
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn trigrams(words: &[String]) -> Vec<String> {
+    let mut trigrams = Vec::new();
+    for word in words {
+        let chars: Vec<char> = word.chars().collect();
+        for t in chars.windows(3) {
+            let t: String = t.iter().collect();
+            if !trigrams.contains(&t) {
+                trigrams.push(t);
+            }
+        }
+    }
+    trigrams
+}
+
+/// The words with no trigrams, which only match the words they start
+fn prefixes(words: &[String]) -> Vec<String> {
+    let mut prefixes = Vec::new();
+    for word in words {
+        if word.chars().count() < 3 && !prefixes.contains(word) {
+            prefixes.push(word.clone());
+        }
+    }
+    prefixes
+}
+
+/// The mean similarity of the search words to their closest words in the text,
+/// `None` unless every search word has a close enough word
+fn score(query: &[String], text: &str) -> Option<f64> {
+    let text = words(text);
+    let mut total = 0.0;
+    for q in query {
+        let best = text.iter().map(|w| similarity(q, w)).fold(0.0, f64::max);
+        if best < MIN_SIMILARITY {
+            return None;
+        }
+        total += best;
+    }
+    Some(total / query.len() as f64)
+}
+
+/// 1 for the same word, a little less when the search word starts or is part of it,
+/// otherwise how close it is to the word or to the start of the word
+fn similarity(query: &str, word: &str) -> f64 {
+    if query == word {
+        return 1.0;
+    }
+    if word.starts_with(query) {
+        return 0.95;
+    }
+    // a letter or two is part of too many words to mean anything
+    if query.chars().count() > 2 && word.contains(query) {
+        return 0.9;
+    }
+    let len = query.chars().count();
+    let start: String = word.chars().take(len).collect();
+    let close = |other: &str| {
+        let longest = len.max(other.chars().count());
+        1.0 - distance(query, other) as f64 / longest as f64
+    };
+    // a partial match is never as good as a whole one
+    close(word).max(close(&start) * 0.9)
+}
+
+/// Levenshtein distance
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(ca != *cb);
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+#[test]
+fn misspelt_names_match_below_exact_ones() {
+    let query = words("Jon Smyth");
+    assert_eq!(trigrams(&query), vec!["jon", "smy", "myt", "yth"]);
+    let short = words("Al Li al");
+    assert_eq!(trigrams(&short), Vec::<String>::new());
+    assert_eq!(prefixes(&short), vec!["al", "li"]);
+    assert!(score(&short, "Alice Lidell alidell").is_some());
+    assert_eq!(score(&short, "Alice Allen aallen"), None);
+    let exact = score(&query, "Jon Smyth jsmyth");
+    let prefix = score(&query, "Jonathan Smythe");
+    let misspelt = score(&query, "Jon Smith jsmith jon@example.org");
+    assert!(exact > prefix && prefix > misspelt && misspelt.is_some());
+    assert_eq!(score(&query, "Jon Brown jbrown"), None);
+    assert_eq!(score(&words("jones"), "Jon Smith"), None);
+    assert_eq!(distance("smyth", "smith"), 1);
+    assert_eq!(distance("", "abc"), 3);
+
+    let mut items = vec!["a", "b", "c"];
+    let ranks = HashMap::from([("a".to_string(), 0.7), ("c".to_string(), 0.9)]);
+    order(&mut items, &ranks, |i| i);
+    assert_eq!(items, vec!["c", "a"]);
+}
@@ -0,0 +1,280 @@
+use crate::logging::{self, mask_literals};
+use crate::model;
+use crate::server::auth::principal::{OrgScoped, Principal};
+use crate::server::db::{Collection, Snapshot, Storage};
+use crate::server::negotiate::Format;
+use crate::server::params::{self, Parameters};
+use crate::server::render::Writer;
+use crate::server::{Result, ServerError};
+use async_std::channel::{self, Sender};
+use async_std::task::{self, JoinHandle};
+use futures::{StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::io;
+use tide::Body;
+
+/// Records read ahead of the response, which bounds the memory of a request
+const READ_AHEAD: usize = 64;
+/// Response bytes gathered before they are handed to the connection
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Whether a collection request can be answered a record at a time,
+/// sorting, searching and past versions need the whole collection at once
+pub(crate) fn streams(params: &Parameters) -> bool {
+    params.sort.is_none() && params.search.is_none() && params.as_of.is_none()
+}
+
+/// How many records of a collection there are, how many the credential sees
+/// and how many of those match the filter
+pub(crate) struct Counts {
+    pub(crate) records: usize,
+    pub(crate) visible: usize,
+    pub(crate) total: usize,
+}
+
+/// A record of a collection as seen through the credential and the query parameters
+enum Seen {
+    Hidden,
+    Filtered,
+    Shown(String),
+}
+
+/// Counts a collection in a new snapshot, returned for the body to be read from so the
+/// counts match it. The database counts the records when every one of them is shown,
+/// otherwise they are read through once without keeping any of them
+pub(crate) async fn count(
+    db: &dyn Storage,
+    view: Collection,
+    principal: Principal,
+    params: &Parameters,
+) -> Result<(Counts, Box<dyn Snapshot>)> {
+    let mut snapshot = db.snapshot().await?;
+    if params.filter.is_none() && principal.orgs.is_none() {
+        let records = snapshot.count_collection(view).await?;
+        let counts = Counts {
+            records,
+            visible: records,
+            total: records,
+        };
+        return Ok((counts, snapshot));
+    }
+    // fields alone do not change which records match
+    let program = match params.filter {
+        Some(_) => params::record_program(params).await?,
+        None => None,
+    };
+    let mut counts = Counts {
+        records: 0,
+        visible: 0,
+        total: 0,
+    };
+    let (mut records, reader) = read(snapshot, view, principal, program);
+    while let Some(seen) = records.next().await {
+        counts.records += 1;
+        match seen? {
+            Seen::Hidden => continue,
+            Seen::Filtered => counts.visible += 1,
+            Seen::Shown(_) => {
+                counts.visible += 1;
+                counts.total += 1;
+            }
+        }
+    }
+    Ok((counts, reader.await))
+}
+
+/// The page of a collection as a response body in the negotiated format,
+/// written as the records are read so only a few of them are held at once
+pub(crate) async fn body(
+    snapshot: Box<dyn Snapshot>,
+    view: Collection,
+    principal: Principal,
+    params: &Parameters,
+    format: Format,
+) -> Result<Body> {
+    let program = params::record_program(params).await?;
+    let (offset, limit) = (params.offset as usize, params.limit as usize);
+    let (wrapper, _, _) = view.history();
+    let writer = Writer::new(format, wrapper, params.fields.as_deref());
+    let (tx, rx) = channel::bounded(READ_AHEAD);
+    let id = logging::request_id();
+    task::spawn(async move {
+        logging::set_request_id(id);
+        let (mut records, _) = read(snapshot, view, principal, program);
+        if let Err(e) = write(&mut records, &tx, writer, wrapper, offset, limit).await {
+            // the status line is gone, all that is left is to cut the response short
+            log::error!("streaming {} failed: {}", wrapper, e);
+            let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+        }
+    });
+    Ok(Body::from_reader(rx.into_async_read(), None))
+}
+
+async fn write(
+    records: &mut channel::Receiver<Result<Seen>>,
+    tx: &Sender<io::Result<Vec<u8>>>,
+    mut writer: Writer,
+    wrapper: &str,
+    offset: usize,
+    limit: usize,
+) -> Result<()> {
+    let mut chunk = writer.head(wrapper)?;
+    let mut shown = 0;
+    while shown < offset + limit {
+        let record = match records.next().await {
+            Some(seen) => match seen? {
+                Seen::Shown(record) => record,
+                _ => continue,
+            },
+            None => break,
+        };
+        shown += 1;
+        if shown <= offset {
+            continue;
+        }
+        writer.record(&record, &mut chunk)?;
+        if chunk.len() >= CHUNK_SIZE {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK_SIZE));
+            if tx.send(Ok(full)).await.is_err() {
+                // the client went away
+                return Ok(());
+            }
+        }
+    }
+    chunk.extend_from_slice(writer.tail());
+    let _ = tx.send(Ok(chunk)).await;
+    Ok(())
+}
+
+/// Reads the records of a collection in a task of their own, stopping once the
+/// receiver is dropped, and hands back the snapshot when done.
+/// A jq program holds on to its thread so filtering runs blocking
+fn read(
+    snapshot: Box<dyn Snapshot>,
+    view: Collection,
+    principal: Principal,
+    program: Option<String>,
+) -> (
+    channel::Receiver<Result<Seen>>,
+    JoinHandle<Box<dyn Snapshot>>,
+) {
+    let (tx, rx) = channel::bounded(READ_AHEAD);
+    let reader = match program {
+        None => task::spawn(see(snapshot, view, principal, tx, |record| {
+            Ok(Some(record))
+        })),
+        Some(program) => task::spawn_blocking(move || {
+            let mut jq = match jq_rs::compile(&program) {
+                Ok(jq) => jq,
+                Err(e) => {
+                    log::debug!("jq error: {}: query: {}", e, mask_literals(&program));
+                    let _ = task::block_on(tx.send(Err(ServerError::InvalidParameters)));
+                    return snapshot;
+                }
+            };
+            let filter = |record: String| {
+                let output = jq.run(&record).map_err(|e| {
+                    log::debug!("jq error: {}: query: {}", e, mask_literals(&program));
+                    ServerError::InvalidParameters
+                })?;
+                let output = output.trim();
+                Ok(Some(output).filter(|o| !o.is_empty()).map(str::to_string))
+            };
+            task::block_on(see(snapshot, view, principal, tx, filter))
+        }),
+    };
+    (rx, reader)
+}
+
+async fn see<F>(
+    mut snapshot: Box<dyn Snapshot>,
+    view: Collection,
+    principal: Principal,
+    tx: Sender<Result<Seen>>,
+    mut filter: F,
+) -> Box<dyn Snapshot>
+where
+    F: FnMut(String) -> Result<Option<String>>,
+{
+    let (_, entity, _) = view.history();
+    let mut rows = snapshot.stream_collection(view);
+    while let Some(row) = rows.next().await {
+        let seen = row.and_then(|row| match record(&principal, entity, &row)? {
+            None => Ok(Seen::Hidden),
+            Some(record) => Ok(match filter(record.to_string())? {
+                None => Seen::Filtered,
+                Some(record) => Seen::Shown(record),
+            }),
+        });
+        let failed = seen.is_err();
+        if tx.send(seen).await.is_err() || failed {
+            break;
+        }
+    }
+    drop(rows);
+    snapshot
+}
+
+/// A record as the credential sees it, `None` when it is not visible.
+/// Records go through their model as they do when served whole
+fn record(principal: &Principal, entity: &str, row: &str) -> Result<Option<Value>> {
+    fn visible<T>(principal: &Principal, row: &str) -> Result<Option<Value>>
+    where
+        T: OrgScoped + DeserializeOwned + Serialize,
+    {
+        let item: T = serde_json::from_str(row)?;
+        if !principal.permits(&item) {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_value(item)?))
+    }
+    let mut record = match entity {
+        "academicSession" => visible::<model::AcademicSession>(principal, row)?,
+        "class" => visible::<model::Class>(principal, row)?,
+        "course" => visible::<model::Course>(principal, row)?,
+        "enrollment" => visible::<model::Enrollment>(principal, row)?,
+        "org" => visible::<model::Org>(principal, row)?,
+        "period" => visible::<model::Period>(principal, row)?,
+        "subject" => visible::<model::Subject>(principal, row)?,
+        "user" => visible::<model::User>(principal, row)?,
+        _ => None,
+    };
+    if let Some(record) = &mut record {
+        principal.redact_record(entity, record);
+    }
+    Ok(record)
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn pages_skip_hidden_and_filtered_records() -> Result<()> {
+    let page = |offset, limit| async move {
+        let (tx, mut records) = channel::bounded(READ_AHEAD);
+        for seen in vec![
+            Seen::Shown("1".to_string()),
+            Seen::Hidden,
+            Seen::Shown("2".to_string()),
+            Seen::Filtered,
+            Seen::Shown("3".to_string()),
+        ] {
+            tx.send(Ok(seen)).await.ok();
+        }
+        drop(tx);
+        let (body, rx) = channel::bounded(READ_AHEAD);
+        let writer = Writer::new(Format::Json, "users", None);
+        write(&mut records, &body, writer, "users", offset, limit).await?;
+        drop(body);
+        let chunks: Vec<io::Result<Vec<u8>>> = rx.collect().await;
+        let bytes: Vec<u8> = chunks
+            .into_iter()
+            .flat_map(|c| c.unwrap_or_default())
+            .collect();
+        Ok::<_, ServerError>(String::from_utf8_lossy(&bytes).to_string())
+    };
+    assert_eq!(page(0, 100).await?, "{\"users\":[1,2,3]}\n");
+    assert_eq!(page(1, 1).await?, "{\"users\":[2]}\n");
+    assert_eq!(page(3, 10).await?, "{\"users\":[]}\n");
+    Ok(())
+}
@@ -0,0 +1,359 @@
+use crate::server::{
+    audit, auth, db, limits, metrics, purge, tls, Creds, Result, ServerError, State,
+};
+use async_std::sync::RwLock;
+use async_std::task::JoinHandle;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Tenants not reached by their hostname are served under /tenants/<name>
+const PATH_PREFIX: &str = "/tenants/";
+
+/// What every tenant shares with the server hosting it
+#[derive(Clone)]
+pub(crate) struct Settings {
+    pub(crate) pool: db::PoolConfig,
+    pub(crate) audit: audit::AuditConfig,
+    pub(crate) purge: purge::PurgeConfig,
+    pub(crate) limits: Arc<limits::Limiter>,
+    pub(crate) client_auth: Option<tls::ClientAuth>,
+    pub(crate) encryption: Option<db::MasterKey>,
+    pub(crate) metrics: bool,
+}
+
+/// The path prefix stripped from a tenant request, for links back to the tenant
+#[derive(Clone)]
+pub(crate) struct Prefix(pub(crate) String);
+
+/// A tenant being served
+struct Running {
+    hostname: Option<String>,
+    app: tide::Server<State>,
+    /// the tenant's purge jobs, stopped when it is removed
+    jobs: Vec<JoinHandle<()>>,
+}
+
+/// The tenants hosted by the server, listed in its own database
+/// and each served by its own app with its own database and signing keys
+pub(crate) struct Tenants {
+    host: Arc<dyn db::Storage>,
+    host_url: String,
+    settings: Settings,
+    running: RwLock<HashMap<String, Running>>,
+}
+
+impl Tenants {
+    /// Starts every tenant listed in the host database,
+    /// one which fails to start is logged and skipped
+    pub(crate) async fn load(
+        host: Arc<dyn db::Storage>,
+        host_url: &str,
+        settings: Settings,
+    ) -> Result<Self> {
+        let tenants = Self {
+            host,
+            host_url: host_url.to_string(),
+            settings,
+            running: RwLock::new(HashMap::new()),
+        };
+        for tenant in tenants.host.get_tenants().await? {
+            let url = db::database_url(&tenant.database);
+            let key = tenants.settings.encryption.as_ref();
+            let started = match db::init(&url, false, &tenants.settings.pool, key).await {
+                Ok(db) => tenants.start(&tenant, db).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = started {
+                log::error!("could not start tenant {}: {}", tenant.name, e);
+            }
+        }
+        Ok(tenants)
+    }
+
+    async fn start(&self, tenant: &db::Tenant, db: Arc<dyn db::Storage>) -> Result<()> {
+        let jwt = auth::keys::from_pem(
+            tenant.private_key.as_bytes(),
+            tenant.public_key.as_bytes(),
+            None,
+        )?;
+        let jobs = vec![
+            audit::spawn_purge(self.settings.audit.clone(), db.clone()),
+            purge::spawn_purge(self.settings.purge.clone(), db.clone()),
+            Some(auth::passwords::spawn_upgrade(db.clone())),
+        ];
+        let state = State {
+            db,
+            jwt: Arc::new(jwt),
+            limits: self.settings.limits.clone(),
+            // each tenant's own figures, served on its own /metrics
+            metrics: Arc::new(metrics::Metrics::new(self.settings.metrics)),
+            client_auth: self.settings.client_auth,
+            tenants: None,
+        };
+        let mut app = tide::with_state(state);
+        super::mount(&mut app);
+        let running = Running {
+            hostname: tenant.hostname.clone(),
+            app,
+            jobs: jobs.into_iter().flatten().collect(),
+        };
+        self.running
+            .write()
+            .await
+            .insert(tenant.name.clone(), running);
+        log::info!("serving tenant {}", tenant.name);
+        Ok(())
+    }
+
+    pub(crate) async fn list(&self) -> Result<Vec<db::Tenant>> {
+        self.host.get_tenants().await
+    }
+
+    /// Creates the tenant's database with a root admin credential and signing keys
+    /// and starts serving it, the credential is only returned when the database had none
+    pub(crate) async fn create(
+        &self,
+        new: db::CreateTenant,
+    ) -> Result<(db::Tenant, Option<Creds>)> {
+        let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+        if new.name.is_empty() || new.name.len() > 63 || !new.name.chars().all(valid) {
+            return Err(ServerError::InvalidTenant(
+                "names are up to 63 lowercase letters, digits and dashes".to_string(),
+            ));
+        }
+        let hostname = new.hostname.map(|h| h.to_ascii_lowercase());
+        {
+            let running = self.running.read().await;
+            if running.contains_key(&new.name) {
+                return Err(ServerError::InvalidTenant(format!(
+                    "{} already exists",
+                    new.name
+                )));
+            }
+            if hostname.is_some() && running.values().any(|r| r.hostname == hostname) {
+                return Err(ServerError::InvalidTenant(
+                    "hostname already in use".to_string(),
+                ));
+            }
+        }
+        let database = match new.database {
+            Some(database) => database,
+            None => db::tenant_database_url(&self.host_url, &new.name)?,
+        };
+        let url = db::database_url(&database);
+        self.check_database(&url).await?;
+        let (private_key, public_key) = auth::keys::generate_jwt_pem()?;
+        let key = self.settings.encryption.as_ref();
+        let (db, creds) = db::provision(&url, &self.settings.pool, key).await?;
+        let tenant = db::Tenant {
+            name: new.name,
+            hostname,
+            database,
+            private_key,
+            public_key,
+            created: Utc::now(),
+        };
+        self.host.insert_tenant(&tenant).await?;
+        self.start(&tenant, db).await?;
+        Ok((tenant, creds))
+    }
+
+    /// Errors unless `url` is a database of the tenant's own, beside the server's
+    async fn check_database(&self, url: &str) -> Result<()> {
+        let location = db::Location::of(url)?;
+        let host = db::Location::of(&self.host_url)?;
+        if location == host {
+            return Err(ServerError::InvalidTenant(
+                "a tenant cannot share the server's database".to_string(),
+            ));
+        }
+        if !location.is_beside(&host) {
+            return Err(ServerError::InvalidTenant(
+                "a tenant's database has to be beside the server's, a file in the same directory \
+                or a database on the same server named after the server's"
+                    .to_string(),
+            ));
+        }
+        for tenant in self.host.get_tenants().await? {
+            let url = db::database_url(&tenant.database);
+            if db::Location::of(&url).ok().as_ref() == Some(&location) {
+                return Err(ServerError::InvalidTenant(format!(
+                    "the database is {}'s",
+                    tenant.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops serving a tenant, its database is left in place
+    pub(crate) async fn remove(&self, name: &str) -> Result<()> {
+        self.host.delete_tenant(name).await?;
+        if let Some(running) = self.running.write().await.remove(name) {
+            for job in running.jobs {
+                job.cancel().await;
+            }
+        }
+        log::info!("stopped serving tenant {}", name);
+        Ok(())
+    }
+
+    async fn app(&self, find: impl Fn(&String, &Running) -> bool) -> Option<tide::Server<State>> {
+        let running = self.running.read().await;
+        running
+            .iter()
+            .find(|(name, r)| find(name, r))
+            .map(|(_, r)| r.app.clone())
+    }
+}
+
+/// Hands requests for a tenant's hostname or path prefix to the tenant's app,
+/// after proxy headers are applied and before anything else sees them
+pub(crate) struct Route {}
+
+impl Route {
+    pub(crate) fn new() -> Self {
+        Self {}
+    }
+}
+
+#[tide::utils::async_trait]
+impl tide::Middleware<State> for Route {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let tenants = match &req.state().tenants {
+            Some(tenants) => tenants.clone(),
+            None => return Ok(next.run(req).await),
+        };
+        let host = req.url().host_str().map(|h| h.to_ascii_lowercase());
+        if let Some(app) = tenants
+            .app(|_, r| host.is_some() && r.hostname == host)
+            .await
+        {
+            let res: http_types::Response = app.respond(req).await?;
+            return Ok(res.into());
+        }
+        let (name, rest) = match req.url().path().strip_prefix(PATH_PREFIX) {
+            Some(path) => match path.split_once('/') {
+                Some((name, rest)) => (name.to_string(), format!("/{}", rest)),
+                None => (path.to_string(), "/".to_string()),
+            },
+            None => return Ok(next.run(req).await),
+        };
+        let app = match tenants.app(|n, _| *n == name).await {
+            Some(app) => app,
+            None => return Ok(tide::Response::new(404)),
+        };
+        let mut inner: http_types::Request = req.into();
+        inner.url_mut().set_path(&rest);
+        inner
+            .ext_mut()
+            .insert(Prefix(format!("{}{}", PATH_PREFIX, name)));
+        let res: http_types::Response = app.respond(inner).await?;
+        Ok(res.into())
+    }
+}
+
+#[cfg(test)]
+#[async_std::test]
+async fn tokens_only_work_where_they_were_issued() -> tide::Result<()> {
+    use http_types::{Body, Method, Request, Response, Url};
+    let dir = std::env::temp_dir().join(format!("oneroster-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir)?;
+    let host_url = db::database_url(&dir.join("host.db").to_string_lossy());
+    let master = db::MasterKey::from_hex(&"ab".repeat(32))?;
+    let pool = db::PoolConfig::default();
+    let (host, _) = db::provision(&host_url, &pool, Some(&master)).await?;
+    let settings = Settings {
+        pool,
+        audit: audit::AuditConfig::default(),
+        purge: purge::PurgeConfig::default(),
+        limits: Arc::new(limits::Limiter::new(limits::LimitConfig::default())),
+        client_auth: None,
+        encryption: Some(master),
+        metrics: false,
+    };
+    let tenants = Tenants::load(host.clone(), &host_url, settings.clone()).await?;
+    let mut creds = Vec::new();
+    for name in ["a", "b"].iter() {
+        let new = db::CreateTenant {
+            name: name.to_string(),
+            hostname: None,
+            database: None,
+        };
+        let (_, created) = tenants.create(new).await?;
+        creds.push(created.expect("a new database has a root admin"));
+    }
+    let elsewhere = std::env::temp_dir().join(format!("oneroster-{}.db", uuid::Uuid::new_v4()));
+    for database in [
+        dir.join("host.db").to_string_lossy().to_string(),
+        format!("{}/./host.db", dir.to_string_lossy()),
+        dir.join("host_a.db").to_string_lossy().to_string(),
+        elsewhere.to_string_lossy().to_string(),
+    ]
+    .iter()
+    {
+        let new = db::CreateTenant {
+            name: "c".to_string(),
+            hostname: None,
+            database: Some(database.clone()),
+        };
+        assert!(matches!(
+            tenants.create(new).await,
+            Err(ServerError::InvalidTenant(_))
+        ));
+    }
+    assert!(!elsewhere.exists());
+    let stored: String = sqlx::query_scalar("SELECT private_key FROM tenants WHERE name = 'a'")
+        .fetch_one(&sqlx::SqlitePool::connect(&host_url).await?)
+        .await?;
+    assert!(!stored.contains("PRIVATE KEY"));
+    assert!(host.get_tenants().await?[0]
+        .private_key
+        .contains("PRIVATE KEY"));
+
+    let (private_key, public_key) = auth::keys::generate_jwt_pem()?;
+    let state = State {
+        db: host,
+        jwt: Arc::new(auth::keys::from_pem(
+            private_key.as_bytes(),
+            public_key.as_bytes(),
+            None,
+        )?),
+        limits: settings.limits.clone(),
+        metrics: Arc::new(metrics::Metrics::default()),
+        client_auth: None,
+        tenants: Some(Arc::new(tenants)),
+    };
+    let mut app = tide::with_state(state);
+    app.with(Route::new());
+    super::mount(&mut app);
+    let url = |path: &str| Url::parse(&format!("http://localhost{}", path)).unwrap();
+
+    let mut login = Request::new(Method::Post, url("/tenants/a/auth/login"));
+    login.set_body(Body::from_form(&Creds {
+        client_id: creds[0].client_id.clone(),
+        client_secret: creds[0].client_secret.clone(),
+        scope: "admin.readonly".to_string(),
+    })?);
+    let mut res: Response = app.respond(login).await?;
+    let token: serde_json::Value = res.body_json().await?;
+    let bearer = format!(
+        "Bearer {}",
+        token["access_token"].as_str().unwrap_or_default()
+    );
+    for (path, status) in [
+        ("/tenants/a/admin/users", 200),
+        ("/tenants/b/admin/users", 403),
+        ("/admin/users", 403),
+    ]
+    .iter()
+    {
+        let mut req = Request::new(Method::Get, url(path));
+        req.insert_header("Authorization", bearer.as_str());
+        let res: Response = app.respond(req).await?;
+        assert_eq!(u16::from(res.status()), *status, "{}", path);
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
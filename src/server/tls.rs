@@ -0,0 +1,426 @@
+use crate::server::{Result, ServerError};
+use async_dup::{Arc, Mutex};
+use async_rustls::server::TlsStream;
+use async_rustls::TlsAcceptor;
+use async_std::io::{self, Read, Write};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use openssl::hash::MessageDigest;
+use openssl::x509::{X509NameBuilder, X509NameRef, X509};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, RootCertStore,
+    ServerConfig, Session,
+};
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tide::listener::{ListenInfo, Listener, ToListener};
+
+/// How a TLS client certificate relates to the bearer token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// a certificate bound to a credential stands in for the token,
+    /// clients without a certificate may still use a token
+    Either,
+    /// a certificate is required and must be bound to the same credential as the token
+    Both,
+}
+
+impl FromStr for ClientAuth {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "either" => Ok(ClientAuth::Either),
+            "both" => Ok(ClientAuth::Both),
+            _ => Err(format!("unknown client auth mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MtlsConfig {
+    /// path to the pem encoded CA bundle client certificates are verified against
+    pub client_ca: String,
+    pub client_auth: ClientAuth,
+}
+
+/// The verified certificate a client presented during the TLS handshake
+#[derive(Debug, Clone)]
+pub(crate) struct ClientCert {
+    /// lowercase hex SHA-256 of the DER certificate
+    pub(crate) fingerprint: String,
+    /// RFC 4514 subject DN, most specific RDN first e.g. CN=vendor,O=Example
+    pub(crate) subject: String,
+}
+
+impl ClientCert {
+    fn from_der(der: &[u8]) -> io::Result<Self> {
+        let cert = X509::from_der(der).map_err(invalid)?;
+        let fingerprint = hex::encode(cert.digest(MessageDigest::sha256()).map_err(invalid)?);
+        Ok(Self {
+            fingerprint,
+            subject: subject(cert.subject_name()),
+        })
+    }
+}
+
+/// Writes a DN as RFC 4514 does, in the reverse of the order certificates encode it
+fn subject(name: &X509NameRef) -> String {
+    let mut rdns = name
+        .entries()
+        .map(|e| {
+            let name = match e.object().nid().short_name() {
+                Ok(name) => name.to_string(),
+                Err(_) => e.object().to_string(),
+            };
+            let value = match e.data().to_string() {
+                Ok(value) => value,
+                Err(_) => String::from_utf8_lossy(e.data().as_slice()).to_string(),
+            };
+            format!("{}={}", name, escape(&value))
+        })
+        .collect::<Vec<_>>();
+    rdns.reverse();
+    rdns.join(",")
+}
+
+/// Escapes an attribute value as RFC 4514 section 2.4 requires, `=` is escaped as well
+fn escape(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if i == 0 || i == last => escaped.push('\\'),
+            _ => (),
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Normalises an admin supplied subject DN by parsing it and writing it back as certificate
+/// subjects are, e.g. `CN=vendor, O=Example` to `CN=vendor,O=Example`
+pub(crate) fn normalise_subject(dn: &str) -> Result<String> {
+    let invalid = |reason: &str| ServerError::InvalidSubject(format!("{} in {}", reason, dn));
+    let mut name = X509NameBuilder::new()?;
+    let mut rdns = split_unescaped(dn, ',');
+    // certificates encode the least specific RDN first
+    rdns.reverse();
+    for rdn in rdns {
+        if split_unescaped(rdn, '+').len() > 1 {
+            return Err(invalid("multi-valued RDNs are not supported"));
+        }
+        let (attribute, value) = rdn
+            .split_once('=')
+            .ok_or_else(|| invalid("an RDN without ="))?;
+        let attribute = attribute.trim();
+        let value = value.trim_start_matches(' ');
+        if value.starts_with('#') {
+            return Err(invalid("hex encoded values are not supported"));
+        }
+        let value = unescape(value).ok_or_else(|| invalid("an invalid escape"))?;
+        if name.append_entry_by_text(attribute, &value).is_err()
+            && name
+                .append_entry_by_text(&attribute.to_uppercase(), &value)
+                .is_err()
+        {
+            return Err(invalid(&format!("an unknown attribute {}", attribute)));
+        }
+    }
+    Ok(subject(&name.build()))
+}
+
+/// Splits on `separator` where it is not escaped by a backslash
+fn split_unescaped(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == separator => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Undoes `escape` and hex pair escapes, dropping trailing spaces which are not escaped
+fn unescape(value: &str) -> Option<String> {
+    let mut bytes = value.bytes();
+    let mut unescaped = Vec::with_capacity(value.len());
+    let mut kept = 0;
+    while let Some(b) = bytes.next() {
+        if b == b'\\' {
+            let next = bytes.next()?;
+            match next {
+                b'"' | b'+' | b',' | b';' | b'<' | b'>' | b'\\' | b'=' | b'#' | b' ' => {
+                    unescaped.push(next)
+                }
+                _ => {
+                    let hex = [next, bytes.next()?];
+                    unescaped.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+                }
+            }
+            kept = unescaped.len();
+        } else {
+            unescaped.push(b);
+        }
+    }
+    while unescaped.len() > kept && unescaped.last() == Some(&b' ') {
+        unescaped.pop();
+    }
+    String::from_utf8(unescaped).ok()
+}
+
+/// Normalises an admin supplied fingerprint e.g. AB:CD:.. to abcd..
+pub(crate) fn normalise_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// HTTPS listener which verifies client certificates against a CA bundle
+/// and exposes the presented certificate to middleware as a `ClientCert` ext
+pub(crate) struct MtlsListener<State> {
+    addr: std::net::SocketAddr,
+    config: std::sync::Arc<ServerConfig>,
+    tcp: Option<TcpListener>,
+    server: Option<tide::Server<State>>,
+}
+
+impl<State> MtlsListener<State> {
+    pub(crate) fn new(
+        addr: std::net::SocketAddr,
+        cert: &str,
+        key: &str,
+        mtls: &MtlsConfig,
+    ) -> io::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        let (added, _) = roots
+            .add_pem_file(&mut BufReader::new(File::open(&mtls.client_ca)?))
+            .map_err(|_| invalid("invalid client CA bundle"))?;
+        if added == 0 {
+            return Err(invalid("no certificates found in client CA bundle"));
+        }
+        let verifier = match mtls.client_auth {
+            ClientAuth::Either => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+            ClientAuth::Both => AllowAnyAuthenticatedClient::new(roots),
+        };
+        let mut config = ServerConfig::new(verifier);
+        let certs = certs(&mut BufReader::new(File::open(cert)?))
+            .map_err(|_| invalid("invalid certificate"))?;
+        config
+            .set_single_cert(certs, load_key(key)?)
+            .map_err(invalid)?;
+        Ok(Self {
+            addr,
+            config: std::sync::Arc::new(config),
+            tcp: None,
+            server: None,
+        })
+    }
+}
+
+fn load_key(path: &str) -> io::Result<rustls::PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if let Ok(mut keys) = pkcs8_private_keys(&mut reader) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    if let Ok(mut keys) = rsa_private_keys(&mut reader) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    Err(invalid("invalid key"))
+}
+
+fn invalid<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+}
+
+fn handle<State: Clone + Send + Sync + 'static>(
+    app: tide::Server<State>,
+    stream: TcpStream,
+    acceptor: TlsAcceptor,
+) {
+    task::spawn(async move {
+        let local_addr = stream.local_addr().ok();
+        let peer_addr = stream.peer_addr().ok();
+        let tls = match acceptor.accept(stream).await {
+            Ok(tls) => tls,
+            Err(e) => {
+                log::debug!("tls handshake failed: {}", e);
+                return;
+            }
+        };
+        let cert = match tls.get_ref().1.get_peer_certificates() {
+            Some(chain) if !chain.is_empty() => match ClientCert::from_der(&chain[0].0) {
+                Ok(cert) => Some(cert),
+                Err(e) => {
+                    log::warn!("could not read client certificate: {}", e);
+                    return;
+                }
+            },
+            _ => None,
+        };
+        let stream = TlsStreamWrapper(Arc::new(Mutex::new(tls)));
+        let fut = async_h1::accept(stream, |mut req| async {
+            if req.url_mut().set_scheme("https").is_err() {
+                log::error!("unable to set https scheme on url: {}", req.url());
+            }
+            req.set_local_addr(local_addr);
+            req.set_peer_addr(peer_addr);
+            if let Some(cert) = cert.clone() {
+                req.ext_mut().insert(cert);
+            }
+            app.respond(req).await
+        });
+        if let Err(e) = fut.await {
+            log::error!("async-h1 error: {}", e);
+        }
+    });
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for MtlsListener<State> {
+    async fn bind(&mut self, server: tide::Server<State>) -> io::Result<()> {
+        self.tcp = Some(TcpListener::bind(self.addr).await?);
+        self.server = Some(server);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let listener = self.tcp.as_ref().unwrap();
+        let server = self.server.as_ref().unwrap();
+        let acceptor = TlsAcceptor::from(self.config.clone());
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => handle(server.clone(), stream, acceptor.clone()),
+                Err(e) => {
+                    log::error!("could not accept connection: {}", e);
+                    task::sleep(std::time::Duration::from_millis(500)).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        vec![ListenInfo::new(
+            format!("https://{}", self.addr),
+            String::from("tcp"),
+            true,
+        )]
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for MtlsListener<State> {
+    type Listener = Self;
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+impl<State> std::fmt::Debug for MtlsListener<State> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MtlsListener")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl<State> std::fmt::Display for MtlsListener<State> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "https://{}", self.addr)
+    }
+}
+
+/// async-h1 needs a cloneable stream
+#[derive(Clone)]
+struct TlsStreamWrapper(Arc<Mutex<TlsStream<TcpStream>>>);
+
+impl Read for TlsStreamWrapper {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_read(cx, buf)
+    }
+}
+
+impl Write for TlsStreamWrapper {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut &*self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut &*self.0).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn subjects_are_written_as_rfc4514() -> std::result::Result<(), openssl::error::ErrorStack> {
+    let mut name = openssl::x509::X509NameBuilder::new()?;
+    name.append_entry_by_text("C", "GB")?;
+    name.append_entry_by_text("O", "Example, Ltd")?;
+    name.append_entry_by_text("OU", "a+b=c \"d\\")?;
+    name.append_entry_by_text("CN", "#vendor ")?;
+    assert_eq!(
+        subject(&name.build()),
+        r#"CN=\#vendor\ ,OU=a\+b\=c \"d\\,O=Example\, Ltd,C=GB"#
+    );
+    assert_eq!(escape(" x"), "\\ x");
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn admin_subjects_are_normalised() {
+    assert_eq!(
+        normalise_subject(" CN=vendor, O=Example ").unwrap(),
+        "CN=vendor,O=Example"
+    );
+    assert_eq!(
+        normalise_subject("commonName=vendor,o=Example").unwrap(),
+        "CN=vendor,O=Example"
+    );
+    let written = r#"CN=\#vendor\ ,OU=a\+b\=c \"d\\,O=Example\, Ltd,C=GB"#;
+    assert_eq!(normalise_subject(written).unwrap(), written);
+    assert_eq!(normalise_subject(r"CN=caf\C3\A9").unwrap(), "CN=café");
+    for dn in ["vendor", "CN=a+O=b", "XX=vendor", "CN=#0403", r"CN=a\"].iter() {
+        assert!(normalise_subject(dn).is_err(), "{}", dn);
+    }
+}